@@ -148,6 +148,7 @@ void loop() {
     }
     client.send(serial_number);
     client.sendBinary((const char*) fb->buf, fb->len);
+    client.sendBinary("", 0); // an empty frame marks the end of the image
     Serial.println("image sent");
     esp_camera_fb_return(fb);
     client.poll();
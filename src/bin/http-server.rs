@@ -1,142 +1,5447 @@
+use async_compression::tokio::bufread::{GzipDecoder, ZlibDecoder};
+use async_zip::{tokio::write::ZipFileWriter, Compression, ZipDateTime, ZipEntryBuilder};
 use axum::{
-    body::Bytes,
-    extract::{Path, Request},
-    http::StatusCode,
-    response::Html,
-    routing::{get, post},
-    BoxError, Router,
+    async_trait,
+    body::{Body, Bytes},
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        ConnectInfo, DefaultBodyLimit, FromRef, FromRequestParts, Multipart, Path, Query, Request,
+        State,
+    },
+    http::{header, request::Parts, HeaderMap, HeaderName, HeaderValue, Method, StatusCode},
+    middleware::{self, Next},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        Html, IntoResponse, Response,
+    },
+    routing::{delete, get, head, post, put},
+    BoxError, Json, Router,
+};
+use axum_extra::TypedHeader;
+use axum_server::tls_rustls::RustlsConfig;
+use chrono::{DateTime, Local, Utc};
+use clap::{ArgAction, Parser, ValueEnum};
+use futures::{stream::BoxStream, Stream, StreamExt, TryStreamExt};
+use headers::{
+    authorization::Basic, Authorization, ContentLength, ETag, HeaderMapExt, IfNoneMatch,
+    LastModified, Range,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    ops::Bound,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, UNIX_EPOCH},
 };
-use chrono::Local;
-use futures::{Stream, TryStreamExt};
-use std::io;
 use tokio::{
     fs::File,
-    io::{BufReader, BufWriter},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    sync::{broadcast, Notify},
+};
+use tokio_util::{
+    compat::TokioAsyncReadCompatExt,
+    io::{ReaderStream, StreamReader},
+};
+use tower_http::{
+    compression::{
+        predicate::{NotForContentType, Predicate},
+        CompressionLayer, DefaultPredicate,
+    },
+    cors::{AllowOrigin, CorsLayer},
+    services::ServeDir,
 };
-use tokio_util::io::StreamReader;
-use tower_http::services::ServeDir;
+use tracing::Instrument;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use upload_image::forwarded::{self, IpCidr};
+use upload_image::storage::{
+    detect_file_format, detect_image_format, is_stored_image, latest_target, parse_mode,
+    parse_overrides, read_sidecar, relative_path_is_valid, remove_if_exists, remove_stale_latest,
+    remove_stale_temps, serial_is_valid, set_mode, sidecar_filename, stored_images,
+    thumbnail_filename, update_latest_symlink, FilenameTime, ImageFormat, LatestName, LatestRepair,
+    Layout, Naming, Rotation, SaveError, SaveOptions, SavedImage, SerialOverrides, Sidecar,
+    Storage, UploadSource, DEFAULT_WRITE_BUFFER_KB, MAX_WRITE_BUFFER_KB, STALE_TEMP_AGE,
+};
+use upload_image::store::{ImageStore, MemoryStore, S3Settings, S3Store};
+use upload_image::webhook::{self, WebhookUrl};
+use upload_image::ws::{self, SocketState};
 
-const UPLOADS_DIRECTORY: &str = "uploads";
+// used when neither `--bind-addr` nor `BIND_ADDR` is given
+const DEFAULT_BIND_ADDR: &str = "0.0.0.0:3000";
 
-#[tokio::main]
-async fn main() {
-    tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| format!("{}=debug", env!("CARGO_CRATE_NAME")).into()),
-        )
-        .with(tracing_subscriber::fmt::layer())
-        .init();
+// used when neither `--uploads-dir` nor `UPLOADS_DIR` is given
+const DEFAULT_UPLOADS_DIRECTORY: &str = "uploads";
 
-    // save files to a separate directory to not override files in the current directory
-    tokio::fs::create_dir_all(UPLOADS_DIRECTORY)
-        .await
-        .expect("failed to create `uploads` directory");
+// used when neither `--max-upload-bytes` nor `MAX_UPLOAD_BYTES` is given
+const DEFAULT_MAX_UPLOAD_BYTES: u64 = 10 * 1024 * 1024;
 
-    let app = Router::new()
-        .route("/", get(home))
-        .route("/upload/:serial_number", post(save_request_body))
-        .nest_service("/images", ServeDir::new(UPLOADS_DIRECTORY));
+// used when neither `--shutdown-timeout-secs` nor `SHUTDOWN_TIMEOUT_SECS` is given
+const DEFAULT_SHUTDOWN_TIMEOUT_SECS: u64 = 30;
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
-    tracing::debug!("listening on {}", listener.local_addr().unwrap());
-    axum::serve(listener, app).await.unwrap();
+// used when neither `--upload-timeout-secs` nor `UPLOAD_TIMEOUT_SECS` is given
+const DEFAULT_UPLOAD_TIMEOUT_SECS: u64 = 60;
+
+// used when neither `--tus-expiry-secs` nor `TUS_EXPIRY_SECS` is given
+const DEFAULT_TUS_EXPIRY_SECS: u32 = 24 * 60 * 60;
+
+// used when neither `--max-tus-uploads` nor `MAX_TUS_UPLOADS` is given
+const DEFAULT_MAX_TUS_UPLOADS: usize = 64;
+
+// used when neither `--reencode-quality` nor `REENCODE_QUALITY` is given
+const DEFAULT_REENCODE_QUALITY: f32 = 80.0;
+
+// used when uploads are rate limited but neither `--upload-rate-burst` nor `UPLOAD_RATE_BURST`
+// is given
+const DEFAULT_UPLOAD_RATE_BURST: f64 = 10.0;
+
+// used when neither `--max-concurrent-uploads` nor `MAX_CONCURRENT_UPLOADS` is given
+const DEFAULT_MAX_CONCURRENT_UPLOADS: usize = 16;
+
+// once this many clients are tracked by the rate limiter, those with a full bucket are forgotten
+const RATE_LIMIT_MAX_CLIENTS: usize = 10_000;
+
+// longest side of the generated thumbnails, in pixels
+const THUMBNAIL_MAX_SIZE: u32 = 256;
+
+// 16x16 icon of a camera lens, served as `/favicon.ico`
+const FAVICON: &[u8] = include_bytes!("../../assets/favicon.ico");
+
+// file parts stored from a single batch upload, the ones past it are rejected
+const MAX_BATCH_FILES: usize = 32;
+
+// room a form upload gets on top of the largest image for its boundaries, part headers and any
+// other fields
+const MULTIPART_OVERHEAD_BYTES: usize = 64 * 1024;
+
+// page size of the gallery when the query sets none, and the largest one it may set
+const DEFAULT_GALLERY_LIMIT: usize = 50;
+const MAX_GALLERY_LIMIT: usize = 500;
+
+// images on a contact sheet when the query sets no count, and the most it may set
+const DEFAULT_CONTACT_SHEET_COUNT: usize = 16;
+const MAX_CONTACT_SHEET_COUNT: usize = 100;
+
+// columns of a contact sheet when the query sets none, and the most it may set
+const DEFAULT_CONTACT_SHEET_COLS: u32 = 4;
+const MAX_CONTACT_SHEET_COLS: u32 = 16;
+
+// side of the square tiles of a contact sheet, each image is scaled down to fit in one
+const CONTACT_SHEET_TILE_SIZE: u32 = 160;
+
+// directory entries the gallery looks at before it gives up on the rest of the uploads tree
+const GALLERY_MAX_SCANNED: usize = 100_000;
+
+// upload events kept for subscribers that fall behind before they start missing some
+const UPLOAD_EVENTS_CAPACITY: usize = 64;
+
+// tries at notifying the `UPLOAD_WEBHOOK` of an image, the delay before the second one doubling
+// after each, and how long each may take
+const WEBHOOK_ATTEMPTS: u32 = 3;
+const WEBHOOK_RETRY_DELAY: Duration = Duration::from_secs(1);
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+// version of the tus resumable upload protocol spoken on `/files`
+const TUS_VERSION: &str = "1.0.0";
+
+// directory under the uploads directory that incomplete resumable uploads are assembled in
+const TUS_DIRECTORY: &str = ".tus";
+
+// longest time between two sweeps for expired resumable uploads
+const TUS_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+const TUS_RESUMABLE: HeaderName = HeaderName::from_static("tus-resumable");
+const UPLOAD_EXPIRES: HeaderName = HeaderName::from_static("upload-expires");
+const UPLOAD_LENGTH: HeaderName = HeaderName::from_static("upload-length");
+const UPLOAD_OFFSET: HeaderName = HeaderName::from_static("upload-offset");
+const X_API_KEY: HeaderName = HeaderName::from_static("x-api-key");
+const X_FILENAME: HeaderName = HeaderName::from_static("x-filename");
+
+// upper bounds of the upload size histogram buckets, in bytes
+const UPLOAD_SIZE_BUCKETS: [u64; 6] = [
+    16 * 1024,
+    64 * 1024,
+    256 * 1024,
+    1024 * 1024,
+    4 * 1024 * 1024,
+    16 * 1024 * 1024,
+];
+
+// Body returned to the client once an upload has been stored
+#[derive(Serialize)]
+struct UploadResponse {
+    serial_number: String,
+    filename: String,
+    bytes: u64,
+    format: ImageFormat,
+    // the bytes matched an image already stored as `filename`, so nothing new was written
+    duplicate: bool,
+    // left out when the stored image header can not be read
+    #[serde(skip_serializing_if = "Option::is_none")]
+    width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    height: Option<u32>,
+    // an image stored under the same upload ID was replaced, which the status code tells
+    #[serde(skip)]
+    replaced: bool,
 }
 
-// Handler that streams the request body to a file.
-async fn save_request_body(
-    Path(serial_number): Path<String>,
-    request: Request,
-) -> Result<(), (StatusCode, String)> {
-    stream_to_file(&serial_number, request.into_body().into_data_stream()).await
+// What `stream_to_file` stores an upload as
+#[derive(Clone, Copy)]
+enum StoreAs<'a> {
+    // a new image, named after the filename the client asked for when it is safe
+    Image(Option<&'a str>),
+    // the latest copy only, keeping no timestamped image
+    LatestOnly,
+    // the image of an upload ID, replacing the one stored under it before
+    Upload(&'a str),
 }
 
-// Handler that returns HTML for the home page.
-async fn home() -> Html<&'static str> {
-    Html(
-        r#"
-        <!doctype html>
-        <html>
-            <head>
-                <title>Upload images</title>
-            </head>
-            <body>
-                to do
-            </body>
-        </html>
-        "#,
-    )
+impl<'a> StoreAs<'a> {
+    // `?keep_history=false` only replaces the latest copy
+    fn image(requested_filename: Option<&'a str>, keep_history: Option<bool>) -> Self {
+        match keep_history.unwrap_or(true) {
+            true => StoreAs::Image(requested_filename),
+            false => StoreAs::LatestOnly,
+        }
+    }
 }
 
-// Save a `Stream` to a file
-async fn stream_to_file<S, E>(serial_number: &str, stream: S) -> Result<(), (StatusCode, String)>
-where
-    S: Stream<Item = Result<Bytes, E>>,
-    E: Into<BoxError>,
-{
-    // if !path_is_valid(path) {
-    //     return Err((StatusCode::BAD_REQUEST, "Invalid path".to_owned()));
-    // }
+// Outcome of one file part of a batch upload, in the order the parts were sent
+#[derive(Serialize)]
+struct BatchPartResponse {
+    // filename the part was sent with
+    part: String,
+    #[serde(flatten)]
+    outcome: BatchPartOutcome,
+}
 
-    async {
-        // Convert the stream into an `AsyncRead`.
-        let body_with_io_error = stream.map_err(|err| io::Error::new(io::ErrorKind::Other, err));
-        let body_reader = StreamReader::new(body_with_io_error);
-        futures::pin_mut!(body_reader);
+#[derive(Serialize)]
+#[serde(untagged)]
+enum BatchPartOutcome {
+    Stored(UploadResponse),
+    Rejected { error: String, code: &'static str },
+}
 
-        let local_time = Local::now().format("%Y%m%d-%H%M%S");
-        let filename = format!("image-{}.jpg", local_time);
+// Sent to the event streams when an image is stored
+#[derive(Clone, Serialize)]
+struct UploadEvent {
+    serial_number: String,
+    filename: String,
+    bytes: u64,
+    format: ImageFormat,
+    received_at: String,
+}
 
-        tokio::fs::create_dir_all(format!("{}/{}", UPLOADS_DIRECTORY, serial_number))
-            .await
-            .expect("failed to create `uploads/<serial_number>` directory");
+// Response to `?validate=true` uploads, telling how the image would be stored
+#[derive(Serialize)]
+struct ValidationResponse {
+    serial_number: String,
+    filename: String,
+    bytes: u64,
+    format: ImageFormat,
+    duplicate: bool,
+}
+
+// Query of the upload endpoint
+#[derive(Deserialize)]
+struct UploadQuery {
+    // check the upload without storing it
+    #[serde(default)]
+    validate: bool,
+    // `false` only replaces the latest copy, for devices that need no timestamped history
+    keep_history: Option<bool>,
+}
 
-        let path = format!("{}/{}", serial_number, filename);
+// Query of the contact sheet endpoint
+#[derive(Deserialize)]
+struct ContactSheetQuery {
+    // tiles per row
+    cols: Option<u32>,
+    // newest images shown
+    count: Option<usize>,
+}
 
-        // Create the file. `File` implements `AsyncWrite`.
-        let path_buf = std::path::Path::new(UPLOADS_DIRECTORY).join(&path);
-        let mut file = BufWriter::new(File::create(path_buf).await?);
+// Query of the rotate endpoint, checked by the handler so that a bad value gets the usual error
+#[derive(Deserialize)]
+struct RotateQuery {
+    // clockwise, 90, 180 or 270
+    deg: Option<String>,
+}
 
-        // Copy the body into the file.
-        tokio::io::copy(&mut body_reader, &mut file).await?;
+// One stored image, as reported by the listing endpoint
+#[derive(Serialize)]
+struct ImageEntry {
+    filename: String,
+    size: u64,
+    modified: String,
+    // only filled in when asked for, and left out for images without one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sidecar: Option<Sidecar>,
+}
 
-        // Read the file just copied
-        let path_buf = std::path::Path::new(UPLOADS_DIRECTORY).join(&path);
-        let mut image_file = BufReader::new(File::open(path_buf).await?);
+// One serial number, as reported by the index of `/images`
+#[derive(Serialize)]
+struct SerialEntry {
+    serial_number: String,
+    images: usize,
+    // RFC 3339 modification time of the image stored last, left out without any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    latest: Option<String>,
+}
 
-        let filename_latest = "aaa-latest.jpg";
-        let path_latest = format!("{}/{}", serial_number, filename_latest);
+// Query of the gallery, every field optional
+#[derive(Deserialize)]
+struct GalleryQuery {
+    // only images of this serial number
+    serial: Option<String>,
+    limit: Option<usize>,
+    #[serde(default)]
+    offset: usize,
+    // only images named after a time no earlier than this, RFC 3339 or written as in the filenames.
+    // Sorted by modification time, the images modified no earlier than this.
+    since: Option<String>,
+    #[serde(default)]
+    sort: ListSort,
+}
 
-        // Create the file. `File` implements `AsyncWrite`.
-        let path_latest_buf = std::path::Path::new(UPLOADS_DIRECTORY).join(&path_latest);
-        let mut file_latest = BufWriter::new(File::create(path_latest_buf).await?);
+// One page of the gallery
+#[derive(Serialize)]
+struct GalleryPage {
+    // images matching the query, over all pages
+    total: usize,
+    offset: usize,
+    limit: usize,
+    // the uploads tree was too large to look at entirely, so some images are missing
+    truncated: bool,
+    entries: Vec<GalleryEntry>,
+}
 
-        // Copy the image file into the latest file.
-        tokio::io::copy(&mut image_file, &mut file_latest).await?;
-        tracing::debug!(
-            "image saved to {}: {} and {}",
-            serial_number,
-            filename,
-            filename_latest
-        );
+#[derive(Serialize)]
+struct GalleryEntry {
+    serial: String,
+    filename: String,
+    size: u64,
+    // time the image is named after, left out of images named by the client, or the time it was
+    // last modified when sorted by it
+    timestamp: Option<String>,
+}
+
+// Order of listings and the gallery, newest first either way
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ListSort {
+    // by the time in the filenames, images named by the client last
+    #[default]
+    Name,
+    // by the time the files were last modified, whatever their names
+    Mtime,
+}
+
+// Query of the listing endpoint
+#[derive(Deserialize)]
+struct ListQuery {
+    // include the sidecar of each image
+    #[serde(default)]
+    sidecars: bool,
+    #[serde(default)]
+    sort: ListSort,
+}
+
+// Settings picked up once at startup, each `--<flag>` option falling back to an environment
+// variable and then to a default
+#[derive(Parser)]
+#[command(about = "Receives images uploaded over HTTP and stores them per serial number")]
+struct Config {
+    /// Directory the uploads are stored in
+    #[arg(long, env = "UPLOADS_DIR", default_value = DEFAULT_UPLOADS_DIRECTORY)]
+    uploads_dir: PathBuf,
+
+    /// Where images are stored: `fs` in `UPLOADS_DIR`, `s3` in the `S3_BUCKET` of an S3-compatible
+    /// object store, or `memory` in the memory of the process until it exits. Only uploads with
+    /// POST, listing them and reading them back are served with `s3` and `memory`.
+    #[arg(long, env = "STORAGE_BACKEND", value_enum, default_value_t = StorageBackend::Fs)]
+    storage_backend: StorageBackend,
+
+    /// Bucket images are stored in with the `s3` storage backend
+    #[arg(long, env = "S3_BUCKET", required_if_eq("storage_backend", "s3"))]
+    s3_bucket: Option<String>,
+
+    /// Put in front of the key of every image in `S3_BUCKET`, such as `uploads/`
+    #[arg(long, env = "S3_PREFIX", default_value = "")]
+    s3_prefix: String,
+
+    /// Endpoint of an S3-compatible store other than AWS, such as `http://localhost:9000`
+    #[arg(long, env = "S3_ENDPOINT")]
+    s3_endpoint: Option<String>,
+
+    /// Address the bucket in the path of requests rather than in the host name, as most
+    /// S3-compatible stores other than AWS need
+    #[arg(long, env = "S3_FORCE_PATH_STYLE")]
+    s3_force_path_style: bool,
+
+    /// Address and port to listen on
+    #[arg(long, env = "BIND_ADDR", default_value = DEFAULT_BIND_ADDR)]
+    bind_addr: SocketAddr,
+
+    /// Largest upload accepted, in bytes
+    #[arg(long, env = "MAX_UPLOAD_BYTES", default_value_t = DEFAULT_MAX_UPLOAD_BYTES)]
+    max_upload_bytes: u64,
+
+    /// Largest image accepted, in millions of pixels, checked from its header before it is decoded.
+    /// Unlimited when not set.
+    #[arg(long, env = "MAX_MEGAPIXELS", value_parser = parse_megapixels)]
+    max_megapixels: Option<f64>,
+
+    /// Uploads are only accepted with this key, when set
+    #[arg(long, env = "UPLOAD_API_KEY", hide_env_values = true)]
+    api_key: Option<String>,
+
+    /// Username that has to be given with HTTP Basic auth to browse the stored images
+    #[arg(long, env = "BROWSE_USERNAME", requires = "browse_password")]
+    browse_username: Option<String>,
+
+    /// Password that goes with `--browse-username`
+    #[arg(
+        long,
+        env = "BROWSE_PASSWORD",
+        requires = "browse_username",
+        hide_env_values = true
+    )]
+    browse_password: Option<String>,
+
+    /// Store JPEG and PNG uploads re-encoded in this format
+    #[arg(long, env = "REENCODE_FORMAT", value_enum)]
+    reencode_format: Option<ReencodeFormat>,
+
+    /// Quality from 0 to 100 of re-encoded uploads
+    #[arg(long, env = "REENCODE_QUALITY", default_value_t = DEFAULT_REENCODE_QUALITY, value_parser = parse_quality)]
+    reencode_quality: f32,
+
+    /// Comma separated formats uploads are accepted in, any image format when not set
+    #[arg(long, env = "ALLOWED_FORMATS", value_delimiter = ',')]
+    allowed_formats: Option<Vec<ImageFormat>>,
+
+    /// Recompress JPEG uploads larger than this many bytes at lower qualities until they fit
+    #[arg(long, env = "JPEG_MAX_BYTES")]
+    jpeg_max_bytes: Option<u64>,
+
+    /// Remove metadata such as EXIF from JPEG and PNG uploads before they are stored
+    #[arg(long = "strip-exif", env = "STRIP_EXIF")]
+    strip_metadata: bool,
+
+    /// Uploads per second allowed from each client IP, unlimited when not set
+    #[arg(long, env = "UPLOAD_RATE_LIMIT", value_parser = parse_rate)]
+    upload_rate_limit: Option<f64>,
+
+    /// Uploads a client IP may make at once before being held to the rate limit
+    #[arg(long, env = "UPLOAD_RATE_BURST", default_value_t = DEFAULT_UPLOAD_RATE_BURST, value_parser = parse_burst)]
+    upload_rate_burst: f64,
+
+    /// Frames per second each `/ws` connection may send, unlimited when not set
+    #[arg(long, env = "FRAME_RATE_LIMIT", value_parser = parse_frame_rate)]
+    frame_rate_limit: Option<f64>,
+
+    /// Frames a `/ws` connection may send at once before being held to the frame rate limit
+    #[arg(long, env = "FRAME_RATE_BURST", default_value_t = ws::DEFAULT_FRAME_RATE_BURST, value_parser = parse_frame_burst)]
+    frame_rate_burst: f64,
+
+    /// Seconds between the pings sent on `/ws` connections
+    #[arg(long, env = "PING_INTERVAL_SECS", default_value_t = ws::DEFAULT_PING_INTERVAL_SECS, value_parser = clap::value_parser!(u64).range(1..))]
+    ping_interval_secs: u64,
+
+    /// Seconds a `/ws` connection may go without sending anything before it is closed
+    #[arg(long, env = "IDLE_TIMEOUT_SECS", default_value_t = ws::DEFAULT_IDLE_TIMEOUT_SECS, value_parser = clap::value_parser!(u64).range(1..))]
+    idle_timeout_secs: u64,
+
+    /// Uploads written to disk at the same time, the others wait for their turn
+    #[arg(long, env = "MAX_CONCURRENT_UPLOADS", default_value_t = DEFAULT_MAX_CONCURRENT_UPLOADS, value_parser = parse_concurrency)]
+    max_concurrent_uploads: usize,
+
+    /// Answer `503 Service Unavailable` to uploads that would wait for their turn
+    #[arg(long, env = "REJECT_WHEN_BUSY")]
+    reject_when_busy: bool,
+
+    /// Capacity of the buffer each upload is written to disk through, in KiB. Fast disks do better
+    /// with a few hundred.
+    #[arg(long, env = "WRITE_BUFFER_KB", default_value_t = DEFAULT_WRITE_BUFFER_KB, value_parser = parse_write_buffer_kb)]
+    write_buffer_kb: usize,
+
+    /// Seconds the whole body of an upload has to arrive within, or it is answered with
+    /// `408 Request Timeout` and what was received of it is removed. A `PATCH` to a resumable
+    /// upload keeps what it brought, for the client to resume after.
+    #[arg(long, env = "UPLOAD_TIMEOUT_SECS", default_value_t = DEFAULT_UPLOAD_TIMEOUT_SECS, value_parser = clap::value_parser!(u64).range(1..))]
+    upload_timeout_secs: u64,
+
+    /// Seconds a resumable upload is kept without more of it arriving, after which what was
+    /// received of it is removed. Every `PATCH` starts it over.
+    #[arg(long, env = "TUS_EXPIRY_SECS", default_value_t = DEFAULT_TUS_EXPIRY_SECS, value_parser = clap::value_parser!(u32).range(1..))]
+    tus_expiry_secs: u32,
+
+    /// Resumable uploads that may be open at once, creating another is answered with
+    /// `503 Service Unavailable`
+    #[arg(long, env = "MAX_TUS_UPLOADS", default_value_t = DEFAULT_MAX_TUS_UPLOADS, value_parser = parse_concurrency)]
+    max_tus_uploads: usize,
+
+    /// Sync every stored image to the disk before answering, so it survives a power cut
+    #[arg(long, env = "FSYNC")]
+    fsync: bool,
+
+    /// Total bytes the stored images of a serial number may take up, unlimited when not set
+    #[arg(long, env = "SERIAL_QUOTA_BYTES")]
+    serial_quota_bytes: Option<u64>,
+
+    /// Evict the oldest images of a serial number to make room for an upload over its quota,
+    /// instead of answering `507 Insufficient Storage`
+    #[arg(long, env = "EVICT_OLDEST", requires = "serial_quota_bytes")]
+    evict_oldest: bool,
+
+    /// Drop an upload that looks like the image last stored for its serial number, when their
+    /// perceptual hashes are at most this many of 64 bits apart. Near duplicates are kept when not
+    /// set.
+    #[arg(long, env = "NEAR_DUPLICATE_DISTANCE", value_parser = clap::value_parser!(u32).range(0..=64))]
+    near_duplicate_distance: Option<u32>,
+
+    /// JSON file of settings of their own for some serial numbers, such as
+    /// `{"cam-01": {"allowed_formats": ["jpeg"], "serial_quota_bytes": 50000000}}`. Each may set
+    /// `allowed_formats`, `webp_quality`, `serial_quota_bytes`, `evict_oldest` and
+    /// `near_duplicate_distance`; the others keep the settings above. Read again on `SIGHUP`.
+    #[arg(long, env = "SERIAL_OVERRIDES")]
+    serial_overrides: Option<PathBuf>,
+
+    /// Take the client of a request from the last `X-Forwarded-For` entry, as added by a reverse
+    /// proxy in front of the server. Only set this when every request goes through one.
+    #[arg(long, env = "TRUST_FORWARDED_FOR")]
+    trust_forwarded_for: bool,
+
+    /// Comma separated addresses and CIDR ranges of the reverse proxies in front of the server.
+    /// Requests from them are taken to come from the client named by `Forwarded` or
+    /// `X-Forwarded-For`, past any of these proxies in it.
+    #[arg(long, env = "TRUSTED_PROXIES", value_delimiter = ',')]
+    trusted_proxies: Option<Vec<IpCidr>>,
+
+    /// What stored images are named after: `timestamp`, or `content-hash` for the SHA-256 of their
+    /// bytes
+    #[arg(long, env = "NAMING", default_value = "timestamp")]
+    naming: Naming,
+
+    /// Subdirectories of each serial directory images are stored in: `none`, or `daily` for
+    /// `YYYY/MM/DD` and `monthly` for `YYYY/MM` after the date they were received. Their filenames
+    /// in listings are then paths, such as `2024/05/06/image-20240506-070809.jpg`.
+    #[arg(long, env = "LAYOUT", default_value = "none")]
+    layout: Layout,
+
+    /// strftime format of the time in the names of images stored without a requested one
+    #[arg(long, env = "FILENAME_TIME_FORMAT", default_value = FilenameTime::DEFAULT_FORMAT, value_parser = parse_time_format)]
+    filename_time_format: String,
+
+    /// Write the time in image names in UTC rather than in the local time zone
+    #[arg(long, env = "USE_UTC", default_value_t = true, action = ArgAction::Set)]
+    use_utc: bool,
+
+    /// Color transparent parts of images are filled with in their JPEG thumbnails, as `RRGGBB` hex
+    #[arg(long, env = "THUMBNAIL_BACKGROUND", default_value = "ffffff", value_parser = parse_color)]
+    thumbnail_background: image::Rgb<u8>,
+
+    /// Name of the copy of the newest image in each serial directory, its extension follows the
+    /// format of the image
+    #[arg(long, env = "LATEST_FILENAME", default_value = LatestName::DEFAULT_STEM, value_parser = LatestName::new)]
+    latest_filename: LatestName,
+
+    /// Mode of the images and other files written, in octal such as `0640`, instead of what the
+    /// umask leaves them. Unix only.
+    #[arg(long, env = "FILE_MODE", value_parser = parse_mode)]
+    file_mode: Option<u32>,
 
-        Ok::<_, io::Error>(())
+    /// Mode of the directories made for serial numbers and dates, in octal such as `0750`. Unix
+    /// only.
+    #[arg(long, env = "DIR_MODE", value_parser = parse_mode)]
+    dir_mode: Option<u32>,
+
+    /// Seconds in-flight requests are given to finish on shutdown
+    #[arg(long, env = "SHUTDOWN_TIMEOUT_SECS", default_value_t = DEFAULT_SHUTDOWN_TIMEOUT_SECS)]
+    shutdown_timeout_secs: u64,
+
+    /// Comma separated origins browsers may call the API from, a single `*` for any
+    #[arg(long, env = "CORS_ALLOWED_ORIGINS", value_delimiter = ',', value_parser = parse_origin)]
+    cors_allowed_origins: Option<Vec<String>>,
+
+    /// Certificate to serve HTTPS with, PEM encoded
+    #[arg(long, env = "TLS_CERT", requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// Private key of the certificate, PEM encoded
+    #[arg(long, env = "TLS_KEY", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// `http://` URL the details of every stored image are POSTed to as JSON, the same ones the
+    /// event streams send. The upload is answered without waiting for it.
+    #[arg(long, env = "UPLOAD_WEBHOOK")]
+    upload_webhook: Option<WebhookUrl>,
+
+    /// Point the latest copy of every serial number back at its newest image, report what was out
+    /// of sync and exit, without starting the server
+    #[arg(long)]
+    repair_latest: bool,
+}
+
+// Formats uploads can be re-encoded to
+#[derive(Clone, Copy, ValueEnum)]
+enum ReencodeFormat {
+    Webp,
+}
+
+// What images are stored in
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum StorageBackend {
+    Fs,
+    S3,
+    Memory,
+}
+
+impl StorageBackend {
+    fn name(self) -> &'static str {
+        match self {
+            StorageBackend::Fs => "fs",
+            StorageBackend::S3 => "s3",
+            StorageBackend::Memory => "memory",
+        }
+    }
+}
+
+impl Config {
+    // Read the settings, stopping the server with a usage message when one is malformed
+    fn load() -> Self {
+        let config = Config::parse();
+        tracing::debug!("maximum upload size is {} bytes", config.max_upload_bytes);
+        if let Some(megapixels) = config.max_megapixels {
+            tracing::debug!("maximum image size is {} megapixels", megapixels);
+        }
+        if config.api_key.is_none() {
+            tracing::debug!("`UPLOAD_API_KEY` is not set, uploads are not authenticated");
+        }
+        if let Some(quality) = config.webp_quality() {
+            tracing::debug!("re-encoding uploads to WebP at quality {}", quality);
+        } else if let Some(budget) = config.jpeg_max_bytes {
+            tracing::debug!("recompressing JPEG uploads larger than {} bytes", budget);
+        }
+        if config.strip_metadata {
+            tracing::debug!("stripping metadata from uploads");
+        }
+        if let Some(quota) = config.serial_quota_bytes {
+            tracing::debug!("limiting each serial number to {} bytes of images", quota);
+        }
+        if let Some(limit) = config.upload_rate_limit() {
+            tracing::debug!(
+                "limiting uploads to {} per second per client, in bursts of up to {}",
+                limit.per_second,
+                limit.burst
+            );
+        }
+        config
+    }
+
+    // JPEG and PNG uploads are stored re-encoded as WebP at this quality, when set
+    fn webp_quality(&self) -> Option<f32> {
+        self.reencode_format.map(|format| match format {
+            ReencodeFormat::Webp => self.reencode_quality,
+        })
+    }
+
+    // uploads per client IP are limited, when set
+    fn upload_rate_limit(&self) -> Option<RateLimit> {
+        self.upload_rate_limit.map(|per_second| RateLimit {
+            per_second,
+            burst: self.upload_rate_burst,
+        })
+    }
+
+    // frames per `/ws` connection are limited, when set
+    fn frame_rate_limit(&self) -> Option<ws::RateLimit> {
+        self.frame_rate_limit.map(|per_second| ws::RateLimit {
+            per_second,
+            burst: self.frame_rate_burst,
+        })
+    }
+
+    // username and password browsing the stored images takes, when set
+    fn browse_credentials(&self) -> Option<(&str, &str)> {
+        self.browse_username
+            .as_deref()
+            .zip(self.browse_password.as_deref())
+    }
+
+    fn filename_time(&self) -> FilenameTime {
+        FilenameTime::new(&self.filename_time_format, self.use_utc)
+            .expect("the format was checked when parsed")
+    }
+
+    fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.shutdown_timeout_secs)
+    }
+
+    // where the `s3` storage backend keeps the images
+    fn s3_settings(&self) -> S3Settings {
+        S3Settings {
+            bucket: self.s3_bucket.clone().unwrap_or_default(),
+            prefix: self.s3_prefix.clone(),
+            endpoint_url: self.s3_endpoint.clone(),
+            force_path_style: self.s3_force_path_style,
+        }
+    }
+
+    // paths of the certificate and key when serving HTTPS
+    fn tls(&self) -> Option<(&std::path::Path, &std::path::Path)> {
+        self.tls_cert.as_deref().zip(self.tls_key.as_deref())
+    }
+}
+
+fn parse_quality(value: &str) -> Result<f32, String> {
+    value
+        .parse()
+        .ok()
+        .filter(|quality| (0.0..=100.0).contains(quality))
+        .ok_or_else(|| "must be a number from 0 to 100".to_owned())
+}
+
+fn parse_rate(value: &str) -> Result<f64, String> {
+    value
+        .parse()
+        .ok()
+        .filter(|rate: &f64| *rate > 0.0)
+        .ok_or_else(|| "must be a positive number of uploads per second".to_owned())
+}
+
+fn parse_frame_rate(value: &str) -> Result<f64, String> {
+    value
+        .parse()
+        .ok()
+        .filter(|rate: &f64| *rate > 0.0)
+        .ok_or_else(|| "must be a positive number of frames per second".to_owned())
+}
+
+fn parse_megapixels(value: &str) -> Result<f64, String> {
+    value
+        .parse()
+        .ok()
+        .filter(|megapixels: &f64| megapixels.is_finite() && *megapixels > 0.0)
+        .ok_or_else(|| "must be a positive number of megapixels".to_owned())
+}
+
+fn parse_write_buffer_kb(value: &str) -> Result<usize, String> {
+    value
+        .parse()
+        .ok()
+        .filter(|kb: &usize| (1..=MAX_WRITE_BUFFER_KB).contains(kb))
+        .ok_or_else(|| format!("must be a number of KiB from 1 to {}", MAX_WRITE_BUFFER_KB))
+}
+
+fn parse_concurrency(value: &str) -> Result<usize, String> {
+    value
+        .parse()
+        .ok()
+        .filter(|uploads: &usize| *uploads >= 1)
+        .ok_or_else(|| "must be a number of uploads of at least 1".to_owned())
+}
+
+fn parse_burst(value: &str) -> Result<f64, String> {
+    value
+        .parse()
+        .ok()
+        .filter(|burst: &f64| *burst >= 1.0)
+        .ok_or_else(|| "must be a number of uploads of at least 1".to_owned())
+}
+
+fn parse_frame_burst(value: &str) -> Result<f64, String> {
+    value
+        .parse()
+        .ok()
+        .filter(|burst: &f64| *burst >= 1.0)
+        .ok_or_else(|| "must be a number of frames of at least 1".to_owned())
+}
+
+// `FILENAME_TIME_FORMAT`, which has to write times that make safe filenames and read back
+fn parse_time_format(value: &str) -> Result<String, String> {
+    FilenameTime::new(value, true).map(|_| value.to_owned())
+}
+
+// A color such as `THUMBNAIL_BACKGROUND`, `RRGGBB` in hex with an optional leading `#`
+fn parse_color(value: &str) -> Result<image::Rgb<u8>, String> {
+    let hex = value.trim().trim_start_matches('#');
+    match u32::from_str_radix(hex, 16) {
+        Ok(rgb) if hex.len() == 6 => {
+            let [_, r, g, b] = rgb.to_be_bytes();
+            Ok(image::Rgb([r, g, b]))
+        }
+        _ => Err(format!(
+            "`{}` is not a color, expected RRGGBB in hex",
+            value
+        )),
+    }
+}
+
+// One entry of `CORS_ALLOWED_ORIGINS`, which has to be usable as a header value
+fn parse_origin(value: &str) -> Result<String, String> {
+    let origin = value.trim();
+    match HeaderValue::from_str(origin) {
+        Ok(_) if !origin.is_empty() => Ok(origin.to_owned()),
+        _ => Err(format!("`{}` is not an origin", origin)),
+    }
+}
+
+#[derive(Clone)]
+struct AppState {
+    storage: Arc<Storage>,
+    // what images are stored with and read back from, `storage` itself with the `fs`
+    // `STORAGE_BACKEND`
+    store: Arc<dyn ImageStore>,
+    config: Arc<Config>,
+    metrics: Arc<Metrics>,
+    // uploads per client IP are limited, when set
+    rate_limiter: Option<Arc<RateLimiter>>,
+    tus_uploads: Arc<TusUploads>,
+    contact_sheets: Arc<ContactSheets>,
+    // every newly stored image, for the live event streams
+    upload_events: broadcast::Sender<UploadEvent>,
+    // what `/ws` connections store their frames with
+    socket: Arc<SocketState>,
+}
+
+// lets handlers that only need the settings extract `State<Arc<Config>>`
+impl FromRef<AppState> for Arc<Config> {
+    fn from_ref(state: &AppState) -> Self {
+        state.config.clone()
+    }
+}
+
+// Rate and burst size of a token bucket
+#[derive(Clone, Copy)]
+struct RateLimit {
+    per_second: f64,
+    burst: f64,
+}
+
+// Token bucket that starts full and refills at `limit.per_second`, holding at most `limit.burst`
+struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: RateLimit) -> Self {
+        TokenBucket {
+            limit,
+            tokens: limit.burst,
+            updated: Instant::now(),
+        }
+    }
+
+    // Take a token, or tell how long until the next one is available
+    fn try_take(&mut self) -> Result<(), Duration> {
+        let now = Instant::now();
+        self.tokens = self.tokens_at(now);
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - self.tokens) / self.limit.per_second,
+            ))
+        }
+    }
+
+    fn tokens_at(&self, now: Instant) -> f64 {
+        let refilled = now.duration_since(self.updated).as_secs_f64() * self.limit.per_second;
+        (self.tokens + refilled).min(self.limit.burst)
+    }
+}
+
+// Upload rate limit, one token bucket per client IP
+struct RateLimiter {
+    limit: RateLimit,
+    buckets: Mutex<HashMap<IpAddr, TokenBucket>>,
+}
+
+impl RateLimiter {
+    fn new(limit: RateLimit) -> Self {
+        RateLimiter {
+            limit,
+            buckets: Mutex::default(),
+        }
+    }
+
+    // Take a token for an upload from `ip`, or tell how long until it may upload again
+    fn check(&self, ip: IpAddr) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= RATE_LIMIT_MAX_CLIENTS {
+            // a full bucket is the same as no bucket at all
+            let now = Instant::now();
+            buckets.retain(|_, bucket| bucket.tokens_at(now) < self.limit.burst);
+        }
+        buckets
+            .entry(ip)
+            .or_insert_with(|| TokenBucket::new(self.limit))
+            .try_take()
     }
-    .await
-    .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
 }
 
-// to prevent directory traversal attacks we ensure the path consists of exactly one normal
-// component
-fn path_is_valid(path: &str) -> bool {
-    let path = std::path::Path::new(path);
-    let mut components = path.components().peekable();
+// Resumable uploads that are still being received, by id
+#[derive(Default)]
+struct TusUploads(Mutex<HashMap<String, Arc<TusUpload>>>);
+
+// One resumable upload, assembled in `path` until all of its `length` bytes have arrived
+struct TusUpload {
+    serial_number: String,
+    // `X-Filename` given when the upload was created
+    requested_filename: Option<String>,
+    // when the request creating the upload was received, which the stored image goes by
+    received_at: DateTime<Local>,
+    length: u64,
+    path: PathBuf,
+    // bytes received so far, locked while a `PATCH` appends to the upload
+    offset: tokio::sync::Mutex<u64>,
+    // when the upload is given up on unless more of it arrives, pushed back by every `PATCH`
+    expires_at: Mutex<DateTime<Utc>>,
+}
+
+impl TusUpload {
+    fn expires_at(&self) -> DateTime<Utc> {
+        *self.expires_at.lock().unwrap()
+    }
+}
 
-    if let Some(first) = components.peek() {
-        if !matches!(first, std::path::Component::Normal(_)) {
+impl TusUploads {
+    // Add `upload`, unless `max` uploads are open already
+    fn try_insert(&self, id: String, upload: TusUpload, max: usize) -> bool {
+        let mut uploads = self.0.lock().unwrap();
+        if uploads.len() >= max {
             return false;
         }
+        uploads.insert(id, Arc::new(upload));
+        true
+    }
+
+    // The upload `id`, as long as it belongs to `serial_number` and has not expired
+    fn get(&self, serial_number: &str, id: &str) -> Option<Arc<TusUpload>> {
+        self.0
+            .lock()
+            .unwrap()
+            .get(id)
+            .filter(|upload| {
+                upload.serial_number == serial_number && upload.expires_at() > Utc::now()
+            })
+            .cloned()
+    }
+
+    fn remove(&self, id: &str) {
+        self.0.lock().unwrap().remove(id);
+    }
+
+    // Take out the uploads that expired by `now`, apart from those a `PATCH` is appending to
+    fn remove_expired(&self, now: DateTime<Utc>) -> Vec<(String, Arc<TusUpload>)> {
+        let mut uploads = self.0.lock().unwrap();
+        let expired: Vec<_> = uploads
+            .iter()
+            .filter(|(_, upload)| upload.expires_at() <= now && upload.offset.try_lock().is_ok())
+            .map(|(id, _)| id.clone())
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|id| uploads.remove_entry(&id))
+            .collect()
+    }
+}
+
+// Last contact sheet made for each serial number, served again while it shows the same images
+#[derive(Default)]
+struct ContactSheets(Mutex<HashMap<String, ContactSheet>>);
+
+struct ContactSheet {
+    // columns, and the filename and modification time of every image shown, newest first
+    key: ContactSheetKey,
+    jpeg: Bytes,
+}
+
+type ContactSheetKey = (u32, Vec<(String, String)>);
+
+impl ContactSheets {
+    fn get(&self, serial_number: &str, key: &ContactSheetKey) -> Option<Bytes> {
+        self.0
+            .lock()
+            .unwrap()
+            .get(serial_number)
+            .filter(|sheet| sheet.key == *key)
+            .map(|sheet| sheet.jpeg.clone())
+    }
+
+    fn insert(&self, serial_number: String, key: ContactSheetKey, jpeg: Bytes) {
+        self.0
+            .lock()
+            .unwrap()
+            .insert(serial_number, ContactSheet { key, jpeg });
+    }
+}
+
+// Extractor that rejects the request unless it carries the `UPLOAD_API_KEY`, either as
+// `Authorization: Bearer <key>` or as `X-API-Key: <key>`. Without a configured key every request
+// is let through.
+struct RequireApiKey;
+
+#[async_trait]
+impl FromRequestParts<AppState> for RequireApiKey {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let Some(expected) = &state.config.api_key else {
+            return Ok(RequireApiKey);
+        };
+
+        let header_value = |name| {
+            parts
+                .headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+        };
+        let provided = header_value(header::AUTHORIZATION.as_str())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .or_else(|| header_value("x-api-key"));
+        match provided {
+            Some(provided) if keys_match(provided, expected) => Ok(RequireApiKey),
+            _ => Err(ApiError::Unauthorized(
+                "Missing or invalid API key".to_owned(),
+            )),
+        }
+    }
+}
+
+// Extractor of the address of the client that sent a request, the peer of the connection unless
+// a trusted proxy forwarded the request for it
+struct ClientIp(IpAddr);
+
+#[async_trait]
+impl FromRequestParts<AppState> for ClientIp {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let ConnectInfo(addr) = ConnectInfo::<SocketAddr>::from_request_parts(parts, state)
+            .await
+            .map_err(|err| ApiError::Internal(err.to_string()))?;
+        let config = &state.config;
+        Ok(ClientIp(forwarded::client_ip(
+            addr.ip(),
+            &parts.headers,
+            config.trust_forwarded_for,
+            config.trusted_proxies.as_deref().unwrap_or_default(),
+        )))
+    }
+}
+
+// Compare keys in constant time, so response timing does not leak how much of a guess was right
+fn keys_match(provided: &str, expected: &str) -> bool {
+    provided.len() == expected.len()
+        && provided
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+// Middleware that asks for the browsing credentials with HTTP Basic auth before stored images can
+// be read, when they are configured. Other methods, such as deleting an image, keep to the API key.
+async fn require_browse_auth(
+    State(config): State<Arc<Config>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some((username, password)) = config.browse_credentials() else {
+        return next.run(request).await;
+    };
+    if !matches!(*request.method(), Method::GET | Method::HEAD) {
+        return next.run(request).await;
+    }
+
+    let authorized = request
+        .headers()
+        .typed_get::<Authorization<Basic>>()
+        .is_some_and(|Authorization(credentials)| {
+            // both are compared, so that the time taken does not tell which one was wrong
+            keys_match(credentials.username(), username)
+                & keys_match(credentials.password(), password)
+        });
+    if authorized {
+        return next.run(request).await;
+    }
+    (
+        [(
+            header::WWW_AUTHENTICATE,
+            "Basic realm=\"images\", charset=\"UTF-8\"",
+        )],
+        ApiError::Unauthorized("Missing or invalid credentials".to_owned()),
+    )
+        .into_response()
+}
+
+// Counters exposed in the Prometheus text format on `/metrics`
+#[derive(Default)]
+struct Metrics {
+    // keyed by serial number and outcome
+    uploads: Mutex<HashMap<(String, &'static str), u64>>,
+    // keyed by serial number, empty when it is not a valid one, and `Rejection` reason
+    rejections: Mutex<HashMap<(String, &'static str), u64>>,
+    bytes_written: AtomicU64,
+    // cumulative counts for `UPLOAD_SIZE_BUCKETS`, the `+Inf` bucket is `upload_size_count`
+    upload_size_buckets: [AtomicU64; UPLOAD_SIZE_BUCKETS.len()],
+    upload_size_sum: AtomicU64,
+    upload_size_count: AtomicU64,
+}
+
+impl Metrics {
+    // Count an upload for `serial_number`, with its size if it was stored
+    fn record_upload(&self, serial_number: &str, stored_bytes: Option<u64>) {
+        let outcome = if stored_bytes.is_some() {
+            "success"
+        } else {
+            "failure"
+        };
+        *self
+            .uploads
+            .lock()
+            .unwrap()
+            .entry((serial_number.to_owned(), outcome))
+            .or_default() += 1;
+
+        if let Some(bytes) = stored_bytes {
+            self.bytes_written.fetch_add(bytes, Ordering::Relaxed);
+            for (bucket, bound) in self.upload_size_buckets.iter().zip(UPLOAD_SIZE_BUCKETS) {
+                if bytes <= bound {
+                    bucket.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            self.upload_size_sum.fetch_add(bytes, Ordering::Relaxed);
+            self.upload_size_count.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // Count an upload for `serial_number` turned away for `reason`
+    fn record_rejection(&self, serial_number: &str, reason: &'static str) {
+        *self
+            .rejections
+            .lock()
+            .unwrap()
+            .entry((serial_number.to_owned(), reason))
+            .or_default() += 1;
+    }
+
+    fn render(&self) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP uploads_total Uploads received, by serial number and outcome.\n");
+        out.push_str("# TYPE uploads_total counter\n");
+        let uploads = self.uploads.lock().unwrap();
+        let mut uploads: Vec<_> = uploads.iter().collect();
+        uploads.sort();
+        for ((serial_number, outcome), count) in uploads {
+            out.push_str(&format!(
+                "uploads_total{{serial_number=\"{}\",outcome=\"{}\"}} {}\n",
+                serial_number, outcome, count
+            ));
+        }
+
+        out.push_str(
+            "# HELP uploads_rejected_total Uploads turned away, by serial number and reason.\n",
+        );
+        out.push_str("# TYPE uploads_rejected_total counter\n");
+        let rejections = self.rejections.lock().unwrap();
+        let mut rejections: Vec<_> = rejections.iter().collect();
+        rejections.sort();
+        for ((serial_number, reason), count) in rejections {
+            out.push_str(&format!(
+                "uploads_rejected_total{{serial_number=\"{}\",reason=\"{}\"}} {}\n",
+                serial_number, reason, count
+            ));
+        }
+
+        out.push_str("# HELP upload_bytes_written_total Bytes of stored uploads.\n");
+        out.push_str("# TYPE upload_bytes_written_total counter\n");
+        out.push_str(&format!(
+            "upload_bytes_written_total {}\n",
+            self.bytes_written.load(Ordering::Relaxed)
+        ));
+
+        out.push_str("# HELP upload_size_bytes Size of stored uploads.\n");
+        out.push_str("# TYPE upload_size_bytes histogram\n");
+        for (bucket, bound) in self.upload_size_buckets.iter().zip(UPLOAD_SIZE_BUCKETS) {
+            out.push_str(&format!(
+                "upload_size_bytes_bucket{{le=\"{}\"}} {}\n",
+                bound,
+                bucket.load(Ordering::Relaxed)
+            ));
+        }
+        let count = self.upload_size_count.load(Ordering::Relaxed);
+        out.push_str(&format!(
+            "upload_size_bytes_bucket{{le=\"+Inf\"}} {}\n",
+            count
+        ));
+        out.push_str(&format!(
+            "upload_size_bytes_sum {}\n",
+            self.upload_size_sum.load(Ordering::Relaxed)
+        ));
+        out.push_str(&format!("upload_size_bytes_count {}\n", count));
+
+        out
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    // `LOG_FORMAT=json` logs one JSON object per line, for log aggregators
+    let json_logs = match std::env::var("LOG_FORMAT").as_deref() {
+        Ok("json") => true,
+        Ok("text") | Err(_) => false,
+        Ok(other) => panic!("`LOG_FORMAT` must be `text` or `json`, not `{}`", other),
+    };
+    tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| {
+                // `upload_image` is the storage shared with the other server
+                format!("{}=debug,upload_image=debug", env!("CARGO_CRATE_NAME")).into()
+            }),
+        )
+        .with(json_logs.then(|| tracing_subscriber::fmt::layer().json()))
+        .with((!json_logs).then(tracing_subscriber::fmt::layer))
+        .init();
+
+    let config = Arc::new(Config::load());
+
+    if config.storage_backend == StorageBackend::Fs {
+        prepare_uploads_dir(&config).await;
+    } else {
+        tracing::debug!(
+            "saving uploads with the {} storage backend",
+            config.storage_backend.name()
+        );
+    }
+
+    let app = app(config.clone());
+
+    // serve HTTPS directly when a certificate is configured
+    let tls_config = match config.tls() {
+        Some((cert, key)) => Some(
+            RustlsConfig::from_pem_file(cert, key)
+                .await
+                .expect("failed to load the `TLS_CERT` certificate and `TLS_KEY` key"),
+        ),
+        None => None,
+    };
+    let (addr, shutdown_timeout) = (config.bind_addr, config.shutdown_timeout());
+
+    if let Some(tls_config) = tls_config {
+        tracing::debug!("listening on {} with TLS", addr);
+
+        // axum-server drains in-flight requests itself, closing them after `shutdown_timeout`
+        let handle = axum_server::Handle::new();
+        tokio::spawn({
+            let handle = handle.clone();
+            async move {
+                shutdown_signal().await;
+                tracing::debug!("shutting down, waiting for in-flight requests");
+                handle.graceful_shutdown(Some(shutdown_timeout));
+            }
+        });
+        axum_server::bind_rustls(addr, tls_config)
+            .handle(handle)
+            .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+            .await
+            .unwrap();
+        return;
+    }
+
+    let listener = tokio::net::TcpListener::bind(addr)
+        .await
+        .unwrap_or_else(|err| panic!("failed to listen on {}: {}", addr, err));
+    tracing::debug!("listening on {}", listener.local_addr().unwrap());
+
+    // On shutdown stop accepting connections and let in-flight uploads finish, but only for up
+    // to `shutdown_timeout`, so a stalled client can not keep the process alive forever.
+    let shutdown_started = Arc::new(Notify::new());
+    let server = axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown({
+        let shutdown_started = shutdown_started.clone();
+        async move {
+            shutdown_signal().await;
+            tracing::debug!("shutting down, waiting for in-flight requests");
+            shutdown_started.notify_one();
+        }
+    });
+    tokio::select! {
+        result = server => result.unwrap(),
+        _ = async {
+            shutdown_started.notified().await;
+            tokio::time::sleep(shutdown_timeout).await;
+        } => tracing::warn!(
+            "in-flight requests still running after {:?}, forcing shutdown",
+            shutdown_timeout
+        ),
+    }
+}
+
+// Create the uploads directory and clean up after the previous run. With `--repair-latest` the
+// latest copies are repaired and the process exits.
+async fn prepare_uploads_dir(config: &Config) {
+    // save files to a separate directory to not override files in the current directory
+    tokio::fs::create_dir_all(&config.uploads_dir)
+        .await
+        .expect("failed to create `uploads` directory");
+    tracing::debug!("saving uploads to {}", config.uploads_dir.display());
+
+    if config.repair_latest {
+        let repaired = repair_latest(&storage(config)).await;
+        std::process::exit(if repaired { 0 } else { 1 });
+    }
+
+    // resumable uploads only live as long as the process, what is left of earlier ones is useless
+    if let Err(err) = tokio::fs::remove_dir_all(config.uploads_dir.join(TUS_DIRECTORY)).await {
+        if err.kind() != io::ErrorKind::NotFound {
+            tracing::warn!("could not remove incomplete resumable uploads: {}", err);
+        }
+    }
+
+    // the other uploads a crash interrupted left their temporary files behind
+    match remove_stale_temps(&config.uploads_dir, STALE_TEMP_AGE).await {
+        Ok(removed) => tracing::info!("removed {} stale temporary files", removed),
+        Err(err) => tracing::warn!("could not remove stale temporary files: {}", err),
+    }
+}
+
+// `--repair-latest`: point the latest copy of every serial number at its newest image again,
+// printing the ones that were out of sync. Returns whether all of them could be repaired.
+async fn repair_latest(storage: &Storage) -> bool {
+    let serials = match read_serials(storage.uploads_dir()).await {
+        Ok(serials) => serials,
+        Err(err) => {
+            eprintln!("could not list the serial numbers: {}", err);
+            return false;
+        }
+    };
+    let (mut repaired, mut failed) = (0, 0);
+    for serial in &serials {
+        match storage.repair_latest(serial).await {
+            Ok(LatestRepair::Unchanged) => {}
+            Ok(LatestRepair::Repointed(target)) => {
+                println!("{}: latest now points at {}", serial, target);
+                repaired += 1;
+            }
+            Ok(LatestRepair::Removed) => {
+                println!("{}: removed the latest copy, no image is left", serial);
+                repaired += 1;
+            }
+            Err(err) => {
+                eprintln!("{}: could not repair the latest copy: {}", serial, err);
+                failed += 1;
+            }
+        }
+    }
+    println!(
+        "checked {} serial numbers, repaired {}, failed {}",
+        serials.len(),
+        repaired,
+        failed
+    );
+    failed == 0
+}
+
+// The storage of uploads `config` describes, stopping the server when its overrides do not read
+fn storage(config: &Config) -> Storage {
+    let storage = Storage::new(config.uploads_dir.clone(), save_options(config));
+    if let Some(path) = &config.serial_overrides {
+        let overrides =
+            read_overrides(path).unwrap_or_else(|err| panic!("`SERIAL_OVERRIDES`: {}", err));
+        tracing::debug!(
+            "{} serial numbers have settings of their own",
+            overrides.len()
+        );
+        storage.set_overrides(overrides);
+    }
+    storage
+}
+
+fn save_options(config: &Config) -> SaveOptions {
+    SaveOptions {
+        max_bytes: config.max_upload_bytes,
+        max_pixels: config
+            .max_megapixels
+            .map(|megapixels| (megapixels * 1_000_000.0) as u64),
+        strip_metadata: config.strip_metadata,
+        webp_quality: config.webp_quality(),
+        jpeg_max_bytes: config.jpeg_max_bytes,
+        fallback_format: None,
+        allowed_formats: config.allowed_formats.clone(),
+        max_concurrent_writes: config.max_concurrent_uploads,
+        reject_when_busy: config.reject_when_busy,
+        write_buffer_bytes: config.write_buffer_kb * 1024,
+        receive_timeout: Some(Duration::from_secs(config.upload_timeout_secs)),
+        fsync: config.fsync,
+        serial_quota_bytes: config.serial_quota_bytes,
+        evict_oldest: config.evict_oldest,
+        near_duplicate_distance: config.near_duplicate_distance,
+        naming: config.naming,
+        layout: config.layout,
+        filename_time: config.filename_time(),
+        latest_name: config.latest_filename.clone(),
+        file_mode: config.file_mode,
+        dir_mode: config.dir_mode,
+    }
+}
+
+// The settings of their own of serial numbers, from the `SERIAL_OVERRIDES` file at `path`
+fn read_overrides(path: &std::path::Path) -> Result<HashMap<String, SerialOverrides>, String> {
+    std::fs::read_to_string(path)
+        .map_err(|err| err.to_string())
+        .and_then(|json| parse_overrides(&json))
+        .map_err(|err| format!("{}: {}", path.display(), err))
+}
+
+// Read the `SERIAL_OVERRIDES` file at `path` again on every `SIGHUP`, for the uploads that start
+// after. A file that does not read keeps the settings from before.
+#[cfg(unix)]
+async fn reload_overrides_on_hangup(storage: Arc<Storage>, path: PathBuf) {
+    let mut hangups = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+        .expect("failed to install SIGHUP handler");
+    while hangups.recv().await.is_some() {
+        match read_overrides(&path) {
+            Ok(overrides) => {
+                tracing::info!(
+                    "reloaded the settings of {} serial numbers from {}",
+                    overrides.len(),
+                    path.display()
+                );
+                storage.set_overrides(overrides);
+            }
+            Err(err) => tracing::warn!("could not reload `SERIAL_OVERRIDES`: {}", err),
+        }
+    }
+}
+
+// The routes of the server, storing uploads as `config` says
+fn app(config: Arc<Config>) -> Router {
+    let storage = Arc::new(storage(&config));
+    let socket = Arc::new(SocketState {
+        storage: storage.clone(),
+        max_upload_bytes: usize::try_from(config.max_upload_bytes).unwrap_or(usize::MAX),
+        frame_rate_limit: config.frame_rate_limit(),
+        ping_interval: Duration::from_secs(config.ping_interval_secs),
+        idle_timeout: Duration::from_secs(config.idle_timeout_secs),
+    });
+    let store: Arc<dyn ImageStore> = match config.storage_backend {
+        StorageBackend::Fs => storage.clone(),
+        StorageBackend::S3 => Arc::new(S3Store::new(config.s3_settings(), save_options(&config))),
+        StorageBackend::Memory => Arc::new(MemoryStore::new(save_options(&config))),
+    };
+    let state = AppState {
+        storage,
+        store,
+        config: config.clone(),
+        metrics: Arc::default(),
+        rate_limiter: config
+            .upload_rate_limit()
+            .map(|limit| Arc::new(RateLimiter::new(limit))),
+        tus_uploads: Arc::default(),
+        contact_sheets: Arc::default(),
+        upload_events: broadcast::channel(UPLOAD_EVENTS_CAPACITY).0,
+        socket,
+    };
+    if config.storage_backend != StorageBackend::Fs {
+        return image_store_app(state);
+    }
+    #[cfg(unix)]
+    if let Some(path) = &config.serial_overrides {
+        tokio::spawn(reload_overrides_on_hangup(
+            state.storage.clone(),
+            path.clone(),
+        ));
+    }
+    tokio::spawn(expire_tus_uploads(
+        state.tus_uploads.clone(),
+        Duration::from_secs(config.tus_expiry_secs.into()).min(TUS_SWEEP_INTERVAL),
+    ));
+
+    let serve_dir = ServeDir::new(&config.uploads_dir);
+    Router::new()
+        .route("/", get(home))
+        .route("/favicon.ico", get(favicon))
+        .route("/health", get(health))
+        .route("/version", get(version))
+        .route("/metrics", get(metrics))
+        .route("/config", get(show_config))
+        .merge(
+            Router::new()
+                .route("/latest/:serial_number", get(latest_image))
+                .route("/gallery", get(gallery))
+                // `/images` itself is routed along with the images
+                .route("/images/", get(list_serials))
+                .route("/events/:serial_number", get(upload_events))
+                .route("/sse/:serial_number", get(upload_event_stream))
+                .route_layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    require_browse_auth,
+                )),
+        )
+        .merge(
+            Router::new()
+                .route(
+                    "/upload/:serial_number",
+                    post(save_request_body).fallback(upload_method_not_allowed),
+                )
+                .route(
+                    "/upload/:serial_number/:upload_id",
+                    put(put_request_body).fallback(put_method_not_allowed),
+                )
+                .route(
+                    "/upload-form/:serial_number",
+                    post(save_multipart)
+                        .fallback(upload_method_not_allowed)
+                        .layer(DefaultBodyLimit::max(
+                            usize::try_from(config.max_upload_bytes)
+                                .unwrap_or(usize::MAX)
+                                .saturating_add(MULTIPART_OVERHEAD_BYTES),
+                        )),
+                )
+                .route(
+                    "/upload-batch/:serial_number",
+                    post(save_multipart_batch)
+                        .fallback(upload_method_not_allowed)
+                        .layer(DefaultBodyLimit::max(
+                            usize::try_from(config.max_upload_bytes)
+                                .unwrap_or(usize::MAX)
+                                .saturating_mul(MAX_BATCH_FILES),
+                        )),
+                )
+                .route("/ws", get(ws_upload))
+                .route_layer(middleware::from_fn_with_state(state.clone(), rate_limit))
+                .route_layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    count_rejections,
+                )),
+        )
+        .merge(
+            Router::new()
+                // only creating an upload counts against the rate limit, not every resumption
+                .route(
+                    "/files/:serial_number",
+                    post(create_tus_upload)
+                        .route_layer(middleware::from_fn_with_state(state.clone(), rate_limit)),
+                )
+                .route(
+                    "/files/:serial_number/:id",
+                    head(tus_upload_offset).patch(append_tus_upload),
+                )
+                .route_layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    count_rejections,
+                ))
+                .layer(middleware::from_fn(tus_protocol)),
+        )
+        .nest(
+            "/images",
+            Router::new()
+                .route("/", get(list_serials))
+                .route("/:serial_number/list", get(list_images))
+                .route("/:serial_number/archive.zip", get(archive_images))
+                .route("/:serial_number/contact-sheet", get(contact_sheet))
+                // other methods on an image path keep being served from disk. The path of an image
+                // in the date subdirectories of a bucketed layout has several segments, so it is
+                // matched as a whole and checked by the handlers.
+                .route(
+                    "/:serial_number",
+                    delete(delete_serial).fallback_service(serve_dir.clone()),
+                )
+                .route(
+                    "/:serial_number/*filename",
+                    delete(delete_image)
+                        .post(rotate_image)
+                        .fallback_service(serve_dir.clone()),
+                )
+                .fallback_service(serve_dir)
+                .layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    image_content_type,
+                ))
+                .layer(middleware::from_fn_with_state(state.clone(), image_etag))
+                .layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    require_browse_auth,
+                )),
+        )
+        .fallback(not_found)
+        .layer(compression_layer())
+        .layer(cors_layer(config.cors_allowed_origins.as_deref()))
+        .with_state(state)
+}
+
+// The server when images are kept in an object store or in memory: uploads with POST, and reading
+// them back. What needs the images on a disk of its own is answered as not implemented.
+fn image_store_app(state: AppState) -> Router {
+    let config = state.config.clone();
+    Router::new()
+        .route("/favicon.ico", get(favicon))
+        .route("/health", get(image_store_health))
+        .route("/version", get(version))
+        .route("/metrics", get(metrics))
+        .route("/config", get(show_config))
+        .merge(
+            Router::new()
+                .route("/latest/:serial_number", get(latest_in_store))
+                .route("/images/:serial_number/list", get(list_in_store))
+                .route("/images/:serial_number/*filename", get(read_from_store))
+                .route_layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    require_browse_auth,
+                )),
+        )
+        .merge(
+            Router::new()
+                .route(
+                    "/upload/:serial_number",
+                    post(put_in_store).fallback(upload_method_not_allowed),
+                )
+                .route_layer(middleware::from_fn_with_state(state.clone(), rate_limit))
+                .route_layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    count_rejections,
+                )),
+        )
+        .fallback(not_implemented)
+        .layer(compression_layer())
+        .layer(cors_layer(config.cors_allowed_origins.as_deref()))
+        .with_state(state)
+}
+
+// Compression of the responses of clients that send `Accept-Encoding`, with gzip or deflate. Images
+// and ZIP archives are already compressed and left as they are, and so are event streams, which
+// would only arrive once a compressed block fills up.
+fn compression_layer() -> CompressionLayer<impl Predicate> {
+    CompressionLayer::new()
+        .no_br()
+        .no_zstd()
+        .compress_when(DefaultPredicate::new().and(NotForContentType::const_new("application/zip")))
+}
+
+// CORS policy for browser clients served from other origins. `allowed_origins` comes from
+// `CORS_ALLOWED_ORIGINS`, a comma-separated list of origins (or `*`); when unset any origin is
+// allowed in debug builds and none in release builds.
+fn cors_layer(allowed_origins: Option<&[String]>) -> CorsLayer {
+    let allow_origin = match allowed_origins {
+        Some([any]) if any == "*" => AllowOrigin::any(),
+        Some(origins) => AllowOrigin::list(origins.iter().map(|origin| {
+            origin
+                .parse::<HeaderValue>()
+                .expect("`CORS_ALLOWED_ORIGINS` must be a comma-separated list of origins")
+        })),
+        None if cfg!(debug_assertions) => AllowOrigin::any(),
+        None => AllowOrigin::list([]),
+    };
+
+    CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_methods([
+            Method::GET,
+            Method::HEAD,
+            Method::POST,
+            Method::PUT,
+            Method::PATCH,
+            Method::DELETE,
+        ])
+        .allow_headers([
+            header::CONTENT_TYPE,
+            header::CONTENT_ENCODING,
+            header::AUTHORIZATION,
+            X_API_KEY,
+            header::IF_NONE_MATCH,
+            header::RANGE,
+            X_FILENAME,
+            TUS_RESUMABLE,
+            UPLOAD_LENGTH,
+            UPLOAD_OFFSET,
+        ])
+        .expose_headers([
+            header::CONTENT_TYPE,
+            header::CONTENT_LENGTH,
+            header::ETAG,
+            header::LAST_MODIFIED,
+            header::ACCEPT_RANGES,
+            header::CONTENT_RANGE,
+            header::LOCATION,
+            X_FILENAME,
+            TUS_RESUMABLE,
+            UPLOAD_EXPIRES,
+            UPLOAD_LENGTH,
+            UPLOAD_OFFSET,
+        ])
+}
+
+// Resolves when the process receives Ctrl+C or SIGTERM
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to install Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+// Middleware that turns away uploads from clients that exceed the `UPLOAD_RATE_LIMIT`
+async fn rate_limit(
+    State(state): State<AppState>,
+    ClientIp(client_ip): ClientIp,
+    request: Request,
+    next: Next,
+) -> Response {
+    if let Some(rate_limiter) = &state.rate_limiter {
+        if let Err(wait) = rate_limiter.check(client_ip) {
+            tracing::warn!("rate limit exceeded by {}", client_ip);
+            return (
+                [(header::RETRY_AFTER, wait.as_secs_f64().ceil().to_string())],
+                ApiError::TooManyRequests("Too many uploads, slow down".to_owned()),
+            )
+                .into_response();
+        }
+    }
+    next.run(request).await
+}
+
+// Middleware that counts the uploads turned away by the handlers and extractors behind it, by the
+// `Rejection` left on their responses. Serial numbers that are not valid are counted as empty,
+// as they could be anything.
+async fn count_rejections(
+    State(state): State<AppState>,
+    params: Option<Path<HashMap<String, String>>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let response = next.run(request).await;
+    if let Some(Rejection(reason)) = response.extensions().get::<Rejection>() {
+        // unauthenticated and traversal attempts can name any serial, so they are counted under
+        // none to keep the labels bounded
+        let serial_number = params
+            .as_ref()
+            .filter(|_| !matches!(*reason, "unauthorized" | "traversal"))
+            .and_then(|Path(params)| params.get("serial_number"))
+            .filter(|serial_number| serial_is_valid(serial_number))
+            .map_or("", String::as_str);
+        state.metrics.record_rejection(serial_number, reason);
+    }
+    response
+}
+
+// Answer to any other method than POST on the upload routes, telling probing clients what to use
+async fn upload_method_not_allowed() -> impl IntoResponse {
+    (
+        [(header::ALLOW, "POST")],
+        ApiError::MethodNotAllowed("images are uploaded with POST".to_owned()),
+    )
+}
+
+async fn put_method_not_allowed() -> impl IntoResponse {
+    (
+        [(header::ALLOW, "PUT")],
+        ApiError::MethodNotAllowed("images are uploaded under an upload ID with PUT".to_owned()),
+    )
+}
+
+// Handler that streams the request body to a file. An `X-Filename` header names the stored image
+// in place of the timestamp, when it is safe to use. With `?validate=true` the body is only checked
+// and counted, and the response tells the name it would be stored under. A body compressed with
+// gzip or deflate, as its `Content-Encoding` says, is stored decompressed.
+async fn save_request_body(
+    _: RequireApiKey,
+    State(state): State<AppState>,
+    ClientIp(client_ip): ClientIp,
+    Path(serial_number): Path<String>,
+    Query(query): Query<UploadQuery>,
+    request: Request,
+) -> Result<Response, ApiError> {
+    if !serial_is_valid(&serial_number) {
+        return Err(ApiError::InvalidPath("Invalid serial number".to_owned()));
+    }
+    // an honest `Content-Length` over the limit is turned away before any of the body is read; the
+    // byte count while storing still catches chunked bodies. A compressed body can decode to less,
+    // so its length says nothing about the image's.
+    let max_bytes = state.storage.max_bytes();
+    if !request.headers().contains_key(header::CONTENT_ENCODING)
+        && header_u64(request.headers(), &header::CONTENT_LENGTH)
+            .is_some_and(|length| length > max_bytes)
+    {
+        return Err(SaveError::TooLarge(max_bytes).into());
+    }
+
+    let requested_filename = request
+        .headers()
+        .get(X_FILENAME)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned);
+    let source = upload_source(client_ip, request.headers());
+    let (parts, body) = request.into_parts();
+    let body = decoded_body(&parts.headers, body)?;
+
+    if query.validate {
+        let body = StreamReader::new(body);
+        futures::pin_mut!(body);
+        let validated = state
+            .storage
+            .validate_image(&serial_number, requested_filename.as_deref(), &source, body)
+            .await
+            .map_err(ApiError::from)?;
+        return Ok(Json(ValidationResponse {
+            serial_number,
+            filename: validated.filename,
+            bytes: validated.bytes,
+            format: validated.format,
+            duplicate: validated.duplicate,
+        })
+        .into_response());
+    }
+
+    let span = upload_span(&serial_number, &source);
+    let started = Instant::now();
+    let result = stream_to_file(
+        &state,
+        &serial_number,
+        StoreAs::image(requested_filename.as_deref(), query.keep_history),
+        &source,
+        body,
+    )
+    .instrument(span.clone())
+    .await;
+    record_upload(&span, started, &result);
+    result.map(|response| Json(response).into_response())
+}
+
+// Handler for `PUT`s of the request body under an upload ID, for clients that retry until they
+// hear back. The image is stored as `upload-<upload_id>`, replacing the one a previous attempt
+// stored, and the answer is `201 Created` the first time and `200 OK` after that.
+async fn put_request_body(
+    _: RequireApiKey,
+    State(state): State<AppState>,
+    ClientIp(client_ip): ClientIp,
+    Path((serial_number, upload_id)): Path<(String, String)>,
+    request: Request,
+) -> Result<Response, ApiError> {
+    if !serial_is_valid(&serial_number) {
+        return Err(ApiError::InvalidPath("Invalid serial number".to_owned()));
+    }
+    if !serial_is_valid(&upload_id) {
+        return Err(SaveError::InvalidUploadId.into());
+    }
+    let max_bytes = state.storage.max_bytes();
+    if !request.headers().contains_key(header::CONTENT_ENCODING)
+        && header_u64(request.headers(), &header::CONTENT_LENGTH)
+            .is_some_and(|length| length > max_bytes)
+    {
+        return Err(SaveError::TooLarge(max_bytes).into());
+    }
+
+    let source = upload_source(client_ip, request.headers());
+    let (parts, body) = request.into_parts();
+    let body = decoded_body(&parts.headers, body)?;
+
+    let span = upload_span(&serial_number, &source);
+    let started = Instant::now();
+    let result = stream_to_file(
+        &state,
+        &serial_number,
+        StoreAs::Upload(&upload_id),
+        &source,
+        body,
+    )
+    .instrument(span.clone())
+    .await;
+    record_upload(&span, started, &result);
+    let response = result?;
+    let status = match response.replaced {
+        true => StatusCode::OK,
+        false => StatusCode::CREATED,
+    };
+    Ok((status, Json(response)).into_response())
+}
+
+// Handler for `multipart/form-data` uploads, as sent by browser forms. The first file part is
+// stored; its filename is kept when it is safe to use, otherwise the usual timestamp naming applies.
+// `?keep_history=false` only replaces the latest copy, as for bodies sent as they are.
+async fn save_multipart(
+    _: RequireApiKey,
+    State(state): State<AppState>,
+    ClientIp(client_ip): ClientIp,
+    Path(serial_number): Path<String>,
+    Query(query): Query<UploadQuery>,
+    headers: HeaderMap,
+    mut multipart: Multipart,
+) -> Result<Json<UploadResponse>, ApiError> {
+    if !serial_is_valid(&serial_number) {
+        return Err(ApiError::InvalidPath("Invalid serial number".to_owned()));
+    }
+
+    let source = upload_source(client_ip, &headers);
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|err| ApiError::BadRequest(err.to_string()))?
+    {
+        let Some(part_filename) = field.file_name() else {
+            continue;
+        };
+        check_part_content_type(&field)?;
+
+        let requested_filename = part_filename.to_owned();
+
+        let span = upload_span(&serial_number, &source);
+        let started = Instant::now();
+        let result = stream_to_file(
+            &state,
+            &serial_number,
+            StoreAs::image(Some(&requested_filename), query.keep_history),
+            &source,
+            field,
+        )
+        .instrument(span.clone())
+        .await;
+        record_upload(&span, started, &result);
+        return result.map(Json);
+    }
+
+    Err(ApiError::EmptyUpload(
+        "form contains no file part".to_owned(),
+    ))
+}
+
+// Handler for `multipart/form-data` uploads of several images at once. Every file part is stored
+// under its own timestamp name, in order, so the last one stored ends up as the latest. A part that
+// fails is reported in its entry of the response and the others are still stored.
+async fn save_multipart_batch(
+    _: RequireApiKey,
+    State(state): State<AppState>,
+    ClientIp(client_ip): ClientIp,
+    Path(serial_number): Path<String>,
+    headers: HeaderMap,
+    mut multipart: Multipart,
+) -> Result<Json<Vec<BatchPartResponse>>, ApiError> {
+    if !serial_is_valid(&serial_number) {
+        return Err(ApiError::InvalidPath("Invalid serial number".to_owned()));
+    }
+
+    let source = upload_source(client_ip, &headers);
+    let mut responses = Vec::new();
+    loop {
+        // the rest of a form that can not be parsed is lost, but the parts before it are stored
+        let field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(err) if responses.is_empty() => return Err(ApiError::BadRequest(err.to_string())),
+            Err(err) => {
+                let err = ApiError::BadRequest(err.to_string());
+                responses.push(BatchPartResponse {
+                    part: String::new(),
+                    outcome: BatchPartOutcome::Rejected {
+                        error: err.message().to_owned(),
+                        code: err.code(),
+                    },
+                });
+                break;
+            }
+        };
+        let Some(part_filename) = field.file_name().map(str::to_owned) else {
+            continue;
+        };
+
+        let result = match (
+            responses.len() < MAX_BATCH_FILES,
+            check_part_content_type(&field),
+        ) {
+            (false, _) => Err(ApiError::PayloadTooLarge(format!(
+                "a batch stores at most {} files",
+                MAX_BATCH_FILES
+            ))),
+            (true, Err(err)) => Err(err),
+            (true, Ok(())) => {
+                let span = upload_span(&serial_number, &source);
+                let started = Instant::now();
+                let result =
+                    stream_to_file(&state, &serial_number, StoreAs::Image(None), &source, field)
+                        .instrument(span.clone())
+                        .await;
+                record_upload(&span, started, &result);
+                result
+            }
+        };
+        responses.push(BatchPartResponse {
+            part: part_filename,
+            outcome: match result {
+                Ok(response) => BatchPartOutcome::Stored(response),
+                Err(err) => BatchPartOutcome::Rejected {
+                    error: err.message().to_owned(),
+                    code: err.code(),
+                },
+            },
+        });
+    }
+
+    if responses.is_empty() {
+        return Err(ApiError::EmptyUpload(
+            "form contains no file part".to_owned(),
+        ));
+    }
+    Ok(Json(responses))
+}
+
+// Turn away a file part its client says is not an image, without reading it
+fn check_part_content_type(field: &axum::extract::multipart::Field<'_>) -> Result<(), ApiError> {
+    match field.content_type() {
+        Some(content_type) if !content_type.starts_with("image/") => {
+            Err(ApiError::UnsupportedMedia(format!(
+                "file part has content type `{}`, expected an image",
+                content_type
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+// Span covering one upload. The outcome fields are filled in by `record_upload` once it is stored.
+fn upload_span(serial_number: &str, source: &UploadSource) -> tracing::Span {
+    tracing::info_span!(
+        "upload",
+        serial_number,
+        client_ip = source.client_ip.map(tracing::field::display),
+        bytes = tracing::field::Empty,
+        format = tracing::field::Empty,
+        elapsed_ms = tracing::field::Empty,
+    )
+}
+
+// POST `event` to the `UPLOAD_WEBHOOK`, in the background of the upload it is about. Failures
+// that may pass, no answer or a 5xx or 429 one, are tried again after a growing delay; the others
+// and the last one are only logged.
+async fn notify_webhook(config: Arc<Config>, event: UploadEvent) {
+    let Some(url) = config.upload_webhook.as_ref() else {
+        return;
+    };
+    let body = serde_json::to_vec(&event).expect("upload events serialize");
+    let mut delay = WEBHOOK_RETRY_DELAY;
+    for attempt in 1..=WEBHOOK_ATTEMPTS {
+        let transient = match webhook::post_json(url, &body, WEBHOOK_TIMEOUT).await {
+            Ok(status) if (200..300).contains(&status) => return,
+            Ok(status) => {
+                tracing::warn!("webhook {} answered {} for {}", url, status, event.filename);
+                status >= 500 || status == 429
+            }
+            Err(err) => {
+                tracing::warn!(
+                    "could not notify webhook {} of {}: {}",
+                    url,
+                    event.filename,
+                    err
+                );
+                true
+            }
+        };
+        if !transient || attempt == WEBHOOK_ATTEMPTS {
+            break;
+        }
+        tokio::time::sleep(delay).await;
+        delay *= 2;
+    }
+    tracing::warn!(
+        "gave up notifying webhook {} of {} for {}",
+        url,
+        event.filename,
+        event.serial_number
+    );
+}
+
+// Record the outcome of the upload in `span`, and log it as the single event of the span
+fn record_upload(
+    span: &tracing::Span,
+    started: Instant,
+    result: &Result<UploadResponse, ApiError>,
+) {
+    span.record("elapsed_ms", started.elapsed().as_millis() as u64);
+    let _entered = span.enter();
+    match result {
+        Ok(response) => {
+            span.record("bytes", response.bytes);
+            span.record("format", response.format.extension());
+            tracing::info!(duplicate = response.duplicate, "upload stored");
+        }
+        Err(err) => tracing::info!(
+            status = err.status().as_u16(),
+            code = err.code(),
+            "upload rejected: {}",
+            err.message()
+        ),
+    }
+}
+
+// Handler for the icon browsers ask for on every page, so that they stop logging a 404 for it
+async fn favicon() -> impl IntoResponse {
+    (
+        [
+            (header::CONTENT_TYPE, "image/x-icon"),
+            (header::CACHE_CONTROL, "public, max-age=86400"),
+        ],
+        FAVICON,
+    )
+}
+
+// Handler for paths no route matches, a page pointing back home rather than an empty body
+async fn not_found() -> (StatusCode, Html<&'static str>) {
+    (
+        StatusCode::NOT_FOUND,
+        Html(
+            r#"
+        <!doctype html>
+        <html>
+            <head>
+                <title>Not found</title>
+            </head>
+            <body>
+                <h1>Not found</h1>
+                <p>There is nothing at this address. <a href="/">Upload an image</a> instead.</p>
+            </body>
+        </html>
+        "#,
+        ),
+    )
+}
+
+// Handler for what the storage backends other than `fs` do not serve, such as the gallery
+async fn not_implemented(State(config): State<Arc<Config>>) -> ApiError {
+    ApiError::NotImplemented(format!(
+        "Not available with the `{}` storage backend",
+        config.storage_backend.name()
+    ))
+}
+
+// Handler that returns HTML for the home page: a form that uploads an image the same way the
+// cameras do and then shows the latest image of that serial number.
+async fn home() -> Html<&'static str> {
+    Html(
+        r#"
+        <!doctype html>
+        <html>
+            <head>
+                <title>Upload images</title>
+            </head>
+            <body>
+                <h1>Upload images</h1>
+                <form id="upload">
+                    <label>Serial number <input id="serial" required pattern="[A-Za-z0-9_-]+"></label>
+                    <input id="file" type="file" accept="image/*" required>
+                    <label>API key <input id="api-key" type="password" placeholder="if required"></label>
+                    <button type="submit">Upload</button>
+                </form>
+                <progress id="progress" max="100" value="0" hidden></progress>
+                <p id="status"></p>
+                <img id="latest" alt="" style="max-width: 100%">
+                <script>
+                    const form = document.getElementById("upload");
+                    const progress = document.getElementById("progress");
+                    const status = document.getElementById("status");
+                    const latest = document.getElementById("latest");
+
+                    form.addEventListener("submit", (event) => {
+                        event.preventDefault();
+                        const serial = document.getElementById("serial").value;
+                        const file = document.getElementById("file").files[0];
+                        const apiKey = document.getElementById("api-key").value;
+
+                        const request = new XMLHttpRequest();
+                        request.open("POST", "/upload/" + encodeURIComponent(serial));
+                        if (apiKey) {
+                            request.setRequestHeader("Authorization", "Bearer " + apiKey);
+                        }
+                        request.upload.onprogress = (event) => {
+                            if (event.lengthComputable) {
+                                progress.value = 100 * event.loaded / event.total;
+                            }
+                        };
+                        request.onload = () => {
+                            progress.hidden = true;
+                            if (request.status !== 200) {
+                                let message = request.responseText;
+                                try {
+                                    message = JSON.parse(message).error;
+                                } catch {}
+                                status.textContent = "Upload failed: " + message;
+                                return;
+                            }
+                            const saved = JSON.parse(request.responseText);
+                            status.textContent = "Saved " + saved.filename + " (" + saved.bytes + " bytes)";
+                            latest.src = "/latest/" + encodeURIComponent(serial) + "?t=" + Date.now();
+                        };
+                        request.onerror = () => {
+                            progress.hidden = true;
+                            status.textContent = "Upload failed: network error";
+                        };
+
+                        progress.value = 0;
+                        progress.hidden = false;
+                        status.textContent = "Uploading...";
+                        request.send(file);
+                    });
+                </script>
+            </body>
+        </html>
+        "#,
+    )
+}
+
+// Handler for load balancer probes: healthy as long as the uploads directory can be written to.
+async fn health(State(state): State<AppState>) -> (StatusCode, Json<serde_json::Value>) {
+    match tokio::fs::metadata(state.storage.uploads_dir()).await {
+        Ok(metadata) if metadata.is_dir() && !metadata.permissions().readonly() => {
+            (StatusCode::OK, Json(serde_json::json!({ "status": "ok" })))
+        }
+        Ok(_) => unhealthy("uploads directory is not a writable directory".to_owned()),
+        Err(err) => unhealthy(format!("uploads directory is unavailable: {}", err)),
+    }
+}
+
+// Handler for load balancer probes when images are not kept on disk. The store is not probed: an
+// unreachable object store fails the uploads rather than the instance.
+async fn image_store_health() -> Json<serde_json::Value> {
+    Json(serde_json::json!({ "status": "ok" }))
+}
+
+// Response that takes the instance out of rotation
+fn unhealthy(reason: String) -> (StatusCode, Json<serde_json::Value>) {
+    tracing::warn!("health check failed: {}", reason);
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        Json(serde_json::json!({ "status": "unavailable", "reason": reason })),
+    )
+}
+
+// Handler that tells which build is running: crate version, git commit and build time
+async fn version() -> Json<serde_json::Value> {
+    Json(upload_image::version::build_info())
+}
+
+// Handler that exposes the upload metrics to Prometheus.
+async fn metrics(State(state): State<AppState>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.metrics.render(),
+    )
+}
+
+// Handler that reports the settings the server runs with, to check what a deployment picked up.
+// The API key itself is never shown, only whether one is required, and neither is the webhook URL
+// as it may carry a token of its own.
+async fn show_config(
+    _: RequireApiKey,
+    State(config): State<Arc<Config>>,
+) -> Json<serde_json::Value> {
+    let image::Rgb([r, g, b]) = config.thumbnail_background;
+    Json(serde_json::json!({
+        "uploads_dir": config.uploads_dir,
+        "storage_backend": config.storage_backend.name(),
+        "s3": config.s3_bucket.as_ref().map(|bucket| serde_json::json!({
+            "bucket": bucket,
+            "prefix": config.s3_prefix,
+            "endpoint": config.s3_endpoint,
+            "force_path_style": config.s3_force_path_style,
+        })),
+        "bind_addr": config.bind_addr.to_string(),
+        "max_upload_bytes": config.max_upload_bytes,
+        "max_megapixels": config.max_megapixels,
+        "auth": config.api_key.is_some(),
+        "browse_auth": config.browse_credentials().is_some(),
+        "tls": config.tls().is_some(),
+        "webp_quality": config.webp_quality(),
+        "allowed_formats": config.allowed_formats,
+        "jpeg_max_bytes": config.jpeg_max_bytes,
+        "strip_metadata": config.strip_metadata,
+        "upload_rate_limit": config.upload_rate_limit().map(|limit| serde_json::json!({
+            "per_second": limit.per_second,
+            "burst": limit.burst,
+        })),
+        "frame_rate_limit": config.frame_rate_limit().map(|limit| serde_json::json!({
+            "per_second": limit.per_second,
+            "burst": limit.burst,
+        })),
+        "ping_interval_secs": config.ping_interval_secs,
+        "idle_timeout_secs": config.idle_timeout_secs,
+        "max_concurrent_uploads": config.max_concurrent_uploads,
+        "reject_when_busy": config.reject_when_busy,
+        "write_buffer_kb": config.write_buffer_kb,
+        "upload_timeout_secs": config.upload_timeout_secs,
+        "tus_expiry_secs": config.tus_expiry_secs,
+        "max_tus_uploads": config.max_tus_uploads,
+        "fsync": config.fsync,
+        "serial_quota_bytes": config.serial_quota_bytes,
+        "evict_oldest": config.evict_oldest,
+        "upload_webhook": config.upload_webhook.is_some(),
+        "near_duplicate_distance": config.near_duplicate_distance,
+        "serial_overrides": config.serial_overrides,
+        "trust_forwarded_for": config.trust_forwarded_for,
+        "trusted_proxies": config
+            .trusted_proxies
+            .as_ref()
+            .map(|proxies| proxies.iter().map(IpCidr::to_string).collect::<Vec<_>>()),
+        "naming": config.naming.name(),
+        "layout": config.layout.name(),
+        "filename_time_format": config.filename_time_format,
+        "use_utc": config.use_utc,
+        "latest_filename": config.latest_filename.stem(),
+        "file_mode": config.file_mode.map(|mode| format!("{:04o}", mode)),
+        "dir_mode": config.dir_mode.map(|mode| format!("{:04o}", mode)),
+        "thumbnail_background": format!("{:02x}{:02x}{:02x}", r, g, b),
+        "shutdown_timeout_secs": config.shutdown_timeout_secs,
+        "cors_allowed_origins": config.cors_allowed_origins,
+    }))
+}
+
+// Handler that lists the images stored for a serial number, newest first. With `?sidecars=true`
+// each image comes with its sidecar, when it has one.
+async fn list_images(
+    State(state): State<AppState>,
+    Path(serial_number): Path<String>,
+    Query(query): Query<ListQuery>,
+) -> Result<Json<Vec<ImageEntry>>, ApiError> {
+    if !serial_is_valid(&serial_number) {
+        return Err(ApiError::InvalidPath("Invalid serial number".to_owned()));
+    }
+
+    let dir = state.storage.serial_dir(&serial_number);
+    let images = async {
+        let mut images = read_images(&dir, &state.storage, query.sort).await?;
+        if query.sidecars {
+            for image in &mut images {
+                image.sidecar = read_sidecar(&dir, &image.filename).await?;
+            }
+        }
+        Ok::<_, io::Error>(images)
+    }
+    .await;
+    match images {
+        Ok(images) => Ok(Json(images)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            Err(ApiError::NotFound("Unknown serial number".to_owned()))
+        }
+        Err(err) => Err(ApiError::Internal(err.to_string())),
+    }
+}
+
+// Handler that streams the request body to the image store. An `X-Filename` header names the
+// stored image like it does for `save_request_body`, and a compressed body is stored decompressed.
+async fn put_in_store(
+    _: RequireApiKey,
+    State(state): State<AppState>,
+    ClientIp(client_ip): ClientIp,
+    Path(serial_number): Path<String>,
+    request: Request,
+) -> Result<Json<UploadResponse>, ApiError> {
+    if !serial_is_valid(&serial_number) {
+        return Err(ApiError::InvalidPath("Invalid serial number".to_owned()));
+    }
+    let max_bytes = state.storage.max_bytes();
+    if !request.headers().contains_key(header::CONTENT_ENCODING)
+        && header_u64(request.headers(), &header::CONTENT_LENGTH)
+            .is_some_and(|length| length > max_bytes)
+    {
+        return Err(SaveError::TooLarge(max_bytes).into());
+    }
+
+    let requested_filename = request
+        .headers()
+        .get(X_FILENAME)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned);
+    let source = upload_source(client_ip, request.headers());
+    let (parts, body) = request.into_parts();
+    let mut body = StreamReader::new(decoded_body(&parts.headers, body)?);
+
+    let span = upload_span(&serial_number, &source);
+    let started = Instant::now();
+    let result = async {
+        let saved = state
+            .store
+            .put(
+                &serial_number,
+                requested_filename.as_deref(),
+                &source,
+                &mut body,
+            )
+            .await?;
+        publish_upload(&state, &serial_number, &saved);
+        Ok(UploadResponse {
+            serial_number: serial_number.clone(),
+            filename: saved.filename,
+            bytes: saved.bytes,
+            format: saved.format,
+            duplicate: saved.duplicate,
+            width: None,
+            height: None,
+            replaced: saved.replaced,
+        })
+    }
+    .instrument(span.clone())
+    .await;
+    state.metrics.record_upload(
+        &serial_number,
+        result.as_ref().ok().map(|response| response.bytes),
+    );
+    record_upload(&span, started, &result);
+    result.map(Json)
+}
+
+// Handler that lists the images in the image store for a serial number, newest first
+async fn list_in_store(
+    State(state): State<AppState>,
+    Path(serial_number): Path<String>,
+) -> Result<Json<Vec<ImageEntry>>, ApiError> {
+    if !serial_is_valid(&serial_number) {
+        return Err(ApiError::InvalidPath("Invalid serial number".to_owned()));
+    }
+    let mut entries = state.store.list(&serial_number).await?;
+    if entries.is_empty() {
+        return Err(ApiError::NotFound("Unknown serial number".to_owned()));
+    }
+    entries.sort_by(|a, b| {
+        b.modified
+            .cmp(&a.modified)
+            .then_with(|| b.filename.cmp(&a.filename))
+    });
+    Ok(Json(
+        entries
+            .into_iter()
+            .map(|entry| ImageEntry {
+                filename: entry.filename,
+                size: entry.size,
+                modified: entry.modified.to_rfc3339(),
+                sidecar: None,
+            })
+            .collect(),
+    ))
+}
+
+// Handler for an image in the image store
+async fn read_from_store(
+    State(state): State<AppState>,
+    Path((serial_number, filename)): Path<(String, String)>,
+) -> Result<Response, ApiError> {
+    if !serial_is_valid(&serial_number) || !relative_path_is_valid(&filename) {
+        return Err(ApiError::InvalidPath("Invalid image path".to_owned()));
+    }
+    match state.store.get(&serial_number, &filename).await? {
+        Some(bytes) => Ok(image_response(bytes)),
+        None => Err(ApiError::NotFound("No such image".to_owned())),
+    }
+}
+
+// Handler for the latest copy of a serial number in the image store
+async fn latest_in_store(
+    State(state): State<AppState>,
+    Path(serial_number): Path<String>,
+) -> Result<Response, ApiError> {
+    if !serial_is_valid(&serial_number) {
+        return Err(ApiError::InvalidPath("Invalid serial number".to_owned()));
+    }
+    match state.store.latest(&serial_number).await? {
+        Some(bytes) => Ok(image_response(bytes)),
+        None => Err(ApiError::NotFound(
+            "No image for this serial number".to_owned(),
+        )),
+    }
+}
+
+// `bytes` of an image, with the content type of the format they are in
+fn image_response(bytes: Vec<u8>) -> Response {
+    let content_type =
+        detect_image_format(&bytes).map_or("application/octet-stream", ImageFormat::mime_type);
+    (
+        [
+            (header::CONTENT_TYPE, content_type),
+            (header::CACHE_CONTROL, "no-cache"),
+        ],
+        bytes,
+    )
+        .into_response()
+}
+
+// Handler that lists the serial numbers with a directory of images, in order, with how many images
+// each has and when the last of them was stored
+async fn list_serials(State(state): State<AppState>) -> Result<Json<Vec<SerialEntry>>, ApiError> {
+    let mut serials = match read_serials(state.storage.uploads_dir()).await {
+        Ok(serials) => serials,
+        // nothing was uploaded yet
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(err) => return Err(err.into()),
+    };
+    serials.sort();
+
+    let mut entries = Vec::new();
+    for serial_number in serials {
+        let serial_dir = state.storage.serial_dir(&serial_number);
+        let images = match stored_images(&serial_dir, state.storage.latest_name()).await {
+            Ok(images) => images,
+            // removed since it was listed
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err.into()),
+        };
+        let latest = images
+            .iter()
+            .filter_map(|(_, metadata)| metadata.modified().ok())
+            .max()
+            .map(|modified| DateTime::<Utc>::from(modified).to_rfc3339());
+        entries.push(SerialEntry {
+            serial_number,
+            images: images.len(),
+            latest,
+        });
+    }
+    Ok(Json(entries))
+}
+
+// Handler that pages through the images of every serial number, or of `serial`, newest first.
+// Images named by the client have no timestamp, so they sort last and `since` leaves them out,
+// unless `?sort=mtime` goes by the modification times instead.
+async fn gallery(
+    State(state): State<AppState>,
+    Query(query): Query<GalleryQuery>,
+) -> Result<Json<GalleryPage>, ApiError> {
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_GALLERY_LIMIT)
+        .min(MAX_GALLERY_LIMIT);
+    let since = match &query.since {
+        Some(since) => Some(
+            parse_since(since, state.storage.filename_time()).ok_or_else(|| {
+                ApiError::BadRequest(format!(
+                    "`since` must be an RFC 3339 time or one written as `{}`",
+                    state.storage.filename_time().format()
+                ))
+            })?,
+        ),
+        None => None,
+    };
+    let serials = match query.serial {
+        Some(serial) if !serial_is_valid(&serial) => {
+            return Err(ApiError::InvalidPath("Invalid serial number".to_owned()));
+        }
+        Some(serial) => vec![serial],
+        None => read_serials(state.storage.uploads_dir())
+            .await
+            .map_err(ApiError::from)?,
+    };
+
+    let mut entries = Vec::new();
+    let mut scanned = 0;
+    let mut truncated = false;
+    'walk: for serial in serials {
+        let serial_dir = state.storage.serial_dir(&serial);
+        let images = match stored_images(&serial_dir, state.storage.latest_name()).await {
+            Ok(images) => images,
+            // a single unknown serial number makes for an empty gallery
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(ApiError::Internal(err.to_string())),
+        };
+        for (filename, metadata) in images {
+            scanned += 1;
+            if scanned > GALLERY_MAX_SCANNED {
+                truncated = true;
+                break 'walk;
+            }
+            let timestamp = match query.sort {
+                ListSort::Name => state.storage.filename_time().of_filename(&filename),
+                ListSort::Mtime => metadata.modified().ok().map(DateTime::<Utc>::from),
+            };
+            if since.is_some() && timestamp < since {
+                continue;
+            }
+            entries.push((timestamp, serial.clone(), filename, metadata.len()));
+        }
+    }
+    if truncated {
+        tracing::warn!(
+            "gallery gave up after {} directory entries",
+            GALLERY_MAX_SCANNED
+        );
+    }
+
+    // newest first, names without a timestamp last
+    entries.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| (&a.1, &a.2).cmp(&(&b.1, &b.2))));
+    Ok(Json(GalleryPage {
+        total: entries.len(),
+        offset: query.offset,
+        limit,
+        truncated,
+        entries: entries
+            .into_iter()
+            .skip(query.offset)
+            .take(limit)
+            .map(|(timestamp, serial, filename, size)| GalleryEntry {
+                serial,
+                filename,
+                size,
+                timestamp: timestamp.map(|timestamp| timestamp.with_timezone(&Local).to_rfc3339()),
+            })
+            .collect(),
+    }))
+}
+
+// Lower bound of the gallery, an RFC 3339 time or one written the way filenames write it
+fn parse_since(since: &str, filename_time: &FilenameTime) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(since)
+        .map(|since| since.with_timezone(&Utc))
+        .ok()
+        .or_else(|| filename_time.parse(since))
+}
+
+// Serial numbers with a directory in the uploads directory `dir`
+async fn read_serials(dir: &std::path::Path) -> io::Result<Vec<String>> {
+    let mut dir = tokio::fs::read_dir(dir).await?;
+    let mut serials = Vec::new();
+    while let Some(entry) = dir.next_entry().await? {
+        let Ok(serial) = entry.file_name().into_string() else {
+            continue;
+        };
+        // leaves out the directory of unfinished resumable uploads
+        if serial_is_valid(&serial) && entry.file_type().await?.is_dir() {
+            serials.push(serial);
+        }
+    }
+    Ok(serials)
+}
+
+// Handler that lays the `count` newest images of a serial number out in a JPEG grid of `cols`
+// columns, for scrubbing through them at a glance. A sheet is served again as long as the newest
+// images and their modification times stay the same, so only a new upload makes another one.
+async fn contact_sheet(
+    State(state): State<AppState>,
+    Path(serial_number): Path<String>,
+    Query(query): Query<ContactSheetQuery>,
+) -> Result<Response, ApiError> {
+    if !serial_is_valid(&serial_number) {
+        return Err(ApiError::InvalidPath("Invalid serial number".to_owned()));
+    }
+    let cols = query.cols.unwrap_or(DEFAULT_CONTACT_SHEET_COLS);
+    if !(1..=MAX_CONTACT_SHEET_COLS).contains(&cols) {
+        return Err(ApiError::BadRequest(format!(
+            "`cols` must be from 1 to {}",
+            MAX_CONTACT_SHEET_COLS
+        )));
+    }
+    let count = query.count.unwrap_or(DEFAULT_CONTACT_SHEET_COUNT);
+    if !(1..=MAX_CONTACT_SHEET_COUNT).contains(&count) {
+        return Err(ApiError::BadRequest(format!(
+            "`count` must be from 1 to {}",
+            MAX_CONTACT_SHEET_COUNT
+        )));
+    }
+
+    let dir = state.storage.serial_dir(&serial_number);
+    let mut images = match read_images(&dir, &state.storage, ListSort::Name).await {
+        Ok(images) => images,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            return Err(ApiError::NotFound("Unknown serial number".to_owned()))
+        }
+        Err(err) => return Err(ApiError::Internal(err.to_string())),
+    };
+    images.truncate(count);
+    if images.is_empty() {
+        return Err(ApiError::NotFound(
+            "No images stored for this serial number".to_owned(),
+        ));
+    }
+
+    let key = (
+        cols,
+        images
+            .iter()
+            .map(|image| (image.filename.clone(), image.modified.clone()))
+            .collect(),
+    );
+    let jpeg = match state.contact_sheets.get(&serial_number, &key) {
+        Some(jpeg) => jpeg,
+        None => {
+            // thumbnails decode much faster than the images they were made from
+            let mut sources = Vec::with_capacity(images.len());
+            for image in &images {
+                let thumbnail = dir.join(thumbnail_filename(&image.filename));
+                sources.push(match tokio::fs::try_exists(&thumbnail).await {
+                    Ok(true) => thumbnail,
+                    _ => dir.join(&image.filename),
+                });
+            }
+            let background = state.config.thumbnail_background;
+            let jpeg = tokio::task::spawn_blocking(move || {
+                compose_contact_sheet(&sources, cols, background)
+            })
+            .await
+            .map_err(|err| ApiError::Internal(err.to_string()))?
+            .map_err(|err| ApiError::Internal(err.to_string()))?;
+            let jpeg = Bytes::from(jpeg);
+            state
+                .contact_sheets
+                .insert(serial_number, key, jpeg.clone());
+            jpeg
+        }
+    };
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, ImageFormat::Jpeg.mime_type()),
+            // the sheet changes with every upload, so caches must always revalidate
+            (header::CACHE_CONTROL, "no-cache"),
+        ],
+        jpeg,
+    )
+        .into_response())
+}
+
+// Handler that streams every image of a serial number as one zip archive, for backups. The archive
+// is produced while it is sent, so only one image at a time is read into memory.
+async fn archive_images(
+    State(state): State<AppState>,
+    Path(serial_number): Path<String>,
+) -> Result<Response, ApiError> {
+    if !serial_is_valid(&serial_number) {
+        return Err(ApiError::InvalidPath("Invalid serial number".to_owned()));
+    }
+
+    let dir = state.storage.serial_dir(&serial_number);
+    let images = match read_images(&dir, &state.storage, ListSort::Name).await {
+        Ok(images) => images,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            return Err(ApiError::NotFound("Unknown serial number".to_owned()));
+        }
+        Err(err) => return Err(ApiError::Internal(err.to_string())),
+    };
+
+    // the archive is written into one end of a pipe while the response body reads the other
+    let (writer, reader) = tokio::io::duplex(64 * 1024);
+    let content_disposition = format!("attachment; filename=\"{}.zip\"", serial_number);
+    tokio::spawn(async move {
+        if let Err(err) = write_archive(&dir, &images, writer).await {
+            tracing::warn!("archive of {} aborted: {}", serial_number, err);
+        }
+    });
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/zip".to_owned()),
+            (header::CONTENT_DISPOSITION, content_disposition),
+        ],
+        Body::from_stream(ReaderStream::new(reader)),
+    )
+        .into_response())
+}
+
+// Write `images` of the serial directory `dir` to `writer` as a zip archive. Images are compressed
+// already, so they are stored as they are.
+async fn write_archive<W>(
+    dir: &std::path::Path,
+    images: &[ImageEntry],
+    writer: W,
+) -> Result<(), BoxError>
+where
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    let mut archive = ZipFileWriter::with_tokio(writer);
+    for image in images {
+        let file = match File::open(dir.join(&image.filename)).await {
+            Ok(file) => file,
+            // deleted since the listing was read
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err.into()),
+        };
+        let modified = DateTime::<Utc>::from(file.metadata().await?.modified()?);
+        let entry = ZipEntryBuilder::new(image.filename.clone().into(), Compression::Stored)
+            .last_modification_date(ZipDateTime::from_chrono(&modified));
+        let mut entry_writer = archive.write_entry_stream(entry).await?;
+        futures::io::copy(file.compat(), &mut entry_writer).await?;
+        entry_writer.close().await?;
+    }
+    archive.close().await?;
+    Ok(())
+}
+
+// Handler that upgrades to a websocket images are pushed on, as the websocket server takes them:
+// the serial number as text, then the image as binary messages up to an `END`
+async fn ws_upload(
+    _: RequireApiKey,
+    State(state): State<AppState>,
+    ClientIp(client_ip): ClientIp,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    ws: WebSocketUpgrade,
+) -> Response {
+    let source = upload_source(client_ip, &headers);
+    ws::upgrade(ws, addr, source, state.socket.clone())
+}
+
+// Handler that upgrades to a websocket on which a JSON `UploadEvent` is sent each time an image is
+// stored for the serial number. Anything the client sends is ignored.
+async fn upload_events(
+    State(state): State<AppState>,
+    Path(serial_number): Path<String>,
+    ws: WebSocketUpgrade,
+) -> Result<Response, ApiError> {
+    if !serial_is_valid(&serial_number) {
+        return Err(ApiError::InvalidPath("Invalid serial number".to_owned()));
+    }
+    let events = state.upload_events.subscribe();
+    Ok(ws.on_upgrade(move |socket| send_upload_events(socket, serial_number, events)))
+}
+
+async fn send_upload_events(
+    mut socket: WebSocket,
+    serial_number: String,
+    events: broadcast::Receiver<UploadEvent>,
+) {
+    let events = serial_upload_events(events, serial_number);
+    futures::pin_mut!(events);
+    loop {
+        tokio::select! {
+            event = events.next() => {
+                let Some(event) = event else {
+                    return;
+                };
+                let message = serde_json::to_string(&event).expect("events serialize to JSON");
+                if socket.send(Message::Text(message)).await.is_err() {
+                    return;
+                }
+            }
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_)) | Err(_)) | None => return,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+}
+
+// Handler for a `text/event-stream` with an `upload` event for each image stored for the serial
+// number, the same `UploadEvent` JSON as on the websocket. A comment goes out every 15 seconds so
+// that proxies keep idle streams open.
+async fn upload_event_stream(
+    State(state): State<AppState>,
+    Path(serial_number): Path<String>,
+) -> Result<Sse<impl Stream<Item = Result<Event, axum::Error>>>, ApiError> {
+    if !serial_is_valid(&serial_number) {
+        return Err(ApiError::InvalidPath("Invalid serial number".to_owned()));
+    }
+    let events = serial_upload_events(state.upload_events.subscribe(), serial_number)
+        .map(|event| Event::default().event("upload").json_data(event));
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+// The events of `serial_number` received on `events`, ending when the server shuts down. A
+// subscriber too slow to keep up skips the uploads it missed.
+fn serial_upload_events(
+    events: broadcast::Receiver<UploadEvent>,
+    serial_number: String,
+) -> impl Stream<Item = UploadEvent> {
+    futures::stream::unfold(events, move |mut events| {
+        let serial_number = serial_number.clone();
+        async move {
+            loop {
+                match events.recv().await {
+                    Ok(event) if event.serial_number == serial_number => {
+                        return Some((event, events));
+                    }
+                    Ok(_) => {}
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        tracing::debug!(
+                            "event stream of {} missed {} uploads",
+                            serial_number,
+                            missed
+                        );
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        }
+    })
+}
+
+// Handler that returns the newest image of a serial number, for a stable URL that does not depend
+// on how the latest copy is named on disk. `HEAD` answers with the same headers and no body, for
+// clients polling for a new image. A single byte `Range` is answered with `206 Partial Content`.
+async fn latest_image(
+    State(state): State<AppState>,
+    method: Method,
+    Path(serial_number): Path<String>,
+    if_none_match: Option<TypedHeader<IfNoneMatch>>,
+    range: Option<TypedHeader<Range>>,
+) -> Result<Response, ApiError> {
+    if !serial_is_valid(&serial_number) {
+        return Err(ApiError::InvalidPath("Invalid serial number".to_owned()));
+    }
+
+    let dir = state.storage.serial_dir(&serial_number);
+    let latest = async {
+        for format in ImageFormat::ALL {
+            let path = dir.join(state.storage.latest_name().filename(format));
+            match File::open(&path).await {
+                // the sniffed format wins over the extension
+                Ok(file) => {
+                    let format = detect_file_format(&path).await?.unwrap_or(format);
+                    return Ok(Some((file, format)));
+                }
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok::<_, io::Error>(None)
+    }
+    .await
+    .map_err(ApiError::from)?;
+    let Some((mut file, format)) = latest else {
+        return Err(ApiError::NotFound(
+            "No image for this serial number".to_owned(),
+        ));
+    };
+
+    // the latest changes with every upload, so caches must always revalidate
+    let cache_control = [(header::CACHE_CONTROL, "no-cache")];
+    let metadata = file.metadata().await.map_err(ApiError::from)?;
+    let etag = file_etag(&metadata);
+    if let (Some(TypedHeader(if_none_match)), Some(etag)) = (&if_none_match, &etag) {
+        if !if_none_match.precondition_passes(etag) {
+            return Ok((
+                StatusCode::NOT_MODIFIED,
+                cache_control,
+                TypedHeader(etag.clone()),
+            )
+                .into_response());
+        }
+    }
+
+    let last_modified = metadata.modified().ok().map(LastModified::from);
+    let len = metadata.len();
+    let (status, content_range, start, served_len) =
+        match requested_range(range.as_ref().map(|TypedHeader(range)| range), len) {
+            RequestedRange::Full => (StatusCode::OK, None, 0, len),
+            RequestedRange::Partial(start, end) => (
+                StatusCode::PARTIAL_CONTENT,
+                Some(format!("bytes {}-{}/{}", start, end, len)),
+                start,
+                end - start + 1,
+            ),
+            RequestedRange::Unsatisfiable => {
+                return Ok((
+                    [(header::CONTENT_RANGE, format!("bytes */{}", len))],
+                    ApiError::RangeNotSatisfiable(format!("The image is {} bytes long", len)),
+                )
+                    .into_response());
+            }
+        };
+    let body = match method {
+        Method::HEAD => Body::empty(),
+        _ => {
+            file.seek(io::SeekFrom::Start(start))
+                .await
+                .map_err(ApiError::from)?;
+            Body::from_stream(ReaderStream::new(file.take(served_len)))
+        }
+    };
+    Ok((
+        status,
+        [
+            (header::CONTENT_TYPE, format.mime_type()),
+            (header::ACCEPT_RANGES, "bytes"),
+        ],
+        cache_control,
+        content_range.map(|content_range| [(header::CONTENT_RANGE, content_range)]),
+        TypedHeader(ContentLength(served_len)),
+        last_modified.map(TypedHeader),
+        etag.map(TypedHeader),
+        body,
+    )
+        .into_response())
+}
+
+// What a `Range` header asks of a file of `len` bytes
+enum RequestedRange {
+    Full,
+    // first and last byte, both inclusive
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+// Only a single range is served partially, a request for several gets the whole file
+fn requested_range(range: Option<&Range>, len: u64) -> RequestedRange {
+    let Some(range) = range else {
+        return RequestedRange::Full;
+    };
+    let ranges: Vec<_> = range.satisfiable_ranges(len).collect();
+    let [(start, end)] = ranges[..] else {
+        return match ranges.is_empty() {
+            true => RequestedRange::Unsatisfiable,
+            false => RequestedRange::Full,
+        };
+    };
+    let start = match start {
+        Bound::Included(start) => start,
+        Bound::Excluded(start) => start + 1,
+        Bound::Unbounded => 0,
+    };
+    let end = match end {
+        Bound::Included(end) => end.min(len.saturating_sub(1)),
+        Bound::Excluded(end) => end.min(len).saturating_sub(1),
+        Bound::Unbounded => len.saturating_sub(1),
+    };
+    match start < len && start <= end {
+        true => RequestedRange::Partial(start, end),
+        false => RequestedRange::Unsatisfiable,
+    }
+}
+
+// Middleware that adds an `ETag` to the images served from disk, and answers `304 Not Modified`
+// when the client already has the current version
+async fn image_etag(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let etag = match served_file_path(&state, &request) {
+        Some(path) => tokio::fs::metadata(path)
+            .await
+            .ok()
+            .filter(|metadata| metadata.is_file())
+            .and_then(|metadata| file_etag(&metadata)),
+        None => None,
+    };
+    let Some(etag) = etag else {
+        return next.run(request).await;
+    };
+
+    if let Some(if_none_match) = request.headers().typed_get::<IfNoneMatch>() {
+        if !if_none_match.precondition_passes(&etag) {
+            return (StatusCode::NOT_MODIFIED, TypedHeader(etag)).into_response();
+        }
+    }
+    let mut response = next.run(request).await;
+    if response.status() == StatusCode::OK {
+        response.headers_mut().typed_insert(etag);
+    }
+    response
+}
+
+// Middleware that labels the images served from disk with the format sniffed from their first
+// bytes, since the extension of files stored by older versions does not always match
+async fn image_content_type(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let path = served_file_path(&state, &request);
+    let mut response = next.run(request).await;
+    if let (Some(path), StatusCode::OK | StatusCode::PARTIAL_CONTENT) = (path, response.status()) {
+        // a file that is not an image, such as `hashes.json`, keeps the type it was served with
+        if let Ok(Some(format)) = detect_file_format(&path).await {
+            response.headers_mut().insert(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static(format.mime_type()),
+            );
+        }
+    }
+    response
+}
+
+// File in the uploads directory that a `GET` or `HEAD` of `/images/<serial_number>/<filename>`
+// serves, when the path is a safe one
+fn served_file_path(state: &AppState, request: &Request) -> Option<PathBuf> {
+    if !matches!(*request.method(), Method::GET | Method::HEAD) {
+        return None;
+    }
+    let (serial_number, filename) = request
+        .uri()
+        .path()
+        .trim_start_matches('/')
+        .split_once('/')?;
+    (serial_is_valid(serial_number) && relative_path_is_valid(filename))
+        .then(|| state.storage.serial_dir(serial_number).join(filename))
+}
+
+// Validator for a stored file, from its modification time and size
+fn file_etag(metadata: &std::fs::Metadata) -> Option<ETag> {
+    let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    format!("\"{:x}-{:x}\"", modified.as_nanos(), metadata.len())
+        .parse()
+        .ok()
+}
+
+// Handler that deletes a stored image. When it was the newest one, the latest copy is moved to the
+// image that is now the newest, or removed if none is left.
+async fn delete_image(
+    _: RequireApiKey,
+    State(state): State<AppState>,
+    Path((serial_number, filename)): Path<(String, String)>,
+) -> Result<StatusCode, ApiError> {
+    if !serial_is_valid(&serial_number)
+        || !relative_path_is_valid(&filename)
+        || state.storage.latest_name().matches(&filename)
+    {
+        return Err(ApiError::InvalidPath("Invalid path".to_owned()));
+    }
+
+    // an upload finishing meanwhile would otherwise race us for the latest copy
+    let _latest_guard = state.storage.lock_latest(&serial_number).await;
+
+    let dir = state.storage.serial_dir(&serial_number);
+    let images = match read_images(&dir, &state.storage, ListSort::Name).await {
+        Ok(images) => images,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            return Err(ApiError::NotFound("Unknown serial number".to_owned()));
+        }
+        Err(err) => return Err(ApiError::Internal(err.to_string())),
+    };
+    let Some(position) = images.iter().position(|image| image.filename == filename) else {
+        return Err(ApiError::NotFound("Unknown image".to_owned()));
+    };
+
+    async {
+        let latest_name = state.storage.latest_name();
+        let was_latest = match latest_target(&dir, latest_name).await? {
+            Some(target) => target == filename,
+            None => position == 0 && latest_is_copy_of(&dir, latest_name, &filename).await?,
+        };
+
+        tokio::fs::remove_file(dir.join(&filename)).await?;
+        remove_if_exists(&dir.join(thumbnail_filename(&filename))).await?;
+        remove_if_exists(&dir.join(sidecar_filename(&filename))).await?;
+
+        if was_latest {
+            let next = match images.iter().find(|image| image.filename != filename) {
+                Some(next) => detect_file_format(&dir.join(&next.filename))
+                    .await?
+                    .map(|format| (next, format)),
+                None => None,
+            };
+            match next {
+                Some((next, format)) => {
+                    update_latest_symlink(&dir, latest_name, &next.filename, format).await?;
+                    remove_stale_latest(&dir, latest_name, Some(format)).await?;
+                }
+                None => remove_stale_latest(&dir, latest_name, None).await?,
+            }
+        }
+        state.storage.forget_usage(&serial_number).await;
+        tracing::debug!("image deleted from {}: {}", serial_number, filename);
+
+        Ok::<_, io::Error>(StatusCode::NO_CONTENT)
+    }
+    .await
+    .map_err(ApiError::from)
+}
+
+// Whether the latest copy in the serial directory `dir` is a plain copy of the stored image
+// `filename`, as made where symlinks are not available. The copy a latest-only upload leaves holds
+// an image that was never stored, and so is a copy of none of them.
+async fn latest_is_copy_of(
+    dir: &std::path::Path,
+    latest_name: &LatestName,
+    filename: &str,
+) -> io::Result<bool> {
+    for format in ImageFormat::ALL {
+        match tokio::fs::read(dir.join(latest_name.filename(format))).await {
+            Ok(copy) => return Ok(copy == tokio::fs::read(dir.join(filename)).await?),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(false)
+}
+
+// Handler for a `POST` to `<filename>/rotate`, that rotates a stored image clockwise by `?deg=` 90,
+// 180 or 270 degrees, in place. Its thumbnail is made again, and the latest copy is refreshed when
+// it is this image.
+async fn rotate_image(
+    _: RequireApiKey,
+    State(state): State<AppState>,
+    Path((serial_number, path)): Path<(String, String)>,
+    Query(query): Query<RotateQuery>,
+) -> Result<StatusCode, ApiError> {
+    let Some(filename) = path.strip_suffix("/rotate") else {
+        return Err(ApiError::MethodNotAllowed(
+            "images can only be rotated with a POST to `<filename>/rotate`".to_owned(),
+        ));
+    };
+    let filename = filename.to_owned();
+    if !serial_is_valid(&serial_number)
+        || !relative_path_is_valid(&filename)
+        || !is_stored_image(&filename, state.storage.latest_name())
+    {
+        return Err(ApiError::InvalidPath("Invalid path".to_owned()));
+    }
+    let Some(rotation) = query
+        .deg
+        .as_deref()
+        .and_then(|deg| deg.parse().ok())
+        .and_then(Rotation::from_degrees)
+    else {
+        return Err(ApiError::BadRequest(
+            "`deg` must be 90, 180 or 270".to_owned(),
+        ));
+    };
+
+    // an upload finishing meanwhile would otherwise race us for the latest copy
+    let _latest_guard = state.storage.lock_latest(&serial_number).await;
+
+    // a symlink already shows the rotated image; a copy of it goes stale
+    let dir = state.storage.serial_dir(&serial_number);
+    let latest_name = state.storage.latest_name();
+    let is_latest = match latest_target(&dir, latest_name).await? {
+        Some(target) => target == filename,
+        None => latest_is_copy_of(&dir, latest_name, &filename).await?,
+    };
+
+    let format = match state
+        .storage
+        .rotate_image(&serial_number, &filename, rotation)
+        .await
+    {
+        Ok(format) => format,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            return Err(ApiError::NotFound("Unknown image".to_owned()));
+        }
+        Err(err) if err.kind() == io::ErrorKind::Unsupported => {
+            return Err(ApiError::UnsupportedMedia(err.to_string()));
+        }
+        Err(err) => return Err(err.into()),
+    };
+
+    let path = dir.join(&filename);
+    let thumbnail_path = dir.join(thumbnail_filename(&filename));
+    let background = state.config.thumbnail_background;
+    let destination = thumbnail_path.clone();
+    match tokio::task::spawn_blocking(move || write_thumbnail(&path, &destination, background))
+        .await
+    {
+        Ok(Ok(())) => {
+            if let Err(err) = set_mode(&thumbnail_path, state.storage.file_mode()).await {
+                tracing::warn!(
+                    "could not set the mode of the thumbnail for {}: {}",
+                    filename,
+                    err
+                );
+            }
+        }
+        Ok(Err(err)) => tracing::warn!("could not create thumbnail for {}: {}", filename, err),
+        Err(err) => tracing::warn!("thumbnail task for {} failed: {}", filename, err),
+    }
+
+    if is_latest {
+        update_latest_symlink(&dir, latest_name, &filename, format).await?;
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Handler that deletes everything stored for a serial number: its images, thumbnails, sidecars
+// and latest copy, along with their directory. Answers how many files were removed.
+async fn delete_serial(
+    _: RequireApiKey,
+    State(state): State<AppState>,
+    Path(serial_number): Path<String>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    if !serial_is_valid(&serial_number) {
+        return Err(ApiError::InvalidPath("Invalid serial number".to_owned()));
+    }
+
+    // an upload finishing meanwhile would otherwise point the latest copy into a removed directory
+    let _latest_guard = state.storage.lock_latest(&serial_number).await;
+
+    let dir = state.storage.serial_dir(&serial_number);
+    let deleted_files = match count_files(&dir).await {
+        Ok(count) => count,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            return Err(ApiError::NotFound("Unknown serial number".to_owned()));
+        }
+        Err(err) => return Err(ApiError::Internal(err.to_string())),
+    };
+    tokio::fs::remove_dir_all(&dir)
+        .await
+        .map_err(ApiError::from)?;
+    state.storage.forget_usage(&serial_number).await;
+    tracing::info!(
+        "deleted {} files stored for {}",
+        deleted_files,
+        serial_number
+    );
+
+    Ok(Json(serde_json::json!({
+        "serial_number": serial_number,
+        "deleted_files": deleted_files,
+    })))
+}
+
+// Files in `dir` and all the directories below it, symlinks included
+async fn count_files(dir: &std::path::Path) -> io::Result<u64> {
+    let mut count = 0;
+    let mut dirs = vec![dir.to_owned()];
+    while let Some(dir) = dirs.pop() {
+        let mut entries = tokio::fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            match entry.file_type().await?.is_dir() {
+                true => dirs.push(entry.path()),
+                false => count += 1,
+            }
+        }
+    }
+    Ok(count)
+}
+
+// Middleware for the tus endpoints: requests for another protocol version are refused, and every
+// response tells the version spoken here
+async fn tus_protocol(request: Request, next: Next) -> Response {
+    let supported = request
+        .headers()
+        .get(TUS_RESUMABLE)
+        .is_none_or(|version| version == TUS_VERSION);
+    let mut response = match supported {
+        true => next.run(request).await,
+        false => {
+            ApiError::PreconditionFailed(format!("only tus version {} is supported", TUS_VERSION))
+                .into_response()
+        }
+    };
+    response
+        .headers_mut()
+        .insert(TUS_RESUMABLE, HeaderValue::from_static(TUS_VERSION));
+    response
+}
+
+// Handler that creates a resumable upload of `Upload-Length` bytes, following the tus protocol.
+// The bytes are then sent with `PATCH` requests to the returned `Location`, and the image is
+// stored once all of them have arrived.
+async fn create_tus_upload(
+    _: RequireApiKey,
+    State(state): State<AppState>,
+    Path(serial_number): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    if !serial_is_valid(&serial_number) {
+        return Err(ApiError::InvalidPath("Invalid serial number".to_owned()));
+    }
+    let Some(length) = header_u64(&headers, &UPLOAD_LENGTH).filter(|length| *length > 0) else {
+        return Err(ApiError::BadRequest(
+            "`Upload-Length` must be a positive number of bytes".to_owned(),
+        ));
+    };
+    let max_bytes = state.storage.max_bytes();
+    if length > max_bytes {
+        return Err(ApiError::PayloadTooLarge(format!(
+            "upload exceeds the limit of {} bytes",
+            max_bytes
+        )));
+    }
+
+    // the expired uploads no longer count against the limit
+    remove_expired_tus_uploads(&state.tus_uploads).await;
+    let id = format!("{:032x}", rand::random::<u128>());
+    let dir = state.storage.uploads_dir().join(TUS_DIRECTORY);
+    let path = dir.join(&id);
+    let requested_filename = headers
+        .get(X_FILENAME)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned);
+    let expires_at = tus_expires_at(&state.config);
+    let inserted = state.tus_uploads.try_insert(
+        id.clone(),
+        TusUpload {
+            serial_number: serial_number.clone(),
+            requested_filename,
+            received_at: Local::now(),
+            length,
+            path: path.clone(),
+            offset: tokio::sync::Mutex::new(0),
+            expires_at: Mutex::new(expires_at),
+        },
+        state.config.max_tus_uploads,
+    );
+    if !inserted {
+        return Err(ApiError::ServiceUnavailable(
+            "too many resumable uploads are open, try again later".to_owned(),
+        ));
+    }
+    if let Err(err) = async {
+        tokio::fs::create_dir_all(&dir).await?;
+        set_mode(&dir, state.storage.dir_mode()).await?;
+        File::create_new(&path).await?;
+        set_mode(&path, state.storage.file_mode()).await
+    }
+    .await
+    {
+        state.tus_uploads.remove(&id);
+        return Err(err.into());
+    }
+    tracing::debug!(
+        "resumable upload {} of {} bytes created for {}",
+        id,
+        length,
+        serial_number
+    );
+
+    Ok((
+        StatusCode::CREATED,
+        [
+            (header::LOCATION, format!("/files/{}/{}", serial_number, id)),
+            (UPLOAD_EXPIRES, http_date(expires_at)),
+        ],
+    )
+        .into_response())
+}
+
+// When a resumable upload that gets more of it now expires
+fn tus_expires_at(config: &Config) -> DateTime<Utc> {
+    Utc::now() + chrono::Duration::seconds(config.tus_expiry_secs.into())
+}
+
+// `time` in the date format of HTTP headers, such as `Upload-Expires`
+fn http_date(time: DateTime<Utc>) -> String {
+    time.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+// Remove the resumable uploads that expired, along with what was received of them
+async fn remove_expired_tus_uploads(uploads: &TusUploads) {
+    for (id, upload) in uploads.remove_expired(Utc::now()) {
+        match remove_if_exists(&upload.path).await {
+            Ok(()) => tracing::debug!(
+                "resumable upload {} for {} expired",
+                id,
+                upload.serial_number
+            ),
+            Err(err) => tracing::warn!("could not remove expired resumable upload {}: {}", id, err),
+        }
+    }
+}
+
+// Remove the resumable uploads that expired every `interval`, for as long as the server runs
+async fn expire_tus_uploads(uploads: Arc<TusUploads>, interval: Duration) {
+    let mut ticks = tokio::time::interval(interval);
+    loop {
+        ticks.tick().await;
+        remove_expired_tus_uploads(&uploads).await;
+    }
+}
+
+// Handler that tells a tus client how much of an upload has arrived, so it can resume after it
+async fn tus_upload_offset(
+    _: RequireApiKey,
+    State(state): State<AppState>,
+    Path((serial_number, id)): Path<(String, String)>,
+) -> Result<Response, ApiError> {
+    let Some(upload) = state.tus_uploads.get(&serial_number, &id) else {
+        return Err(ApiError::NotFound("Unknown upload".to_owned()));
+    };
+    // an append in progress would report a stale offset
+    let offset = *upload.offset.lock().await;
+
+    Ok(([
+        (UPLOAD_OFFSET, offset.to_string()),
+        (UPLOAD_LENGTH, upload.length.to_string()),
+        (UPLOAD_EXPIRES, http_date(upload.expires_at())),
+        (header::CACHE_CONTROL, "no-store".to_owned()),
+    ],)
+        .into_response())
+}
+
+// Handler that appends the request body to a resumable upload at `Upload-Offset`. Whatever arrives
+// before the connection drops is kept. Once the upload is complete the image is stored like any
+// other upload and `X-Filename` tells its name.
+async fn append_tus_upload(
+    _: RequireApiKey,
+    State(state): State<AppState>,
+    ClientIp(client_ip): ClientIp,
+    Path((serial_number, id)): Path<(String, String)>,
+    request: Request,
+) -> Result<Response, ApiError> {
+    let headers = request.headers();
+    if headers.get(header::CONTENT_TYPE).map(HeaderValue::as_bytes)
+        != Some(b"application/offset+octet-stream")
+    {
+        return Err(ApiError::UnsupportedMedia(
+            "content type must be `application/offset+octet-stream`".to_owned(),
+        ));
+    }
+    let Some(client_offset) = header_u64(headers, &UPLOAD_OFFSET) else {
+        return Err(ApiError::BadRequest(
+            "`Upload-Offset` must be a number of bytes".to_owned(),
+        ));
+    };
+    let Some(upload) = state.tus_uploads.get(&serial_number, &id) else {
+        return Err(ApiError::NotFound("Unknown upload".to_owned()));
+    };
+    let Ok(mut offset) = upload.offset.try_lock() else {
+        return Err(ApiError::Locked(
+            "upload is being appended to by another request".to_owned(),
+        ));
+    };
+    // completed by a request that held the lock before us
+    if *offset == upload.length {
+        return Err(ApiError::NotFound("Unknown upload".to_owned()));
+    }
+    if client_offset != *offset {
+        return Err(ApiError::Conflict(format!(
+            "upload is at offset {}",
+            *offset
+        )));
+    }
+
+    // the request that completes the upload is the one its sidecar records, apart from the time it
+    // was received, which is that of the request creating it
+    let source = UploadSource {
+        received_at: Some(upload.received_at),
+        ..upload_source(client_ip, headers)
+    };
+
+    // read at most one byte past the announced length to detect a body that is too long
+    let remaining = upload.length - *offset;
+    let body = StreamReader::new(
+        request
+            .into_body()
+            .into_data_stream()
+            .map_err(io::Error::other),
+    );
+    futures::pin_mut!(body);
+    let (copied, stored) = async {
+        let mut file = tokio::fs::OpenOptions::new()
+            .append(true)
+            .open(&upload.path)
+            .await?;
+        let copied = tokio::time::timeout(
+            Duration::from_secs(state.config.upload_timeout_secs),
+            tokio::io::copy(&mut body.take(remaining + 1), &mut file),
+        )
+        .await;
+        // the bytes written before an interrupted or late body are kept for the client to resume
+        // after
+        file.flush().await?;
+        file.sync_all().await?;
+        let mut stored = file.metadata().await?.len();
+        if stored > upload.length {
+            file.set_len(*offset).await?;
+            stored = *offset;
+        }
+        Ok::<_, io::Error>((copied, stored))
+    }
+    .await
+    .map_err(ApiError::from)?;
+    *offset = stored;
+    let expires_at = tus_expires_at(&state.config);
+    *upload.expires_at.lock().unwrap() = expires_at;
+    match copied {
+        Ok(Ok(copied)) if copied > remaining => {
+            return Err(ApiError::BadRequest(
+                "request body extends past `Upload-Length`".to_owned(),
+            ));
+        }
+        Ok(Ok(_)) => {}
+        Ok(Err(err)) => {
+            tracing::debug!("resumable upload {} interrupted at {}: {}", id, stored, err);
+            return Err(ApiError::BadRequest(err.to_string()));
+        }
+        Err(_) => {
+            tracing::debug!("resumable upload {} timed out at {}", id, stored);
+            return Err(ApiError::RequestTimeout(format!(
+                "body did not arrive within {} seconds, resume at offset {}",
+                state.config.upload_timeout_secs, stored
+            )));
+        }
+    }
+    if stored < upload.length {
+        return Ok((
+            StatusCode::NO_CONTENT,
+            [
+                (UPLOAD_OFFSET, stored.to_string()),
+                (UPLOAD_EXPIRES, http_date(expires_at)),
+            ],
+        )
+            .into_response());
+    }
+
+    // complete, store it the same way as the other uploads
+    state.tus_uploads.remove(&id);
+    let span = upload_span(&serial_number, &source);
+    let started = Instant::now();
+    let result = async {
+        let file = File::open(&upload.path).await.map_err(ApiError::from)?;
+        stream_to_file(
+            &state,
+            &serial_number,
+            StoreAs::Image(upload.requested_filename.as_deref()),
+            &source,
+            ReaderStream::new(file),
+        )
+        .await
+    }
+    .instrument(span.clone())
+    .await;
+    record_upload(&span, started, &result);
+    if let Err(err) = remove_if_exists(&upload.path).await {
+        tracing::warn!("could not remove resumable upload {}: {}", id, err);
+    }
+    let saved = result?;
+
+    Ok((
+        StatusCode::NO_CONTENT,
+        [
+            (UPLOAD_OFFSET, stored.to_string()),
+            (X_FILENAME, saved.filename),
+        ],
+    )
+        .into_response())
+}
+
+// Client of an upload, for its sidecar
+fn upload_source(client_ip: IpAddr, headers: &HeaderMap) -> UploadSource {
+    UploadSource {
+        client_ip: Some(client_ip),
+        user_agent: headers
+            .get(header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_owned),
+        // handlers make the source before reading any of the body
+        received_at: Some(Local::now()),
+    }
+}
+
+// The bytes of an upload body as they were before the client compressed them with the
+// `Content-Encoding` it announced, so that the original image is stored
+fn decoded_body(
+    headers: &HeaderMap,
+    body: Body,
+) -> Result<BoxStream<'static, io::Result<Bytes>>, ApiError> {
+    let encoding = match headers.get(header::CONTENT_ENCODING) {
+        Some(value) => value
+            .to_str()
+            .map(|encoding| encoding.trim().to_ascii_lowercase())
+            .unwrap_or_default(),
+        None => "identity".to_owned(),
+    };
+    let body = StreamReader::new(body.into_data_stream().map_err(io::Error::other));
+    // a body that does not decode is the client's fault, see `save_error_response`
+    let undecodable = |err: io::Error| match err.kind() {
+        io::ErrorKind::InvalidData => io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("body does not decompress: {}", err),
+        ),
+        _ => err,
+    };
+    match encoding.as_str() {
+        "identity" => Ok(body.into_inner().boxed()),
+        "gzip" | "x-gzip" => Ok(ReaderStream::new(GzipDecoder::new(body))
+            .map_err(undecodable)
+            .boxed()),
+        // HTTP's `deflate` is the zlib format, not a raw deflate stream
+        "deflate" => Ok(ReaderStream::new(ZlibDecoder::new(body))
+            .map_err(undecodable)
+            .boxed()),
+        _ => Err(ApiError::UnsupportedMedia(format!(
+            "unsupported Content-Encoding `{}`, expected gzip, deflate or identity",
+            encoding
+        ))),
+    }
+}
+
+// Value of a header holding a number
+fn header_u64(headers: &HeaderMap, name: &HeaderName) -> Option<u64> {
+    headers.get(name)?.to_str().ok()?.parse().ok()
+}
+
+// Save a `Stream` to a file, along with a thumbnail. `StoreAs::LatestOnly` only replaces the latest
+// copy, which needs no thumbnail as nothing lists it.
+async fn stream_to_file<S, E>(
+    state: &AppState,
+    serial_number: &str,
+    store_as: StoreAs<'_>,
+    source: &UploadSource,
+    stream: S,
+) -> Result<UploadResponse, ApiError>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+    E: Into<BoxError>,
+{
+    let result = async {
+        // Convert the stream into an `AsyncRead`, keeping the kind of errors that already are I/O ones.
+        let body_with_io_error = stream.map_err(|err| match err.into().downcast::<io::Error>() {
+            Ok(err) => *err,
+            Err(err) => io::Error::other(err),
+        });
+        let body_reader = StreamReader::new(body_with_io_error);
+        futures::pin_mut!(body_reader);
+
+        let saved = match store_as {
+            StoreAs::Image(requested_filename) => {
+                state
+                    .storage
+                    .save_image(serial_number, requested_filename, source, body_reader)
+                    .await
+            }
+            StoreAs::LatestOnly => {
+                state
+                    .storage
+                    .save_latest_only(serial_number, source, body_reader)
+                    .await
+            }
+            StoreAs::Upload(upload_id) => {
+                state
+                    .storage
+                    .put_image(serial_number, upload_id, source, body_reader)
+                    .await
+            }
+        }
+        .map_err(ApiError::from)?;
+        let dimensions = read_dimensions(saved.path.clone()).await;
+
+        // Decoding is CPU-bound, so keep it off the async worker threads. A thumbnail failure
+        // (e.g. a corrupt image) is not worth failing the upload over. A duplicate already has one.
+        if !saved.duplicate && !matches!(store_as, StoreAs::LatestOnly) {
+            let path_buf = saved.path.clone();
+            let thumbnail_path = state
+                .storage
+                .serial_dir(serial_number)
+                .join(thumbnail_filename(&saved.filename));
+            let background = state.config.thumbnail_background;
+            let destination = thumbnail_path.clone();
+            match tokio::task::spawn_blocking(move || {
+                write_thumbnail(&path_buf, &destination, background)
+            })
+            .await
+            {
+                Ok(Ok(())) => {
+                    if let Err(err) = set_mode(&thumbnail_path, state.storage.file_mode()).await {
+                        tracing::warn!(
+                            "could not set the mode of the thumbnail for {}: {}",
+                            saved.filename,
+                            err
+                        );
+                    }
+                }
+                Ok(Err(err)) => {
+                    tracing::warn!("could not create thumbnail for {}: {}", saved.filename, err)
+                }
+                Err(err) => tracing::warn!("thumbnail task for {} failed: {}", saved.filename, err),
+            }
+        }
+
+        // a duplicate adds no image to show
+        if !saved.duplicate {
+            publish_upload(state, serial_number, &saved);
+        }
+
+        Ok(UploadResponse {
+            serial_number: serial_number.to_owned(),
+            filename: saved.filename,
+            bytes: saved.bytes,
+            format: saved.format,
+            duplicate: saved.duplicate,
+            width: dimensions.map(|(width, _)| width),
+            height: dimensions.map(|(_, height)| height),
+            replaced: saved.replaced,
+        })
+    }
+    .await;
+    state.metrics.record_upload(
+        serial_number,
+        result.as_ref().ok().map(|response| response.bytes),
+    );
+    result
+}
+
+// Tell the event streams and the `UPLOAD_WEBHOOK` about the newly stored `saved`. Nobody
+// listening is not an error.
+fn publish_upload(state: &AppState, serial_number: &str, saved: &SavedImage) {
+    let event = UploadEvent {
+        serial_number: serial_number.to_owned(),
+        filename: saved.filename.clone(),
+        bytes: saved.bytes,
+        format: saved.format,
+        received_at: saved.received_at.to_rfc3339(),
+    };
+    if state.config.upload_webhook.is_some() {
+        tokio::spawn(notify_webhook(state.config.clone(), event.clone()));
+    }
+    let _ = state.upload_events.send(event);
+}
+
+// A failed request, answered as `{"error": "<message>", "code": "<code>"}` whatever went wrong, so
+// that clients can tell failures apart by `code` rather than by parsing messages
+#[derive(Debug)]
+enum ApiError {
+    BadRequest(String),
+    // a serial number, upload ID or path that could reach outside of where the images are, answered
+    // as a bad request
+    InvalidPath(String),
+    // an upload without an image in it, answered as a bad request
+    EmptyUpload(String),
+    Unauthorized(String),
+    NotFound(String),
+    MethodNotAllowed(String),
+    Conflict(String),
+    Locked(String),
+    PreconditionFailed(String),
+    RequestTimeout(String),
+    PayloadTooLarge(String),
+    UnsupportedMedia(String),
+    TooManyRequests(String),
+    ServiceUnavailable(String),
+    RangeNotSatisfiable(String),
+    InsufficientStorage(String),
+    Internal(String),
+    NotImplemented(String),
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    error: &'a str,
+    code: &'static str,
+}
+
+impl ApiError {
+    fn status(&self) -> StatusCode {
+        match self {
+            ApiError::BadRequest(_) | ApiError::InvalidPath(_) | ApiError::EmptyUpload(_) => {
+                StatusCode::BAD_REQUEST
+            }
+            ApiError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::MethodNotAllowed(_) => StatusCode::METHOD_NOT_ALLOWED,
+            ApiError::Conflict(_) => StatusCode::CONFLICT,
+            ApiError::Locked(_) => StatusCode::LOCKED,
+            ApiError::PreconditionFailed(_) => StatusCode::PRECONDITION_FAILED,
+            ApiError::RequestTimeout(_) => StatusCode::REQUEST_TIMEOUT,
+            ApiError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::UnsupportedMedia(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ApiError::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::ServiceUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::RangeNotSatisfiable(_) => StatusCode::RANGE_NOT_SATISFIABLE,
+            ApiError::InsufficientStorage(_) => StatusCode::INSUFFICIENT_STORAGE,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            ApiError::NotImplemented(_) => StatusCode::NOT_IMPLEMENTED,
+        }
+    }
+
+    // machine-readable kind of the failure
+    fn code(&self) -> &'static str {
+        match self {
+            ApiError::BadRequest(_) | ApiError::InvalidPath(_) | ApiError::EmptyUpload(_) => {
+                "bad_request"
+            }
+            ApiError::Unauthorized(_) => "unauthorized",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::MethodNotAllowed(_) => "method_not_allowed",
+            ApiError::Conflict(_) => "conflict",
+            ApiError::Locked(_) => "locked",
+            ApiError::PreconditionFailed(_) => "precondition_failed",
+            ApiError::RequestTimeout(_) => "request_timeout",
+            ApiError::PayloadTooLarge(_) => "payload_too_large",
+            ApiError::UnsupportedMedia(_) => "unsupported_media_type",
+            ApiError::TooManyRequests(_) => "too_many_requests",
+            ApiError::ServiceUnavailable(_) => "service_unavailable",
+            ApiError::RangeNotSatisfiable(_) => "range_not_satisfiable",
+            ApiError::InsufficientStorage(_) => "insufficient_storage",
+            ApiError::Internal(_) => "internal",
+            ApiError::NotImplemented(_) => "not_implemented",
+        }
+    }
+
+    fn message(&self) -> &str {
+        match self {
+            ApiError::BadRequest(message)
+            | ApiError::InvalidPath(message)
+            | ApiError::EmptyUpload(message)
+            | ApiError::Unauthorized(message)
+            | ApiError::NotFound(message)
+            | ApiError::MethodNotAllowed(message)
+            | ApiError::Conflict(message)
+            | ApiError::Locked(message)
+            | ApiError::PreconditionFailed(message)
+            | ApiError::RequestTimeout(message)
+            | ApiError::PayloadTooLarge(message)
+            | ApiError::UnsupportedMedia(message)
+            | ApiError::TooManyRequests(message)
+            | ApiError::ServiceUnavailable(message)
+            | ApiError::RangeNotSatisfiable(message)
+            | ApiError::InsufficientStorage(message)
+            | ApiError::Internal(message)
+            | ApiError::NotImplemented(message) => message,
+        }
+    }
+
+    // why an upload was turned away, for `uploads_rejected_total`, when it was the client's doing
+    fn rejection_reason(&self) -> Option<&'static str> {
+        match self {
+            ApiError::PayloadTooLarge(_) => Some("oversize"),
+            ApiError::UnsupportedMedia(_) => Some("bad_format"),
+            ApiError::Unauthorized(_) => Some("unauthorized"),
+            ApiError::InvalidPath(_) => Some("traversal"),
+            ApiError::EmptyUpload(_) => Some("empty"),
+            _ => None,
+        }
+    }
+}
+
+// Why a response turned an upload away, left on it for `count_rejections`
+#[derive(Clone, Copy)]
+struct Rejection(&'static str);
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = ErrorBody {
+            error: self.message(),
+            code: self.code(),
+        };
+        let mut response = (self.status(), Json(body)).into_response();
+        if let Some(reason) = self.rejection_reason() {
+            response.extensions_mut().insert(Rejection(reason));
+        }
+        response
+    }
+}
+
+impl From<io::Error> for ApiError {
+    fn from(err: io::Error) -> Self {
+        ApiError::Internal(err.to_string())
+    }
+}
+
+impl From<SaveError> for ApiError {
+    fn from(err: SaveError) -> Self {
+        let message = err.to_string();
+        match err {
+            SaveError::InvalidSerial | SaveError::InvalidUploadId => ApiError::InvalidPath(message),
+            SaveError::Empty => ApiError::EmptyUpload(message),
+            SaveError::UnsupportedFormat | SaveError::FormatNotAllowed(_) => {
+                ApiError::UnsupportedMedia(message)
+            }
+            SaveError::TooLarge(_) | SaveError::TooManyPixels { .. } => {
+                ApiError::PayloadTooLarge(message)
+            }
+            SaveError::Busy => ApiError::ServiceUnavailable(message),
+            SaveError::QuotaExceeded(_) => ApiError::InsufficientStorage(message),
+            SaveError::TimedOut(_) => ApiError::RequestTimeout(message),
+            // only a compressed body that fails to decompress reads as invalid data
+            SaveError::Io(ref err) if err.kind() == io::ErrorKind::InvalidData => {
+                ApiError::BadRequest(message)
+            }
+            SaveError::Io(_) => ApiError::Internal(message),
+        }
+    }
+}
+
+// Write a JPEG copy of `source` scaled down to at most `THUMBNAIL_MAX_SIZE` on its longest side
+fn write_thumbnail(
+    source: &std::path::Path,
+    destination: &std::path::Path,
+    background: image::Rgb<u8>,
+) -> image::ImageResult<()> {
+    let mut image = image::ImageReader::open(source)?
+        .with_guessed_format()?
+        .decode()?;
+    if image.width() > THUMBNAIL_MAX_SIZE || image.height() > THUMBNAIL_MAX_SIZE {
+        image = image.thumbnail(THUMBNAIL_MAX_SIZE, THUMBNAIL_MAX_SIZE);
+    }
+    image::DynamicImage::ImageRgb8(onto_background(&image, background))
+        .save_with_format(destination, image::ImageFormat::Jpeg)
+}
+
+// `image` without its alpha channel, which JPEG does not have. Transparent pixels are blended onto
+// `background` rather than losing their alpha and turning black.
+fn onto_background(image: &image::DynamicImage, background: image::Rgb<u8>) -> image::RgbImage {
+    let rgba = image.to_rgba8();
+    image::RgbImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let image::Rgba([r, g, b, a]) = *rgba.get_pixel(x, y);
+        let blend = |channel: u8, background: u8| {
+            ((channel as u32 * a as u32 + background as u32 * (255 - a as u32) + 127) / 255) as u8
+        };
+        image::Rgb([
+            blend(r, background[0]),
+            blend(g, background[1]),
+            blend(b, background[2]),
+        ])
+    })
+}
+
+// JPEG grid of the images at `sources`, `cols` to a row, each scaled down to fit and centered in a
+// `CONTACT_SHEET_TILE_SIZE` square on `background`. An image that fails to decode leaves its tile
+// empty rather than failing the whole sheet.
+fn compose_contact_sheet(
+    sources: &[PathBuf],
+    cols: u32,
+    background: image::Rgb<u8>,
+) -> image::ImageResult<Vec<u8>> {
+    let tile = CONTACT_SHEET_TILE_SIZE;
+    let cols = cols.min(sources.len() as u32);
+    let rows = (sources.len() as u32).div_ceil(cols);
+    let mut sheet = image::RgbImage::from_pixel(cols * tile, rows * tile, background);
+    for (index, source) in (0..).zip(sources) {
+        let decoded = image::ImageReader::open(source)
+            .map_err(image::ImageError::from)
+            .and_then(|reader| {
+                reader
+                    .with_guessed_format()
+                    .map_err(image::ImageError::from)
+            })
+            .and_then(|reader| reader.decode());
+        let image = match decoded {
+            Ok(image) => image.thumbnail(tile, tile),
+            Err(err) => {
+                tracing::warn!(
+                    "could not add {} to a contact sheet: {}",
+                    source.display(),
+                    err
+                );
+                continue;
+            }
+        };
+        let x = index % cols * tile + (tile - image.width()) / 2;
+        let y = index / cols * tile + (tile - image.height()) / 2;
+        image::imageops::overlay(
+            &mut sheet,
+            &onto_background(&image, background),
+            x.into(),
+            y.into(),
+        );
+    }
+
+    let mut jpeg = Vec::new();
+    image::DynamicImage::ImageRgb8(sheet)
+        .write_to(&mut io::Cursor::new(&mut jpeg), image::ImageFormat::Jpeg)?;
+    Ok(jpeg)
+}
+
+// Width and height of the image at `path`, read from its header without decoding the pixels
+async fn read_dimensions(path: PathBuf) -> Option<(u32, u32)> {
+    tokio::task::spawn_blocking(move || {
+        image::ImageReader::open(path)
+            .ok()?
+            .with_guessed_format()
+            .ok()?
+            .into_dimensions()
+            .ok()
+    })
+    .await
+    .ok()
+    .flatten()
+}
+
+// Images stored in the serial directory `dir`, newest first as `sort` says
+async fn read_images(
+    dir: &std::path::Path,
+    storage: &Storage,
+    sort: ListSort,
+) -> io::Result<Vec<ImageEntry>> {
+    let mut entries = Vec::new();
+    for (filename, metadata) in stored_images(dir, storage.latest_name()).await? {
+        let modified = DateTime::<Utc>::from(metadata.modified()?);
+        entries.push((
+            modified,
+            ImageEntry {
+                filename,
+                size: metadata.len(),
+                modified: modified.to_rfc3339(),
+                sidecar: None,
+            },
+        ));
+    }
+
+    // names without a timestamp sort last
+    entries.sort_by(|(a_modified, a), (b_modified, b)| {
+        let filename_time = storage.filename_time();
+        match sort {
+            ListSort::Name => filename_time
+                .of_filename(&b.filename)
+                .cmp(&filename_time.of_filename(&a.filename)),
+            ListSort::Mtime => b_modified.cmp(a_modified),
+        }
+        .then_with(|| b.filename.cmp(&a.filename))
+    });
+    Ok(entries.into_iter().map(|(_, entry)| entry).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::extract::connect_info::MockConnectInfo;
+    use std::{ffi::OsStr, io::Cursor};
+    use tower::ServiceExt;
+
+    fn jpeg() -> Vec<u8> {
+        let mut bytes = Vec::new();
+        image::RgbImage::from_pixel(4, 4, image::Rgb([128; 3]))
+            .write_to(&mut Cursor::new(&mut bytes), image::ImageFormat::Jpeg)
+            .unwrap();
+        bytes
+    }
+
+    // The server with its defaults, storing uploads in `uploads_dir`
+    fn test_app(uploads_dir: &std::path::Path) -> Router {
+        test_app_with(uploads_dir, &[])
+    }
+
+    // The server storing uploads in `uploads_dir`, with the options `args` on top of the defaults
+    fn test_app_with(uploads_dir: &std::path::Path, args: &[&str]) -> Router {
+        let mut argv = vec![
+            OsStr::new("http-server"),
+            OsStr::new("--uploads-dir"),
+            uploads_dir.as_os_str(),
+        ];
+        argv.extend(args.iter().map(OsStr::new));
+        let config = Config::parse_from(argv);
+        app(Arc::new(config)).layer(MockConnectInfo(SocketAddr::from(([127, 0, 0, 1], 0))))
+    }
+
+    // The response to `request`, with its body read
+    async fn send(app: Router, request: Request) -> (StatusCode, HeaderMap, Vec<u8>) {
+        let response = app.oneshot(request).await.unwrap();
+        let (status, headers) = (response.status(), response.headers().clone());
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, headers, body.to_vec())
+    }
+
+    async fn get(app: Router, uri: &str) -> (StatusCode, HeaderMap, Vec<u8>) {
+        send(app, Request::get(uri).body(Body::empty()).unwrap()).await
+    }
+
+    async fn post(app: Router, uri: &str, body: Vec<u8>) -> (StatusCode, Vec<u8>) {
+        let request = Request::post(uri).body(Body::from(body)).unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, body.to_vec())
+    }
+
+    #[tokio::test]
+    async fn images_pushed_over_the_websocket_are_stored() {
+        use futures::SinkExt;
+        use tokio_tungstenite::tungstenite::Message;
+
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let config = Config::parse_from([
+            OsStr::new("http-server"),
+            OsStr::new("--uploads-dir"),
+            uploads_dir.path().as_os_str(),
+        ]);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = app(Arc::new(config)).into_make_service_with_connect_info::<SocketAddr>();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let image = jpeg();
+        let (mut socket, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws", addr))
+            .await
+            .unwrap();
+        socket.send(Message::Text("cam".to_owned())).await.unwrap();
+        socket.send(Message::Binary(image.clone())).await.unwrap();
+        socket.send(Message::Text("END".to_owned())).await.unwrap();
+        let ack = loop {
+            match socket.next().await.unwrap().unwrap() {
+                Message::Text(text) => break text,
+                _ => continue,
+            }
+        };
+
+        let ack: serde_json::Value = serde_json::from_str(&ack).unwrap();
+        assert_eq!(ack["saved"], true);
+        let filename = ack["filename"].as_str().unwrap();
+        assert_eq!(
+            std::fs::read(uploads_dir.path().join("cam").join(filename)).unwrap(),
+            image
+        );
+    }
+
+    #[tokio::test]
+    async fn repeated_puts_overwrite_the_first() {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let put = |body: Vec<u8>| async {
+            let request = Request::put("/upload/cam/retry-1")
+                .body(Body::from(body))
+                .unwrap();
+            let response = test_app(uploads_dir.path()).oneshot(request).await.unwrap();
+            let status = response.status();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            (status, body["filename"].as_str().unwrap().to_owned())
+        };
+
+        let (status, filename) = put(jpeg()).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(filename, "upload-retry-1.jpg");
+        let (status, again) = put(jpeg()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(again, filename);
+        let images = std::fs::read_dir(uploads_dir.path().join("cam"))
+            .unwrap()
+            .filter_map(|entry| entry.unwrap().file_name().into_string().ok())
+            .filter(|filename| filename.starts_with("upload-") && filename.ends_with(".jpg"))
+            .count();
+        assert_eq!(images, 1);
+    }
+
+    #[tokio::test]
+    async fn uploads_are_stored_and_become_the_latest() {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let image = jpeg();
+
+        let (status, body) = post(test_app(uploads_dir.path()), "/upload/cam", image.clone()).await;
+        assert_eq!(status, StatusCode::OK);
+        let response: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let filename = response["filename"].as_str().unwrap();
+
+        let serial_dir = uploads_dir.path().join("cam");
+        assert_eq!(std::fs::read(serial_dir.join(filename)).unwrap(), image);
+        assert_eq!(
+            std::fs::read(serial_dir.join("aaa-latest.jpg")).unwrap(),
+            image
+        );
+
+        let request = Request::get("/latest/cam").body(Body::empty()).unwrap();
+        let response = test_app(uploads_dir.path()).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body, image);
+    }
+
+    #[tokio::test]
+    async fn form_uploads_are_not_held_to_the_default_body_limit() {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        // past axum's 2 MiB default, still within `MAX_UPLOAD_BYTES`
+        let mut image = jpeg();
+        image.resize(3 * 1024 * 1024, 0);
+        let mut body = b"--boundary\r\n\
+            Content-Disposition: form-data; name=\"file\"; filename=\"large.jpg\"\r\n\
+            Content-Type: image/jpeg\r\n\r\n"
+            .to_vec();
+        body.extend_from_slice(&image);
+        body.extend_from_slice(b"\r\n--boundary--\r\n");
+
+        let request = Request::post("/upload-form/cam")
+            .header(
+                header::CONTENT_TYPE,
+                "multipart/form-data; boundary=boundary",
+            )
+            .body(Body::from(body))
+            .unwrap();
+        let response = test_app(uploads_dir.path()).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let stored = uploads_dir.path().join("cam").join("large.jpg");
+        assert_eq!(std::fs::read(stored).unwrap(), image);
+    }
+
+    #[tokio::test]
+    async fn images_in_date_buckets_can_be_rotated_and_deleted() {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let app = test_app_with(uploads_dir.path(), &["--layout", "daily"]);
+        let mut wide = Vec::new();
+        image::RgbImage::from_pixel(8, 4, image::Rgb([128; 3]))
+            .write_to(&mut Cursor::new(&mut wide), image::ImageFormat::Jpeg)
+            .unwrap();
+        let (status, body) = post(app.clone(), "/upload/cam", wide).await;
+        assert_eq!(status, StatusCode::OK);
+        let response: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let filename = response["filename"].as_str().unwrap().to_owned();
+        assert_eq!(filename.matches('/').count(), 3, "{}", filename);
+        let path = uploads_dir.path().join("cam").join(&filename);
+
+        let uri = format!("/images/cam/{}/rotate?deg=90", filename);
+        let request = Request::post(uri).body(Body::empty()).unwrap();
+        let (status, _, _) = send(app.clone(), request).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert_eq!(image::image_dimensions(&path).unwrap(), (4, 8));
+
+        let request = Request::post(format!("/images/cam/{}", filename))
+            .body(Body::empty())
+            .unwrap();
+        let (status, _, _) = send(app.clone(), request).await;
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+
+        let request = Request::delete(format!("/images/cam/{}", filename))
+            .body(Body::empty())
+            .unwrap();
+        let (status, _, _) = send(app.clone(), request).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert!(!path.exists());
+        assert!(!uploads_dir.path().join("cam/aaa-latest.jpg").exists());
+
+        let request = Request::delete("/images/cam/2024/..%2F..%2F..%2Fcam")
+            .body(Body::empty())
+            .unwrap();
+        let (status, _, _) = send(app, request).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn latest_only_copies_survive_rotating_and_deleting_the_only_image() {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let app = test_app(uploads_dir.path());
+        let mut wide = Vec::new();
+        image::RgbImage::from_pixel(8, 4, image::Rgb([128; 3]))
+            .write_to(&mut Cursor::new(&mut wide), image::ImageFormat::Jpeg)
+            .unwrap();
+        let (status, body) = post(app.clone(), "/upload/cam", wide).await;
+        assert_eq!(status, StatusCode::OK);
+        let response: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let newest = response["filename"].as_str().unwrap().to_owned();
+
+        let mut latest_only = Vec::new();
+        image::RgbImage::from_pixel(2, 2, image::Rgb([7; 3]))
+            .write_to(&mut Cursor::new(&mut latest_only), image::ImageFormat::Jpeg)
+            .unwrap();
+        let uri = "/upload/cam?keep_history=false";
+        let (status, _) = post(app.clone(), uri, latest_only.clone()).await;
+        assert_eq!(status, StatusCode::OK);
+        let latest = uploads_dir.path().join("cam/aaa-latest.jpg");
+        assert!(!latest.is_symlink());
+
+        let uri = format!("/images/cam/{}/rotate?deg=90", newest);
+        let request = Request::post(uri).body(Body::empty()).unwrap();
+        let (status, _, _) = send(app.clone(), request).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert!(!latest.is_symlink());
+        assert_eq!(std::fs::read(&latest).unwrap(), latest_only);
+
+        let request = Request::delete(format!("/images/cam/{}", newest))
+            .body(Body::empty())
+            .unwrap();
+        let (status, _, _) = send(app, request).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert!(!latest.is_symlink());
+        assert_eq!(std::fs::read(&latest).unwrap(), latest_only);
+    }
+
+    // A `PATCH` of `body` to the resumable upload at `location`, sent as starting at `offset`
+    fn tus_patch(location: &str, offset: usize, body: Body) -> Request {
+        Request::patch(location)
+            .header(TUS_RESUMABLE, TUS_VERSION)
+            .header(header::CONTENT_TYPE, "application/offset+octet-stream")
+            .header(UPLOAD_OFFSET, offset)
+            .body(body)
+            .unwrap()
+    }
+
+    // Create a resumable upload of `length` bytes for `cam`, answering its status and `Location`
+    async fn create_tus(app: Router, length: usize) -> (StatusCode, HeaderMap) {
+        let request = Request::post("/files/cam")
+            .header(TUS_RESUMABLE, TUS_VERSION)
+            .header(UPLOAD_LENGTH, length)
+            .body(Body::empty())
+            .unwrap();
+        let (status, headers, _) = send(app, request).await;
+        (status, headers)
+    }
+
+    #[tokio::test]
+    async fn resumable_uploads_resume_at_their_offset() {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let app = test_app(uploads_dir.path());
+        let image = jpeg();
+        let half = image.len() / 2;
+
+        let (status, headers) = create_tus(app.clone(), image.len()).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert!(headers.contains_key(UPLOAD_EXPIRES));
+        let location = headers[header::LOCATION].to_str().unwrap().to_owned();
+        let offset = |headers: &HeaderMap| headers[UPLOAD_OFFSET].to_str().unwrap().to_owned();
+
+        let head = || Request::head(&location).body(Body::empty()).unwrap();
+        let (status, headers, _) = send(app.clone(), head()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(offset(&headers), "0");
+        assert_eq!(headers[UPLOAD_LENGTH], image.len().to_string());
+
+        let first = Body::from(image[..half].to_vec());
+        let (status, headers, _) = send(app.clone(), tus_patch(&location, 0, first)).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert_eq!(offset(&headers), half.to_string());
+
+        // a client that lost track of the offset is told where to resume
+        let again = Body::from(image[..half].to_vec());
+        let (status, _, _) = send(app.clone(), tus_patch(&location, 0, again)).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        let (_, headers, _) = send(app.clone(), head()).await;
+        assert_eq!(offset(&headers), half.to_string());
+
+        let rest = Body::from(image[half..].to_vec());
+        let (status, headers, _) = send(app.clone(), tus_patch(&location, half, rest)).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let filename = headers[X_FILENAME].to_str().unwrap();
+        assert_eq!(
+            std::fs::read(uploads_dir.path().join("cam").join(filename)).unwrap(),
+            image
+        );
+        let (status, _, _) = send(app, head()).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn resumable_uploads_expire_and_are_capped() {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let args = ["--tus-expiry-secs", "1", "--max-tus-uploads", "1"];
+        let app = test_app_with(uploads_dir.path(), &args);
+
+        let (status, headers) = create_tus(app.clone(), 100).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let location = headers[header::LOCATION].to_str().unwrap().to_owned();
+        let id = location.rsplit('/').next().unwrap();
+        let path = uploads_dir.path().join(TUS_DIRECTORY).join(id);
+        assert!(path.exists());
+        let (status, _) = create_tus(app.clone(), 100).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        let request = Request::head(&location).body(Body::empty()).unwrap();
+        let (status, _, _) = send(app.clone(), request).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        // the expired upload makes room for the next one, and goes from the disk
+        let (status, _) = create_tus(app, 100).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn stalled_patches_time_out_keeping_what_arrived() {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let app = test_app_with(uploads_dir.path(), &["--upload-timeout-secs", "1"]);
+        let (_, headers) = create_tus(app.clone(), 100).await;
+        let location = headers[header::LOCATION].to_str().unwrap().to_owned();
+
+        let stalled = futures::stream::iter([Ok::<_, io::Error>(vec![0; 10])])
+            .chain(futures::stream::pending());
+        let request = tus_patch(&location, 0, Body::from_stream(stalled));
+        let (status, _, _) = send(app.clone(), request).await;
+        assert_eq!(status, StatusCode::REQUEST_TIMEOUT);
+
+        let request = Request::head(&location).body(Body::empty()).unwrap();
+        let (status, headers, _) = send(app, request).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[UPLOAD_OFFSET], "10");
+    }
+
+    #[tokio::test]
+    async fn traversal_attempts_are_rejected() {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let root = uploads_dir.path().join("root");
+        std::fs::create_dir(&root).unwrap();
+
+        let (status, _) = post(test_app(&root), "/upload/..", jpeg()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = post(test_app(&root), "/upload/..%2Fescaped", jpeg()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(std::fs::read_dir(uploads_dir.path()).unwrap().count(), 1);
+
+        // an unsafe requested name falls back to the timestamp one, inside the serial directory
+        let request = Request::post("/upload/cam")
+            .header("x-filename", "../../escaped.jpg")
+            .body(Body::from(jpeg()))
+            .unwrap();
+        let response = test_app(&root).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(!uploads_dir.path().join("escaped.jpg").exists());
+        assert!(!root.join("escaped.jpg").exists());
+
+        let request = Request::delete("/images/cam/..%2Fcam")
+            .body(Body::empty())
+            .unwrap();
+        let response = test_app(&root).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(root.join("cam").is_dir());
+    }
+
+    #[tokio::test]
+    async fn oversized_content_lengths_are_rejected_before_the_body_is_read() {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        // a body that never arrives, which would stall the upload if it were read
+        let request = Request::post("/upload/cam")
+            .header(header::CONTENT_LENGTH, DEFAULT_MAX_UPLOAD_BYTES + 1)
+            .body(Body::from_stream(futures::stream::pending::<
+                Result<Bytes, io::Error>,
+            >()))
+            .unwrap();
+        let response = test_app(uploads_dir.path()).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(!uploads_dir.path().join("cam").exists());
+    }
+
+    #[tokio::test]
+    async fn object_store_backends_answer_what_they_do_not_serve() {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let app = test_app_with(
+            uploads_dir.path(),
+            &["--storage-backend", "s3", "--s3-bucket", "images"],
+        );
+
+        let (status, _, body) = send(
+            app.clone(),
+            Request::get("/gallery").body(Body::empty()).unwrap(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_IMPLEMENTED);
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["code"], "not_implemented");
+        let (status, _, _) = send(
+            app.clone(),
+            Request::get("/health").body(Body::empty()).unwrap(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        // turned away before the bucket is reached
+        let (status, _) = post(app, "/upload/bad.serial", jpeg()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(std::fs::read_dir(uploads_dir.path()).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn the_memory_backend_serves_back_its_uploads_without_touching_the_disk() {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let app = test_app_with(uploads_dir.path(), &["--storage-backend", "memory"]);
+        let jpeg = jpeg();
+
+        let request = Request::post("/upload/cam")
+            .header(X_FILENAME, "front.jpg")
+            .body(Body::from(jpeg.clone()))
+            .unwrap();
+        let (status, _, body) = send(app.clone(), request).await;
+        assert_eq!(status, StatusCode::OK);
+        let response: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(response["filename"], "front.jpg");
+
+        for uri in ["/images/cam/front.jpg", "/latest/cam"] {
+            let (status, headers, body) =
+                send(app.clone(), Request::get(uri).body(Body::empty()).unwrap()).await;
+            assert_eq!(status, StatusCode::OK, "{}", uri);
+            assert_eq!(headers[header::CONTENT_TYPE], "image/jpeg");
+            assert_eq!(body, jpeg);
+        }
+        let (status, _, body) = send(
+            app.clone(),
+            Request::get("/images/cam/list")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let listed: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(listed[0]["filename"], "front.jpg");
+        assert_eq!(listed[0]["size"], jpeg.len());
+        let (status, _, _) = send(
+            app,
+            Request::get("/images/cam/missing.jpg")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        assert_eq!(std::fs::read_dir(uploads_dir.path()).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn listings_are_compressed_and_images_are_not() {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let (status, body) = post(test_app(uploads_dir.path()), "/upload/cam", jpeg()).await;
+        assert_eq!(status, StatusCode::OK);
+        let response: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let filename = response["filename"].as_str().unwrap();
+
+        for (uri, encoding) in [
+            ("/images/cam/list".to_owned(), Some("gzip")),
+            (format!("/images/cam/{}", filename), None),
+        ] {
+            let request = Request::get(&uri)
+                .header(header::ACCEPT_ENCODING, "gzip")
+                .body(Body::empty())
+                .unwrap();
+            let response = test_app(uploads_dir.path()).oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let content_encoding = response
+                .headers()
+                .get(header::CONTENT_ENCODING)
+                .map(|value| value.to_str().unwrap());
+            assert_eq!(content_encoding, encoding, "{}", uri);
+        }
+    }
+
+    #[tokio::test]
+    async fn the_images_index_counts_each_serial_number() {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        for serial_number in ["door", "cam", "cam"] {
+            let uri = format!("/upload/{}", serial_number);
+            let (status, _) = post(test_app(uploads_dir.path()), &uri, jpeg()).await;
+            assert_eq!(status, StatusCode::OK);
+        }
+        // the second upload to `cam` was a duplicate of the first, which stored nothing new
+        let mut other = Vec::new();
+        image::RgbImage::from_pixel(4, 4, image::Rgb([0; 3]))
+            .write_to(&mut Cursor::new(&mut other), image::ImageFormat::Jpeg)
+            .unwrap();
+        post(test_app(uploads_dir.path()), "/upload/cam", other).await;
+
+        for uri in ["/images", "/images/"] {
+            let request = Request::get(uri).body(Body::empty()).unwrap();
+            let response = test_app(uploads_dir.path()).oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let serials: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            let counts: Vec<_> = serials
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|serial| {
+                    assert!(serial["latest"].is_string());
+                    (
+                        serial["serial_number"].as_str().unwrap(),
+                        serial["images"].as_u64().unwrap(),
+                    )
+                })
+                .collect();
+            assert_eq!(counts, [("cam", 2), ("door", 1)], "{}", uri);
+        }
+    }
+
+    #[tokio::test]
+    async fn rejected_uploads_are_counted_by_reason() {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let app = test_app(uploads_dir.path());
+        let (status, _) = post(app.clone(), "/upload/cam", Vec::new()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = post(app.clone(), "/upload/cam", b"not an image".to_vec()).await;
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        let (status, _) = post(app.clone(), "/upload/cam", b"still not one".to_vec()).await;
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        let (status, _) = post(app.clone(), "/upload/..%2Fcam", jpeg()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let request = Request::get("/metrics").body(Body::empty()).unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let metrics = String::from_utf8(body.to_vec()).unwrap();
+        for line in [
+            "uploads_rejected_total{serial_number=\"\",reason=\"traversal\"} 1",
+            "uploads_rejected_total{serial_number=\"cam\",reason=\"bad_format\"} 2",
+            "uploads_rejected_total{serial_number=\"cam\",reason=\"empty\"} 1",
+        ] {
+            assert!(metrics.lines().any(|metric| metric == line), "{}", metrics);
+        }
+    }
+
+    #[tokio::test]
+    async fn unauthorized_uploads_are_counted_without_their_serial() {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let app = test_app_with(uploads_dir.path(), &["--api-key", "secret"]);
+        for serial_number in ["cam", "other"] {
+            let uri = format!("/upload/{}", serial_number);
+            let (status, _) = post(app.clone(), &uri, jpeg()).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+        }
+
+        let request = Request::get("/metrics").body(Body::empty()).unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let metrics = String::from_utf8(body.to_vec()).unwrap();
+        let line = "uploads_rejected_total{serial_number=\"\",reason=\"unauthorized\"} 2";
+        assert!(metrics.lines().any(|metric| metric == line), "{}", metrics);
+        assert!(!metrics.contains("serial_number=\"cam\""), "{}", metrics);
+    }
+
+    #[tokio::test]
+    async fn health_fails_once_the_uploads_directory_is_gone() {
+        let root = tempfile::tempdir().unwrap();
+        let uploads_dir = root.path().join("uploads");
+        std::fs::create_dir(&uploads_dir).unwrap();
+        let app = test_app(&uploads_dir);
+
+        let (status, _, body) = get(app.clone(), "/health").await;
+        assert_eq!(status, StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["status"], "ok");
+
+        std::fs::remove_dir(&uploads_dir).unwrap();
+        let (status, _, body) = get(app, "/health").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["status"], "unavailable");
+    }
+
+    #[tokio::test]
+    async fn the_home_page_is_a_form_posting_to_the_upload_route() {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let (status, headers, body) = get(test_app(uploads_dir.path()), "/").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(headers[header::CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .starts_with("text/html"));
+        let page = String::from_utf8(body).unwrap();
+        assert!(page.contains("<form id=\"upload\">"));
+        assert!(page.contains("\"/upload/\" + encodeURIComponent(serial)"));
+    }
+
+    #[tokio::test]
+    async fn cors_preflights_are_answered_for_the_allowed_origins_only() {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let app = test_app_with(
+            uploads_dir.path(),
+            &["--cors-allowed-origins", "https://app.example"],
+        );
+        let preflight = |origin: &str| {
+            Request::options("/upload/cam/retry-1")
+                .header(header::ORIGIN, origin)
+                .header(header::ACCESS_CONTROL_REQUEST_METHOD, "PUT")
+                .header(
+                    header::ACCESS_CONTROL_REQUEST_HEADERS,
+                    "x-api-key,content-encoding,x-filename",
+                )
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let (status, headers, _) = send(app.clone(), preflight("https://app.example")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://app.example"
+        );
+        let methods = headers[header::ACCESS_CONTROL_ALLOW_METHODS]
+            .to_str()
+            .unwrap();
+        assert!(methods.contains("PUT"), "{}", methods);
+        let allowed = headers[header::ACCESS_CONTROL_ALLOW_HEADERS]
+            .to_str()
+            .unwrap();
+        for name in ["x-api-key", "content-encoding", "x-filename"] {
+            assert!(allowed.contains(name), "{}", allowed);
+        }
+
+        let (_, headers, _) = send(app, preflight("https://other.example")).await;
+        assert!(!headers.contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+    }
+
+    #[tokio::test]
+    async fn uploads_take_the_api_key_in_either_header() {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let app = test_app_with(uploads_dir.path(), &["--api-key", "secret"]);
+        let upload = |header: Option<(HeaderName, &str)>| {
+            let mut request = Request::post("/upload/cam");
+            if let Some((name, value)) = header {
+                request = request.header(name, value);
+            }
+            request.body(Body::from(jpeg())).unwrap()
+        };
+
+        let (status, _, _) = send(app.clone(), upload(None)).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _, _) = send(app.clone(), upload(Some((X_API_KEY, "wrong")))).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _, _) = send(app.clone(), upload(Some((X_API_KEY, "secret")))).await;
+        assert_eq!(status, StatusCode::OK);
+        let bearer = Some((header::AUTHORIZATION, "Bearer secret"));
+        let (status, _, _) = send(app, upload(bearer)).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn uploads_past_the_rate_limit_are_told_when_to_retry() {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let app = test_app_with(
+            uploads_dir.path(),
+            &["--upload-rate-limit", "0.5", "--upload-rate-burst", "1"],
+        );
+
+        let (status, _) = post(app.clone(), "/upload/cam", jpeg()).await;
+        assert_eq!(status, StatusCode::OK);
+        let request = Request::post("/upload/cam")
+            .body(Body::from(jpeg()))
+            .unwrap();
+        let (status, headers, body) = send(app, request).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(headers[header::RETRY_AFTER], "2");
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["code"], "too_many_requests");
+    }
+
+    #[tokio::test]
+    async fn images_unchanged_since_their_etag_are_not_sent_again() {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let app = test_app(uploads_dir.path());
+        let (status, body) = post(app.clone(), "/upload/cam", jpeg()).await;
+        assert_eq!(status, StatusCode::OK);
+        let response: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let image_uri = format!("/images/cam/{}", response["filename"].as_str().unwrap());
+
+        for uri in [image_uri.as_str(), "/latest/cam"] {
+            let (status, headers, _) = get(app.clone(), uri).await;
+            assert_eq!(status, StatusCode::OK, "{}", uri);
+            let etag = headers[header::ETAG].clone();
+            let request = Request::get(uri)
+                .header(header::IF_NONE_MATCH, etag.clone())
+                .body(Body::empty())
+                .unwrap();
+            let (status, headers, body) = send(app.clone(), request).await;
+            assert_eq!(status, StatusCode::NOT_MODIFIED, "{}", uri);
+            assert_eq!(headers[header::ETAG], etag);
+            assert!(body.is_empty());
+
+            let request = Request::get(uri)
+                .header(header::IF_NONE_MATCH, "\"stale\"")
+                .body(Body::empty())
+                .unwrap();
+            let (status, _, _) = send(app.clone(), request).await;
+            assert_eq!(status, StatusCode::OK, "{}", uri);
+        }
+    }
+
+    #[tokio::test]
+    async fn x_filename_names_the_stored_image_when_it_is_safe() {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let app = test_app(uploads_dir.path());
+        let upload = |filename: &str, body: Vec<u8>| {
+            Request::post("/upload/cam")
+                .header(X_FILENAME, filename)
+                .body(Body::from(body))
+                .unwrap()
+        };
+
+        // the extension follows the detected format
+        let (status, _, body) = send(app.clone(), upload("front-door.png", jpeg())).await;
+        assert_eq!(status, StatusCode::OK);
+        let response: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(response["filename"], "front-door.jpg");
+        assert!(uploads_dir.path().join("cam/front-door.jpg").is_file());
+
+        // not a duplicate of the first
+        let mut other = jpeg();
+        other.push(0);
+        let (status, _, body) = send(app, upload("../escaped.jpg", other)).await;
+        assert_eq!(status, StatusCode::OK);
+        let response: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert!(response["filename"].as_str().unwrap().starts_with("image-"));
+        assert!(!uploads_dir.path().join("escaped.jpg").exists());
+    }
+
+    #[tokio::test]
+    async fn the_images_of_a_serial_number_download_as_a_zip() {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let app = test_app(uploads_dir.path());
+        let request = Request::post("/upload/cam")
+            .header(X_FILENAME, "front.jpg")
+            .body(Body::from(jpeg()))
+            .unwrap();
+        let (status, _, _) = send(app.clone(), request).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, headers, body) = get(app.clone(), "/images/cam/archive.zip").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::CONTENT_TYPE], "application/zip");
+        assert_eq!(
+            headers[header::CONTENT_DISPOSITION],
+            "attachment; filename=\"cam.zip\""
+        );
+        // images are stored in the archive as they are
+        assert!(body.starts_with(b"PK\x03\x04"));
+        let contains = |part: &[u8]| body.windows(part.len()).any(|window| window == part);
+        assert!(contains(b"front.jpg"));
+        assert!(contains(&jpeg()));
+
+        let (status, _, _) = get(app, "/images/unknown/archive.zip").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn served_images_are_labelled_with_their_sniffed_format() {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let serial_dir = uploads_dir.path().join("cam");
+        std::fs::create_dir(&serial_dir).unwrap();
+        // stored by an older version under the extension the client gave it
+        let mut png = Vec::new();
+        image::RgbImage::from_pixel(4, 4, image::Rgb([0; 3]))
+            .write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+        std::fs::write(serial_dir.join("old.jpg"), &png).unwrap();
+
+        let (status, headers, body) =
+            get(test_app(uploads_dir.path()), "/images/cam/old.jpg").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::CONTENT_TYPE], "image/png");
+        assert_eq!(body, png);
+    }
+
+    #[tokio::test]
+    async fn the_config_endpoint_reports_the_settings_without_the_api_key() {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let app = test_app_with(
+            uploads_dir.path(),
+            &["--api-key", "secret", "--max-upload-bytes", "1234"],
+        );
+
+        let (status, _, _) = get(app.clone(), "/config").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let request = Request::get("/config")
+            .header(X_API_KEY, "secret")
+            .body(Body::empty())
+            .unwrap();
+        let (status, _, body) = send(app, request).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(!String::from_utf8_lossy(&body).contains("secret"));
+        let config: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(config["max_upload_bytes"], 1234);
+        assert_eq!(config["auth"], true);
+        assert_eq!(config["storage_backend"], "fs");
+    }
+
+    #[tokio::test]
+    async fn listings_include_sidecars_when_asked() {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let app = test_app(uploads_dir.path());
+        let image = jpeg();
+        let request = Request::post("/upload/cam")
+            .header(header::USER_AGENT, "esp32cam/1.0")
+            .body(Body::from(image.clone()))
+            .unwrap();
+        let (status, _, _) = send(app.clone(), request).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, _, body) = get(app.clone(), "/images/cam/list").await;
+        assert_eq!(status, StatusCode::OK);
+        let listed: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert!(listed[0].get("sidecar").is_none());
+
+        let (status, _, body) = get(app, "/images/cam/list?sidecars=true").await;
+        assert_eq!(status, StatusCode::OK);
+        let listed: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let sidecar = &listed[0]["sidecar"];
+        assert_eq!(sidecar["bytes"], image.len());
+        assert_eq!(sidecar["client_ip"], "127.0.0.1");
+        assert_eq!(sidecar["user_agent"], "esp32cam/1.0");
+        assert_eq!(sidecar["format"], "jpeg");
+    }
+
+    #[tokio::test]
+    async fn validated_uploads_are_checked_but_not_stored() {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let app = test_app(uploads_dir.path());
+        let request = Request::post("/upload/cam?validate=true")
+            .header(X_FILENAME, "front.jpg")
+            .body(Body::from(jpeg()))
+            .unwrap();
+        let (status, _, body) = send(app.clone(), request).await;
+        assert_eq!(status, StatusCode::OK);
+        let response: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(response["filename"], "front.jpg");
+        assert_eq!(response["bytes"], jpeg().len());
+        assert_eq!(response["duplicate"], false);
+        assert!(!uploads_dir.path().join("cam/front.jpg").exists());
+        assert!(!uploads_dir.path().join("cam/aaa-latest.jpg").exists());
+
+        let (status, _) = post(app, "/upload/cam?validate=true", b"not an image".to_vec()).await;
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    #[tokio::test]
+    async fn the_uploads_of_a_serial_number_are_pushed_to_its_event_websocket() {
+        use tokio_tungstenite::tungstenite::Message;
+
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let app = test_app(uploads_dir.path());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn({
+            let app = app
+                .clone()
+                .into_make_service_with_connect_info::<SocketAddr>();
+            async move { axum::serve(listener, app).await }
+        });
+
+        let (mut socket, _) = tokio_tungstenite::connect_async(format!("ws://{}/events/cam", addr))
+            .await
+            .unwrap();
+        let (status, _) = post(app.clone(), "/upload/other", jpeg()).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, body) = post(app, "/upload/cam", jpeg()).await;
+        assert_eq!(status, StatusCode::OK);
+        let response: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+        let event = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Message::Text(text) = socket.next().await.unwrap().unwrap() {
+                    break text;
+                }
+            }
+        })
+        .await
+        .unwrap();
+        let event: serde_json::Value = serde_json::from_str(&event).unwrap();
+        assert_eq!(event["serial_number"], "cam");
+        assert_eq!(event["filename"], response["filename"]);
+        assert_eq!(event["bytes"], jpeg().len());
+    }
+
+    #[tokio::test]
+    async fn the_uploads_of_a_serial_number_are_sent_as_server_sent_events() {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let app = test_app(uploads_dir.path());
+        let request = Request::get("/sse/cam").body(Body::empty()).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "text/event-stream"
+        );
+        let mut stream = response.into_body().into_data_stream();
+
+        let (status, body) = post(app, "/upload/cam", jpeg()).await;
+        assert_eq!(status, StatusCode::OK);
+        let response: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+        let chunk = tokio::time::timeout(Duration::from_secs(5), stream.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        let chunk = String::from_utf8(chunk.to_vec()).unwrap();
+        let data = chunk
+            .lines()
+            .find_map(|line| line.strip_prefix("data: "))
+            .unwrap();
+        assert!(chunk.starts_with("event: upload\n"), "{}", chunk);
+        let event: serde_json::Value = serde_json::from_str(data).unwrap();
+        assert_eq!(event["filename"], response["filename"]);
+    }
+
+    #[tokio::test]
+    async fn the_gallery_pages_through_every_serial_number() {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let app = test_app(uploads_dir.path());
+        for serial_number in ["cam", "door"] {
+            let (status, _) =
+                post(app.clone(), &format!("/upload/{}", serial_number), jpeg()).await;
+            assert_eq!(status, StatusCode::OK);
+        }
+
+        let (status, _, body) = get(app.clone(), "/gallery?limit=1").await;
+        assert_eq!(status, StatusCode::OK);
+        let page: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(page["total"], 2);
+        assert_eq!(page["limit"], 1);
+        assert_eq!(page["entries"].as_array().unwrap().len(), 1);
+        let (_, _, body) = get(app.clone(), "/gallery?limit=1&offset=1").await;
+        let next: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_ne!(next["entries"][0], page["entries"][0]);
+
+        let (status, _, body) = get(app, "/gallery?serial=door").await;
+        assert_eq!(status, StatusCode::OK);
+        let page: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(page["total"], 1);
+        assert_eq!(page["entries"][0]["serial"], "door");
+    }
+
+    #[tokio::test]
+    async fn browsing_takes_the_basic_auth_credentials_and_uploading_does_not() {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let app = test_app_with(
+            uploads_dir.path(),
+            &["--browse-username", "user", "--browse-password", "pass"],
+        );
+        let (status, _) = post(app.clone(), "/upload/cam", jpeg()).await;
+        assert_eq!(status, StatusCode::OK);
+
+        for uri in ["/images/cam/list", "/latest/cam", "/gallery"] {
+            let (status, headers, _) = get(app.clone(), uri).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED, "{}", uri);
+            assert!(headers[header::WWW_AUTHENTICATE]
+                .to_str()
+                .unwrap()
+                .starts_with("Basic "));
+            let request = Request::get(uri)
+                // `user:pass`
+                .header(header::AUTHORIZATION, "Basic dXNlcjpwYXNz")
+                .body(Body::empty())
+                .unwrap();
+            let (status, _, _) = send(app.clone(), request).await;
+            assert_eq!(status, StatusCode::OK, "{}", uri);
+        }
+    }
+
+    #[tokio::test]
+    async fn other_methods_on_the_upload_routes_are_told_which_one_to_use() {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let app = test_app(uploads_dir.path());
+        for (uri, allowed) in [
+            ("/upload/cam", "POST"),
+            ("/upload-form/cam", "POST"),
+            ("/upload-batch/cam", "POST"),
+            ("/upload/cam/retry-1", "PUT"),
+        ] {
+            let (status, headers, body) = get(app.clone(), uri).await;
+            assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED, "{}", uri);
+            assert_eq!(headers[header::ALLOW], allowed, "{}", uri);
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(body["code"], "method_not_allowed");
+        }
+    }
+
+    #[tokio::test]
+    async fn gzip_encoded_uploads_are_stored_decompressed() {
+        use async_compression::tokio::bufread::GzipEncoder;
+
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let app = test_app(uploads_dir.path());
+        let image = jpeg();
+        let mut compressed = Vec::new();
+        GzipEncoder::new(&image[..])
+            .read_to_end(&mut compressed)
+            .await
+            .unwrap();
+
+        let request = Request::post("/upload/cam")
+            .header(header::CONTENT_ENCODING, "gzip")
+            .body(Body::from(compressed))
+            .unwrap();
+        let (status, _, body) = send(app.clone(), request).await;
+        assert_eq!(status, StatusCode::OK);
+        let response: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let filename = response["filename"].as_str().unwrap();
+        assert_eq!(
+            std::fs::read(uploads_dir.path().join("cam").join(filename)).unwrap(),
+            image
+        );
+
+        let request = Request::post("/upload/cam")
+            .header(header::CONTENT_ENCODING, "gzip")
+            .body(Body::from(image))
+            .unwrap();
+        let (status, _, _) = send(app, request).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn browsers_get_a_favicon_and_an_html_page_for_unknown_paths() {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let app = test_app(uploads_dir.path());
+
+        let (status, headers, body) = get(app.clone(), "/favicon.ico").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::CONTENT_TYPE], "image/x-icon");
+        assert_eq!(body, FAVICON);
+
+        let (status, headers, body) = get(app, "/nothing-here").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(headers[header::CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .starts_with("text/html"));
+        assert!(String::from_utf8(body).unwrap().contains("<a href=\"/\">"));
+    }
+
+    // The client IP recorded in the sidecar of an upload with `headers`, with the options `args`
+    async fn recorded_client_ip(args: &[&str], headers: &[(&str, &str)]) -> serde_json::Value {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let app = test_app_with(uploads_dir.path(), args);
+        let mut request = Request::post("/upload/cam");
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        let (status, _, _) = send(app.clone(), request.body(Body::from(jpeg())).unwrap()).await;
+        assert_eq!(status, StatusCode::OK);
+        let (_, _, body) = get(app, "/images/cam/list?sidecars=true").await;
+        let listed: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        listed[0]["sidecar"]["client_ip"].clone()
+    }
+
+    #[tokio::test]
+    async fn x_forwarded_for_names_the_client_only_when_trusted() {
+        let forwarded = [("x-forwarded-for", "198.51.100.1, 203.0.113.7")];
+        assert_eq!(recorded_client_ip(&[], &forwarded).await, "127.0.0.1");
+        assert_eq!(
+            recorded_client_ip(&["--trust-forwarded-for"], &forwarded).await,
+            "203.0.113.7"
+        );
+    }
+
+    #[tokio::test]
+    async fn head_on_the_latest_image_tells_its_length_and_age_only() {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let app = test_app(uploads_dir.path());
+        let image = jpeg();
+        let (status, _) = post(app.clone(), "/upload/cam", image.clone()).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let request = Request::head("/latest/cam").body(Body::empty()).unwrap();
+        let (status, headers, body) = send(app.clone(), request).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::CONTENT_LENGTH], image.len().to_string());
+        assert_eq!(headers[header::CONTENT_TYPE], "image/jpeg");
+        assert!(headers.contains_key(header::LAST_MODIFIED));
+        assert!(body.is_empty());
+
+        let request = Request::head("/latest/other").body(Body::empty()).unwrap();
+        let (status, _, _) = send(app, request).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn byte_ranges_of_the_latest_image_are_served_partially() {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let app = test_app(uploads_dir.path());
+        let image = jpeg();
+        let (status, _) = post(app.clone(), "/upload/cam", image.clone()).await;
+        assert_eq!(status, StatusCode::OK);
+        let ranged = |range: &str| {
+            Request::get("/latest/cam")
+                .header(header::RANGE, range)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let (status, headers, body) = send(app.clone(), ranged("bytes=2-5")).await;
+        assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            headers[header::CONTENT_RANGE],
+            format!("bytes 2-5/{}", image.len())
+        );
+        assert_eq!(body, image[2..=5]);
+
+        let (status, headers, _) = send(app, ranged(&format!("bytes={}-", image.len()))).await;
+        assert_eq!(status, StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(
+            headers[header::CONTENT_RANGE],
+            format!("bytes */{}", image.len())
+        );
+    }
+
+    #[tokio::test]
+    async fn the_version_endpoint_tells_which_build_runs() {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let (status, _, body) = get(test_app(uploads_dir.path()), "/version").await;
+        assert_eq!(status, StatusCode::OK);
+        let version: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(version["version"], env!("CARGO_PKG_VERSION"));
+        assert!(version.get("git_commit").is_some());
+        assert!(version.get("built_at").is_some());
+    }
+
+    #[tokio::test]
+    async fn batch_uploads_answer_for_each_file() {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let mut body = Vec::new();
+        for (filename, content) in [("a.jpg", jpeg()), ("b.jpg", b"not an image".to_vec())] {
+            body.extend_from_slice(
+                format!(
+                    "--boundary\r\n\
+                    Content-Disposition: form-data; name=\"files\"; filename=\"{}\"\r\n\
+                    Content-Type: image/jpeg\r\n\r\n",
+                    filename
+                )
+                .as_bytes(),
+            );
+            body.extend_from_slice(&content);
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(b"--boundary--\r\n");
+
+        let request = Request::post("/upload-batch/cam")
+            .header(
+                header::CONTENT_TYPE,
+                "multipart/form-data; boundary=boundary",
+            )
+            .body(Body::from(body))
+            .unwrap();
+        let (status, _, body) = send(test_app(uploads_dir.path()), request).await;
+        assert_eq!(status, StatusCode::OK);
+        let parts: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(parts[0]["part"], "a.jpg");
+        let stored = parts[0]["filename"].as_str().unwrap();
+        assert!(uploads_dir.path().join("cam").join(stored).is_file());
+        assert_eq!(parts[1]["part"], "b.jpg");
+        assert_eq!(parts[1]["code"], "unsupported_media_type");
+    }
+
+    #[tokio::test]
+    async fn deleting_a_serial_number_removes_everything_stored_for_it() {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let app = test_app(uploads_dir.path());
+        let (status, _) = post(app.clone(), "/upload/cam", jpeg()).await;
+        assert_eq!(status, StatusCode::OK);
+        let serial_dir = uploads_dir.path().join("cam");
+        let files = std::fs::read_dir(&serial_dir).unwrap().count();
+
+        let request = Request::delete("/images/cam").body(Body::empty()).unwrap();
+        let (status, _, body) = send(app.clone(), request).await;
+        assert_eq!(status, StatusCode::OK);
+        let response: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(response["deleted_files"], files);
+        assert!(!serial_dir.exists());
+        let (status, _, _) = get(app.clone(), "/latest/cam").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let request = Request::delete("/images/cam").body(Body::empty()).unwrap();
+        let (status, _, _) = send(app, request).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn listings_sort_by_modification_time_when_asked() {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let app = test_app(uploads_dir.path());
+        for (filename, shade) in [("a.jpg", 0), ("b.jpg", 255)] {
+            let mut bytes = Vec::new();
+            image::RgbImage::from_pixel(4, 4, image::Rgb([shade; 3]))
+                .write_to(&mut Cursor::new(&mut bytes), image::ImageFormat::Jpeg)
+                .unwrap();
+            let request = Request::post("/upload/cam")
+                .header(X_FILENAME, filename)
+                .body(Body::from(bytes))
+                .unwrap();
+            let (status, _, _) = send(app.clone(), request).await;
+            assert_eq!(status, StatusCode::OK);
+        }
+        // `a.jpg` was touched since
+        std::fs::File::options()
+            .write(true)
+            .open(uploads_dir.path().join("cam/a.jpg"))
+            .unwrap()
+            .set_modified(std::time::SystemTime::now() + Duration::from_secs(60))
+            .unwrap();
+
+        let filenames = |uri: &'static str| {
+            let app = app.clone();
+            async move {
+                let (status, _, body) = get(app, uri).await;
+                assert_eq!(status, StatusCode::OK);
+                let listed: Vec<serde_json::Value> = serde_json::from_slice(&body).unwrap();
+                listed
+                    .into_iter()
+                    .map(|entry| entry["filename"].as_str().unwrap().to_owned())
+                    .collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(filenames("/images/cam/list").await, ["b.jpg", "a.jpg"]);
+        assert_eq!(
+            filenames("/images/cam/list?sort=mtime").await,
+            ["a.jpg", "b.jpg"]
+        );
+    }
+
+    #[tokio::test]
+    async fn contact_sheets_montage_the_newest_images_of_a_serial_number() {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let app = test_app(uploads_dir.path());
+        let (status, _) = post(app.clone(), "/upload/cam", jpeg()).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, headers, body) = get(app.clone(), "/images/cam/contact-sheet?cols=2").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::CONTENT_TYPE], "image/jpeg");
+        let sheet = image::load_from_memory(&body).unwrap();
+        // a single image takes a single tile
+        assert_eq!(sheet.width(), CONTACT_SHEET_TILE_SIZE);
+        assert_eq!(sheet.height(), CONTACT_SHEET_TILE_SIZE);
+
+        let (status, _, _) = get(app.clone(), "/images/cam/contact-sheet?cols=0").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _, _) = get(app, "/images/other/contact-sheet").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn trusted_proxies_are_skipped_to_find_the_client() {
+        let proxies = ["--trusted-proxies", "127.0.0.1,10.0.0.0/8"];
+        let forwarded = [("x-forwarded-for", "203.0.113.7, 198.51.100.1, 10.1.2.3")];
+        assert_eq!(
+            recorded_client_ip(&proxies, &forwarded).await,
+            "198.51.100.1"
+        );
+        let forwarded = [("forwarded", "for=203.0.113.7")];
+        assert_eq!(
+            recorded_client_ip(&proxies, &forwarded).await,
+            "203.0.113.7"
+        );
+        // a peer that is not one of them is the client, whatever it forwards
+        let proxies = ["--trusted-proxies", "10.0.0.0/8"];
+        assert_eq!(recorded_client_ip(&proxies, &forwarded).await, "127.0.0.1");
+    }
+
+    #[test]
+    fn contact_sheets_lay_the_images_out_in_rows() {
+        let dir = tempfile::tempdir().unwrap();
+        let black = dir.path().join("black.png");
+        image::RgbImage::from_pixel(40, 20, image::Rgb([0; 3]))
+            .save(&black)
+            .unwrap();
+        let sources = [black.clone(), dir.path().join("missing.png"), black];
+
+        let background = image::Rgb([255; 3]);
+        let jpeg = compose_contact_sheet(&sources, 2, background).unwrap();
+        let sheet = image::load_from_memory(&jpeg).unwrap().to_rgb8();
+        let tile = CONTACT_SHEET_TILE_SIZE;
+        assert_eq!(sheet.dimensions(), (2 * tile, 2 * tile));
+        // the centers of the tiles, the image that is missing leaves its own empty
+        assert!(sheet.get_pixel(tile / 2, tile / 2)[0] < 16);
+        assert!(sheet.get_pixel(tile + tile / 2, tile / 2)[0] > 240);
+        assert!(sheet.get_pixel(tile / 2, tile + tile / 2)[0] < 16);
+        // above and below the wide image, the tile keeps the background
+        assert!(sheet.get_pixel(tile / 2, 4)[0] > 240);
+    }
+
+    #[test]
+    fn transparent_pixels_are_filled_with_the_thumbnail_background() {
+        let dir = tempfile::tempdir().unwrap();
+        let (source, destination) = (dir.path().join("clear.png"), dir.path().join("thumb.jpg"));
+        image::RgbaImage::from_pixel(8, 8, image::Rgba([0, 0, 0, 0]))
+            .save(&source)
+            .unwrap();
+
+        for background in ["ffffff", "#3366cc"] {
+            let background = parse_color(background).unwrap();
+            write_thumbnail(&source, &destination, background).unwrap();
+            let thumbnail = image::open(&destination).unwrap().to_rgb8();
+            // JPEG is lossy, so the fill only has to come close
+            for pixel in thumbnail.pixels() {
+                for (channel, expected) in pixel.0.iter().zip(background.0) {
+                    assert!(channel.abs_diff(expected) <= 4, "{:?}", pixel);
+                }
+            }
+        }
     }
-    components.count() == 1
 }
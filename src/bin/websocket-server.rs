@@ -17,63 +17,364 @@
 //! ```
 
 use axum::{
-    extract::ws::{Message, WebSocket, WebSocketUpgrade},
+    async_trait,
+    extract::{ws::WebSocketUpgrade, FromRequestParts, State},
+    http::{header, request::Parts, HeaderMap, StatusCode},
     response::IntoResponse,
     routing::get,
-    Router,
+    Json, Router,
 };
 use axum_extra::TypedHeader;
+use axum_server::tls_rustls::RustlsConfig;
 
-use chrono::Local;
-use std::borrow::Cow;
-use std::io;
-use std::ops::ControlFlow;
-use std::{net::SocketAddr, path::PathBuf};
-use tokio::{
-    fs::File,
-    io::{BufReader, BufWriter},
-};
+use std::time::Duration;
+use std::{net::SocketAddr, path::PathBuf, sync::Arc};
 use tower_http::{
     services::ServeDir,
     trace::{DefaultMakeSpan, TraceLayer},
 };
+use upload_image::forwarded::{self, IpCidr};
+use upload_image::storage::{
+    parse_mode, remove_stale_temps, FilenameTime, ImageFormat, LatestName, Layout, Naming,
+    SaveOptions, Storage, UploadSource, DEFAULT_WRITE_BUFFER_KB, MAX_WRITE_BUFFER_KB,
+    STALE_TEMP_AGE,
+};
+use upload_image::ws::{
+    self, RateLimit, SocketState, DEFAULT_FRAME_RATE_BURST, DEFAULT_IDLE_TIMEOUT_SECS,
+    DEFAULT_PING_INTERVAL_SECS,
+};
 
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 //allows to extract the IP of connecting user
 use axum::extract::connect_info::ConnectInfo;
-use axum::extract::ws::CloseFrame;
 
-//allows to split the websocket stream into separate TX and RX branches
-use futures::{sink::SinkExt, stream::StreamExt};
+/// used when neither `--bind-addr` nor `BIND_ADDR` is given
+const DEFAULT_BIND_ADDR: &str = "0.0.0.0:3003";
+
+/// used when neither `--uploads-dir` nor `UPLOADS_DIR` is given
+const DEFAULT_UPLOADS_DIRECTORY: &str = "uploads-websocket";
+
+/// used when `MAX_UPLOAD_BYTES` is not set
+const DEFAULT_MAX_UPLOAD_BYTES: usize = 10 * 1024 * 1024;
+
+/// used when `MAX_CONCURRENT_UPLOADS` is not set
+const DEFAULT_MAX_CONCURRENT_UPLOADS: usize = 16;
+
+#[derive(Clone)]
+struct AppState {
+    /// the storage and limits every connection is handled with
+    socket: Arc<SocketState>,
+    /// connections are only accepted with this key, when set
+    api_key: Option<Arc<str>>,
+    /// reverse proxies that are believed about the client they forward a connection for
+    trusted_proxies: Arc<[IpCidr]>,
+}
+
+/// Extractor that rejects the request unless it carries the `UPLOAD_API_KEY`, either as
+/// `Authorization: Bearer <key>` or as `X-API-Key: <key>`. Without a configured key every request
+/// is let through.
+struct RequireApiKey;
+
+#[async_trait]
+impl FromRequestParts<AppState> for RequireApiKey {
+    type Rejection = (StatusCode, String);
 
-const UPLOADS_DIRECTORY: &str = "uploads-websocket";
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let Some(expected) = &state.api_key else {
+            return Ok(RequireApiKey);
+        };
+
+        let header_value = |name| {
+            parts
+                .headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+        };
+        let provided = header_value(header::AUTHORIZATION.as_str())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .or_else(|| header_value("x-api-key"));
+        match provided {
+            Some(provided) if keys_match(provided, expected) => Ok(RequireApiKey),
+            _ => Err((
+                StatusCode::UNAUTHORIZED,
+                "Missing or invalid API key".to_owned(),
+            )),
+        }
+    }
+}
+
+/// Compare keys in constant time, so response timing does not leak how much of a guess was right
+fn keys_match(provided: &str, expected: &str) -> bool {
+    provided.len() == expected.len()
+        && provided
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
 
 #[tokio::main]
 async fn main() {
+    // `LOG_FORMAT=json` logs one JSON object per line, for log aggregators
+    let json_logs = match std::env::var("LOG_FORMAT").as_deref() {
+        Ok("json") => true,
+        Ok("text") | Err(_) => false,
+        Ok(other) => panic!("`LOG_FORMAT` must be `text` or `json`, not `{}`", other),
+    };
     tracing_subscriber::registry()
         .with(
             tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| {
-                format!("{}=debug,tower_http=debug", env!("CARGO_CRATE_NAME")).into()
+                format!(
+                    "{}=debug,upload_image=debug,tower_http=debug",
+                    env!("CARGO_CRATE_NAME")
+                )
+                .into()
             }),
         )
-        .with(tracing_subscriber::fmt::layer())
+        .with(json_logs.then(|| tracing_subscriber::fmt::layer().json()))
+        .with((!json_logs).then(tracing_subscriber::fmt::layer))
         .init();
 
     let assets_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("assets");
 
+    // `--uploads-dir` takes precedence over `UPLOADS_DIR`
+    let uploads_dir = PathBuf::from(
+        cli_flag("uploads-dir")
+            .or_else(|| std::env::var("UPLOADS_DIR").ok())
+            .unwrap_or_else(|| DEFAULT_UPLOADS_DIRECTORY.to_owned()),
+    );
+
+    // `--bind-addr` takes precedence over `BIND_ADDR`
+    let addr: SocketAddr = cli_flag("bind-addr")
+        .or_else(|| std::env::var("BIND_ADDR").ok())
+        .as_deref()
+        .unwrap_or(DEFAULT_BIND_ADDR)
+        .parse()
+        .expect("`BIND_ADDR` must be an address and port such as `0.0.0.0:3003`");
+    println!("saving images to {}", uploads_dir.display());
+
+    // saves a crash interrupted left their temporary files behind
+    match remove_stale_temps(&uploads_dir, STALE_TEMP_AGE).await {
+        Ok(removed) => tracing::info!("removed {} stale temporary files", removed),
+        Err(err) => tracing::warn!("could not remove stale temporary files: {}", err),
+    }
+
+    let max_upload_bytes = match std::env::var("MAX_UPLOAD_BYTES") {
+        Ok(value) => value
+            .parse()
+            .expect("`MAX_UPLOAD_BYTES` must be a number of bytes"),
+        Err(_) => DEFAULT_MAX_UPLOAD_BYTES,
+    };
+
+    let frame_rate_limit = std::env::var("FRAME_RATE_LIMIT")
+        .ok()
+        .map(|value| RateLimit {
+            per_second: value
+                .parse()
+                .ok()
+                .filter(|rate: &f64| *rate > 0.0)
+                .expect("`FRAME_RATE_LIMIT` must be a positive number of frames per second"),
+            burst: match std::env::var("FRAME_RATE_BURST") {
+                Ok(value) => value
+                    .parse()
+                    .ok()
+                    .filter(|burst: &f64| *burst >= 1.0)
+                    .expect("`FRAME_RATE_BURST` must be a number of frames of at least 1"),
+                Err(_) => DEFAULT_FRAME_RATE_BURST,
+            },
+        });
+
+    let seconds = |name: &str, default: u64| match std::env::var(name) {
+        Ok(value) => Duration::from_secs(
+            value
+                .parse()
+                .ok()
+                .filter(|seconds| *seconds > 0)
+                .unwrap_or_else(|| panic!("`{}` must be a positive number of seconds", name)),
+        ),
+        Err(_) => Duration::from_secs(default),
+    };
+    let ping_interval = seconds("PING_INTERVAL_SECS", DEFAULT_PING_INTERVAL_SECS);
+    let idle_timeout = seconds("IDLE_TIMEOUT_SECS", DEFAULT_IDLE_TIMEOUT_SECS);
+
     // build our application with some routes
     let app = Router::new()
         .fallback_service(ServeDir::new(assets_dir).append_index_html_on_directories(true))
         .route("/ws", get(ws_handler))
+        .route("/version", get(version))
         // logging so we can see whats going on
         .layer(
             TraceLayer::new_for_http()
                 .make_span_with(DefaultMakeSpan::default().include_headers(true)),
-        );
+        )
+        .with_state(AppState {
+            socket: Arc::new(SocketState {
+                storage: Arc::new(Storage::new(
+                uploads_dir,
+                SaveOptions {
+                    max_bytes: max_upload_bytes as u64,
+                    max_pixels: std::env::var("MAX_MEGAPIXELS").ok().map(|value| {
+                        let megapixels: f64 = value
+                            .parse()
+                            .ok()
+                            .filter(|megapixels: &f64| megapixels.is_finite() && *megapixels > 0.0)
+                            .expect("`MAX_MEGAPIXELS` must be a positive number of megapixels");
+                        (megapixels * 1_000_000.0) as u64
+                    }),
+                    strip_metadata: match std::env::var("STRIP_EXIF") {
+                        Ok(value) => value
+                            .parse()
+                            .expect("`STRIP_EXIF` must be `true` or `false`"),
+                        Err(_) => false,
+                    },
+                    webp_quality: None,
+                    jpeg_max_bytes: std::env::var("JPEG_MAX_BYTES").ok().map(|value| {
+                        value
+                            .parse()
+                            .expect("`JPEG_MAX_BYTES` must be a number of bytes")
+                    }),
+                    // frames that are not recognized keep the historical `.jpg` naming
+                    fallback_format: Some(ImageFormat::Jpeg),
+                    allowed_formats: std::env::var("ALLOWED_FORMATS").ok().map(|formats| {
+                        formats
+                            .split(',')
+                            .map(|format| {
+                                format
+                                    .parse()
+                                    .unwrap_or_else(|err| panic!("`ALLOWED_FORMATS`: {}", err))
+                            })
+                            .collect()
+                    }),
+                    max_concurrent_writes: match std::env::var("MAX_CONCURRENT_UPLOADS") {
+                        Ok(value) => value.parse().ok().filter(|uploads| *uploads >= 1).expect(
+                            "`MAX_CONCURRENT_UPLOADS` must be a number of uploads of at least 1",
+                        ),
+                        Err(_) => DEFAULT_MAX_CONCURRENT_UPLOADS,
+                    },
+                    // a camera streams its next image on the same connection, it might as well wait
+                    reject_when_busy: false,
+                    write_buffer_bytes: match std::env::var("WRITE_BUFFER_KB") {
+                        Ok(value) => value
+                            .parse()
+                            .ok()
+                            .filter(|kb: &usize| (1..=MAX_WRITE_BUFFER_KB).contains(kb))
+                            .unwrap_or_else(|| {
+                                panic!(
+                                    "`WRITE_BUFFER_KB` must be a number of KiB from 1 to {}",
+                                    MAX_WRITE_BUFFER_KB
+                                )
+                            }),
+                        Err(_) => DEFAULT_WRITE_BUFFER_KB,
+                    } * 1024,
+                    // images are only saved once every frame of them is in memory
+                    receive_timeout: None,
+                    fsync: match std::env::var("FSYNC") {
+                        Ok(value) => value.parse().expect("`FSYNC` must be `true` or `false`"),
+                        Err(_) => false,
+                    },
+                    serial_quota_bytes: std::env::var("SERIAL_QUOTA_BYTES").ok().map(|value| {
+                        value
+                            .parse()
+                            .expect("`SERIAL_QUOTA_BYTES` must be a number of bytes")
+                    }),
+                    evict_oldest: match std::env::var("EVICT_OLDEST") {
+                        Ok(value) => value
+                            .parse()
+                            .expect("`EVICT_OLDEST` must be `true` or `false`"),
+                        Err(_) => false,
+                    },
+                    near_duplicate_distance: std::env::var("NEAR_DUPLICATE_DISTANCE").ok().map(
+                        |value| {
+                            value
+                                .parse()
+                                .ok()
+                                .filter(|distance| *distance <= 64)
+                                .expect(
+                                    "`NEAR_DUPLICATE_DISTANCE` must be a number of bits up to 64",
+                                )
+                        },
+                    ),
+                    naming: std::env::var("NAMING").map_or(Naming::default(), |value| {
+                        value
+                            .parse()
+                            .unwrap_or_else(|err| panic!("`NAMING`: {}", err))
+                    }),
+                    layout: std::env::var("LAYOUT").map_or(Layout::default(), |value| {
+                        value
+                            .parse()
+                            .unwrap_or_else(|err| panic!("`LAYOUT`: {}", err))
+                    }),
+                    filename_time: FilenameTime::new(
+                        &std::env::var("FILENAME_TIME_FORMAT")
+                            .unwrap_or_else(|_| FilenameTime::DEFAULT_FORMAT.to_owned()),
+                        match std::env::var("USE_UTC") {
+                            Ok(value) => {
+                                value.parse().expect("`USE_UTC` must be `true` or `false`")
+                            }
+                            Err(_) => true,
+                        },
+                    )
+                    .unwrap_or_else(|err| panic!("`FILENAME_TIME_FORMAT`: {}", err)),
+                    latest_name: match std::env::var("LATEST_FILENAME") {
+                        Ok(name) => LatestName::new(&name)
+                            .unwrap_or_else(|err| panic!("`LATEST_FILENAME`: {}", err)),
+                        Err(_) => LatestName::default(),
+                    },
+                    file_mode: std::env::var("FILE_MODE").ok().map(|value| {
+                        parse_mode(&value).unwrap_or_else(|err| panic!("`FILE_MODE`: {}", err))
+                    }),
+                    dir_mode: std::env::var("DIR_MODE").ok().map(|value| {
+                        parse_mode(&value).unwrap_or_else(|err| panic!("`DIR_MODE`: {}", err))
+                    }),
+                },
+                )),
+                max_upload_bytes,
+                frame_rate_limit,
+                ping_interval,
+                idle_timeout,
+            }),
+            api_key: std::env::var("UPLOAD_API_KEY").ok().map(Arc::from),
+            trusted_proxies: match std::env::var("TRUSTED_PROXIES") {
+                Ok(value) => value
+                    .split(',')
+                    .map(|proxy| {
+                        proxy
+                            .parse()
+                            .unwrap_or_else(|err| panic!("`TRUSTED_PROXIES`: {}", err))
+                    })
+                    .collect(),
+                Err(_) => Arc::from([]),
+            },
+        });
+
+    // serve WSS directly when a certificate is configured
+    let tls_config = match (std::env::var("TLS_CERT"), std::env::var("TLS_KEY")) {
+        (Ok(cert), Ok(key)) => Some(
+            RustlsConfig::from_pem_file(cert, key)
+                .await
+                .expect("failed to load the `TLS_CERT` certificate and `TLS_KEY` key"),
+        ),
+        (Err(_), Err(_)) => None,
+        _ => panic!("`TLS_CERT` and `TLS_KEY` must be set together"),
+    };
+
+    if let Some(tls_config) = tls_config {
+        tracing::debug!("listening on {} with TLS", addr);
+        axum_server::bind_rustls(addr, tls_config)
+            .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+            .await
+            .unwrap();
+        return;
+    }
 
     // run it with hyper
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3003").await.unwrap();
+    let listener = tokio::net::TcpListener::bind(addr)
+        .await
+        .unwrap_or_else(|err| panic!("failed to listen on {}: {}", addr, err));
     tracing::debug!("listening on {}", listener.local_addr().unwrap());
     axum::serve(
         listener,
@@ -83,182 +384,62 @@ async fn main() {
     .unwrap();
 }
 
+/// Tells which build is running: crate version, git commit and build time
+async fn version() -> Json<serde_json::Value> {
+    Json(upload_image::version::build_info())
+}
+
 /// The handler for the HTTP request (this gets called when the HTTP GET lands at the start
 /// of websocket negotiation). After this completes, the actual switching from HTTP to
 /// websocket protocol will occur.
 /// This is the last point where we can extract TCP/IP metadata such as IP address of the client
 /// as well as things from HTTP headers such as user-agent of the browser etc.
 async fn ws_handler(
+    _: RequireApiKey,
     ws: WebSocketUpgrade,
+    State(state): State<AppState>,
     user_agent: Option<TypedHeader<headers::UserAgent>>,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
 ) -> impl IntoResponse {
-    let user_agent = if let Some(TypedHeader(user_agent)) = user_agent {
+    let user_agent_header = user_agent;
+    let user_agent = if let Some(TypedHeader(user_agent)) = &user_agent_header {
         user_agent.to_string()
     } else {
         String::from("Unknown browser")
     };
     println!("`{user_agent}` at {addr} connected.");
+    // behind a trusted reverse proxy the peer is the proxy, and the client is the one it names
+    let source = UploadSource {
+        client_ip: Some(forwarded::client_ip(
+            addr.ip(),
+            &headers,
+            false,
+            &state.trusted_proxies,
+        )),
+        user_agent: user_agent_header.map(|TypedHeader(user_agent)| user_agent.to_string()),
+        // each image is received when its first frame arrives
+        received_at: None,
+    };
     // finalize the upgrade process by returning upgrade callback.
     // we can customize the callback by sending additional info such as address.
-    ws.on_upgrade(move |socket| handle_socket(socket, addr))
+    ws::upgrade(ws, addr, source, state.socket)
 }
 
-/// Actual websocket statemachine (one will be spawned per connection)
-async fn handle_socket(mut socket: WebSocket, who: SocketAddr) {
-    let mut serial_number = String::from("undefined");
-    // send a ping (unsupported by some browsers) just to kick things off and get a response
-    if socket.send(Message::Ping(vec![1, 2, 3])).await.is_ok() {
-        println!("Pinged {who}...");
-    } else {
-        println!("Could not send ping {who}!");
-        // no Error here since the only thing we can do is to close the connection.
-        // If we can not send messages, there is no way to salvage the statemachine anyway.
-        return;
-    }
-
-    if let Some(msg) = socket.recv().await {
-        if let Ok(msg) = msg {
-            let message = get_text(msg, who);
-            if message.is_break() {
-                return;
-            } else {
-                if let Some(text) = message.continue_value() {
-                    serial_number = text.clone();
-                    println!("received serial_number = {}", serial_number);
-                }
-            }
-        } else {
-            println!("client {who} abruptly disconnected");
-            return;
-        }
-    }
-
-    // receive single message from a client (we can either receive or send with socket).
-    // this will likely be the Pong for our Ping or a hello message from client.
-    // waiting for message from a client will block this task, but will not block other client's
-    // connections.
-    while let Some(msg) = socket.recv().await {
-        if let Ok(msg) = msg {
-            if process_message(msg, who, &serial_number).await.is_break() {
-                return;
-            }
-        } else {
-            println!("client {who} abruptly disconnected");
-            return;
-        }
-    }
-
-    // returning from the handler closes the websocket connection
-    println!("Websocket context {who} destroyed");
-}
-
-fn get_text(msg: Message, who: SocketAddr) -> ControlFlow<(), String> {
-    match msg {
-        Message::Text(text) => {
-            println!(">>> {who} sent str: {text:?}");
-            ControlFlow::Continue(text)
-        }
-        Message::Close(c) => {
-            if let Some(cf) = c {
-                println!(
-                    ">>> {} sent close with code {} and reason `{}`",
-                    who, cf.code, cf.reason
-                );
-            } else {
-                println!(">>> {who} somehow sent close message without CloseFrame");
-            }
-            return ControlFlow::Break(());
-        }
-        _ => {
-            println!("unexpected message");
-            return ControlFlow::Break(());
-        }
-    }
-}
-
-/// helper to print contents of messages to stdout. Has special treatment for Close.
-async fn process_message(
-    msg: Message,
-    who: SocketAddr,
-    serial_number: &str,
-) -> ControlFlow<(), ()> {
-    match msg {
-        Message::Text(t) => {
-            println!(">>> {who} sent str: {t:?}");
+/// Value of `--<name> <value>` or `--<name>=<value>` on the command line
+fn cli_flag(name: &str) -> Option<String> {
+    let flag = format!("--{}", name);
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == flag {
+            return args.next();
         }
-        Message::Binary(d) => {
-            println!(">>> {} sent {} bytes: {:?}", who, d.len(), d);
-            println!("going to save received image to file");
-            let _ = save_image(serial_number, d).await;
-            // to do
-            // save the image to disk
+        if let Some(value) = arg
+            .strip_prefix(&flag)
+            .and_then(|rest| rest.strip_prefix('='))
+        {
+            return Some(value.to_owned());
         }
-        Message::Close(c) => {
-            if let Some(cf) = c {
-                println!(
-                    ">>> {} sent close with code {} and reason `{}`",
-                    who, cf.code, cf.reason
-                );
-            } else {
-                println!(">>> {who} somehow sent close message without CloseFrame");
-            }
-            return ControlFlow::Break(());
-        }
-
-        Message::Pong(v) => {
-            println!(">>> {who} sent pong with {v:?}");
-        }
-        // You should never need to manually handle Message::Ping, as axum's websocket library
-        // will do so for you automagically by replying with Pong and copying the v according to
-        // spec. But if you need the contents of the pings you can see them here.
-        Message::Ping(v) => {
-            println!(">>> {who} sent ping with {v:?}");
-        }
-    }
-    ControlFlow::Continue(())
-}
-
-async fn save_image(serial_number: &str, data: Vec<u8>) -> Result<(), String> {
-    async {
-        let local_time = Local::now().format("%Y%m%d-%H%M%S");
-        let filename = format!("image-{}.jpg", local_time);
-
-        tokio::fs::create_dir_all(format!("{}/{}", UPLOADS_DIRECTORY, serial_number))
-            .await
-            .expect("failed to create `uploads/<serial_number>` directory");
-
-        let path = format!("{}/{}", serial_number, filename);
-
-        // Create the file. `File` implements `AsyncWrite`.
-        let path_buf = std::path::Path::new(UPLOADS_DIRECTORY).join(&path);
-        let mut file = BufWriter::new(File::create(path_buf).await?);
-
-        // Copy the body into the file.
-        tokio::io::copy(&mut data.as_slice(), &mut file).await?;
-
-        // Read the file just copied
-        let path_buf = std::path::Path::new(UPLOADS_DIRECTORY).join(&path);
-        let mut image_file = BufReader::new(File::open(path_buf).await?);
-
-        let filename_latest = "aaa-latest.jpg";
-        let path_latest = format!("{}/{}", serial_number, filename_latest);
-
-        // Create the file. `File` implements `AsyncWrite`.
-        let path_latest_buf = std::path::Path::new(UPLOADS_DIRECTORY).join(&path_latest);
-        let mut file_latest = BufWriter::new(File::create(path_latest_buf).await?);
-
-        // Copy the image file into the latest file.
-        tokio::io::copy(&mut image_file, &mut file_latest).await?;
-        tracing::debug!(
-            "image saved to {}: {} and {}",
-            serial_number,
-            filename,
-            filename_latest
-        );
-
-        Ok::<_, io::Error>(())
     }
-    .await
-    .map_err(|err| err.to_string())
+    None
 }
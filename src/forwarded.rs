@@ -0,0 +1,213 @@
+//! Which client a request came from, when it reached the servers through reverse proxies
+
+use axum::http::{header, HeaderMap, HeaderName};
+use std::{fmt, net::IpAddr, str::FromStr};
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+
+/// A range of addresses in CIDR notation, such as `10.0.0.0/8` or `2001:db8::/32`. A bare address
+/// is a range of its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpCidr {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpCidr {
+    /// Whether `ip` is in the range. IPv4 addresses mapped to IPv6, as dual-stack sockets report
+    /// them, are taken as the IPv4 address they carry.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let (network, ip, bits) = match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                (u32::from(network).into(), u32::from(ip).into(), 32)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => (u128::from(network), u128::from(ip), 128),
+            _ => return false,
+        };
+        // every address is in a range of prefix length 0, which shifts all the bits out
+        let shift = bits - u32::from(self.prefix_len);
+        network.checked_shr(shift).unwrap_or(0) == ip.checked_shr(shift).unwrap_or(0)
+    }
+}
+
+/// Parses `<address>/<prefix length>` and bare addresses
+impl FromStr for IpCidr {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "`{}` is not an address or a CIDR range such as 10.0.0.0/8",
+                value
+            )
+        };
+        let (network, prefix_len) = match value.trim().split_once('/') {
+            Some((network, prefix_len)) => (network, Some(prefix_len)),
+            None => (value.trim(), None),
+        };
+        let network: IpAddr = network.parse().map_err(|_| invalid())?;
+        let max_prefix_len = match network {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .parse()
+                .ok()
+                .filter(|prefix_len| *prefix_len <= max_prefix_len)
+                .ok_or_else(invalid)?,
+            None => max_prefix_len,
+        };
+        Ok(IpCidr {
+            network,
+            prefix_len,
+        })
+    }
+}
+
+impl fmt::Display for IpCidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+/// Address of the client that sent a request over a connection from `peer`. Forwarding headers
+/// are only believed when the peer is a trusted proxy: any peer with `trust_any_peer`, or one in
+/// `trusted_proxies`. Each proxy appends the address it got the request from, so the chain is
+/// walked back from its end past the trusted proxies in it; entries before that could be made up
+/// by the client. With `trust_any_peer` only the last entry is taken, as anything could be added
+/// before it.
+pub fn client_ip(
+    peer: IpAddr,
+    headers: &HeaderMap,
+    trust_any_peer: bool,
+    trusted_proxies: &[IpCidr],
+) -> IpAddr {
+    let is_trusted = |ip: IpAddr| trusted_proxies.iter().any(|proxy| proxy.contains(ip));
+    if !trust_any_peer && !is_trusted(peer) {
+        return peer;
+    }
+    let mut client = peer;
+    // an entry that is not an address, such as `unknown`, leaves the proxy before it as the client
+    for ip in forwarded_chain(headers)
+        .into_iter()
+        .rev()
+        .map_while(|ip| ip)
+    {
+        client = ip;
+        if trust_any_peer || !is_trusted(ip) {
+            break;
+        }
+    }
+    client
+}
+
+/// Addresses the forwarding headers say a request went through, the client first. `Forwarded` is
+/// read when a proxy sent one, and `X-Forwarded-For` otherwise. Entries that are not an address
+/// are `None`.
+fn forwarded_chain(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let entries = |name: HeaderName| {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+    };
+    if headers.contains_key(header::FORWARDED) {
+        return entries(header::FORWARDED)
+            .map(|element| {
+                element
+                    .split(';')
+                    .filter_map(|pair| pair.split_once('='))
+                    .find(|(name, _)| name.trim().eq_ignore_ascii_case("for"))
+                    .and_then(|(_, value)| forwarded_for_ip(value))
+            })
+            .collect();
+    }
+    entries(X_FORWARDED_FOR)
+        .map(|entry| entry.trim().parse().ok())
+        .collect()
+}
+
+/// Address of a `for=` parameter of `Forwarded`: `192.0.2.1`, `"192.0.2.1:80"`, `"[2001:db8::1]"`
+/// or `"[2001:db8::1]:80"`
+fn forwarded_for_ip(value: &str) -> Option<IpAddr> {
+    let value = value.trim().trim_matches('"');
+    if let Some(bracketed) = value.strip_prefix('[') {
+        return bracketed.split_once(']')?.0.parse().ok();
+    }
+    value
+        .parse()
+        .ok()
+        .or_else(|| value.split_once(':')?.0.parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn ranges_contain_the_addresses_under_their_prefix() {
+        let range: IpCidr = "10.1.0.0/16".parse().unwrap();
+        assert!(range.contains(ip("10.1.200.3")));
+        assert!(range.contains(ip("::ffff:10.1.0.1")));
+        assert!(!range.contains(ip("10.2.0.1")));
+        assert!(!range.contains(ip("::1")));
+        assert!("0.0.0.0/0"
+            .parse::<IpCidr>()
+            .unwrap()
+            .contains(ip("192.0.2.1")));
+        assert!("::/0"
+            .parse::<IpCidr>()
+            .unwrap()
+            .contains(ip("2001:db8::1")));
+        assert!("192.0.2.7"
+            .parse::<IpCidr>()
+            .unwrap()
+            .contains(ip("192.0.2.7")));
+        assert!("10.0.0.0/33".parse::<IpCidr>().is_err());
+        assert!("proxy".parse::<IpCidr>().is_err());
+    }
+
+    #[test]
+    fn forwarded_clients_are_only_believed_from_trusted_proxies() {
+        let proxies = ["10.0.0.0/8".parse().unwrap()];
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            X_FORWARDED_FOR,
+            HeaderValue::from_static("198.51.100.9, 203.0.113.5, 10.0.0.2"),
+        );
+        // the last entry that is not a proxy, the ones before it are up to the client
+        assert_eq!(
+            client_ip(ip("10.0.0.1"), &headers, false, &proxies),
+            ip("203.0.113.5")
+        );
+        assert_eq!(
+            client_ip(ip("192.0.2.1"), &headers, false, &proxies),
+            ip("192.0.2.1")
+        );
+        assert_eq!(
+            client_ip(ip("192.0.2.1"), &headers, true, &[]),
+            ip("10.0.0.2")
+        );
+
+        headers.insert(
+            header::FORWARDED,
+            HeaderValue::from_static(r#"for="[2001:db8::7]:4711";proto=https, for=10.0.0.2"#),
+        );
+        assert_eq!(
+            client_ip(ip("10.0.0.1"), &headers, false, &proxies),
+            ip("2001:db8::7")
+        );
+        headers.insert(header::FORWARDED, HeaderValue::from_static("for=unknown"));
+        assert_eq!(
+            client_ip(ip("10.0.0.1"), &headers, false, &proxies),
+            ip("10.0.0.1")
+        );
+    }
+}
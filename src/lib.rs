@@ -0,0 +1,8 @@
+//! Shared code of the upload servers in `src/bin`.
+
+pub mod forwarded;
+pub mod storage;
+pub mod store;
+pub mod version;
+pub mod webhook;
+pub mod ws;
@@ -0,0 +1,3658 @@
+//! Storing uploaded images on disk, the same way for every server.
+//!
+//! Images live in one directory per serial number under the uploads directory. Each upload is
+//! streamed into a hidden temporary file and renamed into place once complete, deduplicated by
+//! its SHA-256, and the latest copy of its serial directory (`aaa-latest.<ext>` unless named
+//! otherwise) is moved to it. A `<filename>.json` sidecar next to each image describes its upload.
+
+use chrono::{format::StrftimeItems, DateTime, Local, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use img_parts::{
+    jpeg::markers::{APP1, APP13, APP15, COM},
+    Bytes,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fmt,
+    future::Future,
+    io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    pin::Pin,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt, BufWriter, ReadBuf},
+    sync::{OwnedMutexGuard, Semaphore},
+    time::Instant,
+};
+
+/// per serial directory, maps the SHA-256 of each stored image to its filename
+const HASHES_FILENAME: &str = "hashes.json";
+
+/// PNG chunks that only carry metadata, dropped when `SaveOptions::strip_metadata` is set
+const PNG_METADATA_CHUNKS: [&[u8; 4]; 5] = [b"eXIf", b"tEXt", b"zTXt", b"iTXt", b"tIME"];
+
+/// enough bytes to recognize every format in `detect_image_format`
+pub(crate) const MAGIC_BYTES_LEN: usize = 12;
+
+/// bytes read from an upload at a time while it is copied to disk
+const COPY_CHUNK_LEN: usize = 64 * 1024;
+
+/// capacity of the buffer uploads are written to disk through, in KiB, unless configured. As large
+/// as a chunk of the copy, so that each one is handed to the OS in a single write; fast disks do
+/// better with a few hundred KiB.
+pub const DEFAULT_WRITE_BUFFER_KB: usize = 64;
+
+/// largest write buffer that may be configured, in KiB, as every upload being written holds one
+pub const MAX_WRITE_BUFFER_KB: usize = 64 * 1024;
+
+/// a progress line is logged each time an upload grows by this many bytes
+const PROGRESS_INTERVAL_BYTES: u64 = 1024 * 1024;
+
+/// temporary files older than this were left behind by a process that stopped mid-save, rather
+/// than being written by a running one
+pub const STALE_TEMP_AGE: Duration = Duration::from_secs(5 * 60);
+
+/// JPEG qualities tried in turn when recompressing to a size budget
+const RECOMPRESS_QUALITIES: [u8; 8] = [90, 80, 70, 60, 50, 40, 30, 20];
+
+/// quality a rotated JPEG is encoded at again
+const ROTATED_JPEG_QUALITY: u8 = 90;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageFormat {
+    Jpeg,
+    Png,
+    Gif,
+    Webp,
+}
+
+impl ImageFormat {
+    pub const ALL: [ImageFormat; 4] = [
+        ImageFormat::Jpeg,
+        ImageFormat::Png,
+        ImageFormat::Gif,
+        ImageFormat::Webp,
+    ];
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ImageFormat::Jpeg => "jpg",
+            ImageFormat::Png => "png",
+            ImageFormat::Gif => "gif",
+            ImageFormat::Webp => "webp",
+        }
+    }
+
+    /// How the format is called in messages
+    pub fn name(self) -> &'static str {
+        match self {
+            ImageFormat::Jpeg => "JPEG",
+            ImageFormat::Png => "PNG",
+            ImageFormat::Gif => "GIF",
+            ImageFormat::Webp => "WebP",
+        }
+    }
+
+    pub fn mime_type(self) -> &'static str {
+        match self {
+            ImageFormat::Jpeg => "image/jpeg",
+            ImageFormat::Png => "image/png",
+            ImageFormat::Gif => "image/gif",
+            ImageFormat::Webp => "image/webp",
+        }
+    }
+}
+
+/// Parses the lowercase names the format serializes to, and `jpg`
+impl FromStr for ImageFormat {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "jpeg" | "jpg" => Ok(ImageFormat::Jpeg),
+            "png" => Ok(ImageFormat::Png),
+            "gif" => Ok(ImageFormat::Gif),
+            "webp" => Ok(ImageFormat::Webp),
+            _ => Err(format!(
+                "`{}` is not an image format, expected jpeg, png, gif or webp",
+                name
+            )),
+        }
+    }
+}
+
+/// How uploads are stored
+#[derive(Debug, Clone)]
+pub struct SaveOptions {
+    /// uploads larger than this are rejected
+    pub max_bytes: u64,
+    /// images with more pixels than this are rejected from their header alone, before anything
+    /// decodes them, when set
+    pub max_pixels: Option<u64>,
+    /// metadata such as EXIF is removed from JPEG and PNG uploads before they are stored
+    pub strip_metadata: bool,
+    /// JPEG and PNG uploads are stored re-encoded as WebP at this quality, when set
+    pub webp_quality: Option<f32>,
+    /// JPEG uploads larger than this are recompressed at lower qualities to fit, when set. Ignored
+    /// when uploads are re-encoded as WebP.
+    pub jpeg_max_bytes: Option<u64>,
+    /// unrecognized uploads are stored as this format, when set, instead of being rejected
+    pub fallback_format: Option<ImageFormat>,
+    /// uploads in other formats are rejected, when set
+    pub allowed_formats: Option<Vec<ImageFormat>>,
+    /// uploads written to disk at the same time, the others wait for their turn
+    pub max_concurrent_writes: usize,
+    /// uploads that would have to wait for their turn fail with `SaveError::Busy` instead
+    pub reject_when_busy: bool,
+    /// capacity of the buffer each upload is written to disk through, in bytes
+    pub write_buffer_bytes: usize,
+    /// the whole body of an upload has to arrive within this long, or it fails with
+    /// `SaveError::TimedOut` and the part received so far is removed. Unlimited when not set.
+    pub receive_timeout: Option<Duration>,
+    /// images, sidecars and their directory are synced to the disk before a save returns, so that
+    /// they survive a power cut. Otherwise they are only handed to the OS.
+    pub fsync: bool,
+    /// the stored images of a serial number may take up this many bytes in total, when set
+    pub serial_quota_bytes: Option<u64>,
+    /// an upload over the quota evicts the oldest images of its serial number to make room, instead
+    /// of failing with `SaveError::QuotaExceeded`
+    pub evict_oldest: bool,
+    /// an upload whose perceptual hash is at most this many bits away from that of the image last
+    /// stored for its serial number is dropped as a duplicate of it, when set. 0 only drops frames
+    /// that look the same, a few bits more the ones a little sensor noise sets apart.
+    pub near_duplicate_distance: Option<u32>,
+    /// what stored images are named after
+    pub naming: Naming,
+    /// subdirectories of the serial directory stored images go in
+    pub layout: Layout,
+    /// how timestamp-named images are named
+    pub filename_time: FilenameTime,
+    /// name of the copy of the newest image of each serial number
+    pub latest_name: LatestName,
+    /// mode the files written are given on Unix, such as `0o640`, instead of what the umask leaves
+    /// them, when set. Ignored elsewhere.
+    pub file_mode: Option<u32>,
+    /// mode the serial directories and their date subdirectories are given on Unix when they are
+    /// made, when set. Ignored elsewhere.
+    pub dir_mode: Option<u32>,
+}
+
+/// What stored images are named after
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Naming {
+    /// the time the upload was received, or the name the client asked for when it is safe
+    #[default]
+    Timestamp,
+    /// `<sha256>.<ext>`, the hash of the uploaded bytes, so the same image always gets the same
+    /// name and is only stored once. Names asked for by clients are ignored.
+    ContentHash,
+}
+
+impl Naming {
+    pub fn name(self) -> &'static str {
+        match self {
+            Naming::Timestamp => "timestamp",
+            Naming::ContentHash => "content-hash",
+        }
+    }
+}
+
+/// Parses the names returned by `Naming::name`
+impl FromStr for Naming {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "timestamp" => Ok(Naming::Timestamp),
+            "content-hash" => Ok(Naming::ContentHash),
+            _ => Err(format!(
+                "`{}` is not a naming, expected timestamp or content-hash",
+                name
+            )),
+        }
+    }
+}
+
+/// How the stored images of a serial number are spread over subdirectories of its directory, so
+/// that a single one does not get slow to list
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Layout {
+    /// all in the serial directory itself
+    #[default]
+    Flat,
+    /// in `YYYY/MM/DD` subdirectories, after the day each was received
+    Daily,
+    /// in `YYYY/MM` subdirectories, after the month each was received
+    Monthly,
+}
+
+impl Layout {
+    pub fn name(self) -> &'static str {
+        match self {
+            Layout::Flat => "none",
+            Layout::Daily => "daily",
+            Layout::Monthly => "monthly",
+        }
+    }
+
+    /// Subdirectory an image received at `at` goes in, with the date in UTC or in the local time
+    /// zone as its filename has the time
+    fn bucket(self, at: DateTime<Local>, utc: bool) -> Option<String> {
+        let format = match self {
+            Layout::Flat => return None,
+            Layout::Daily => "%Y/%m/%d",
+            Layout::Monthly => "%Y/%m",
+        };
+        Some(match utc {
+            true => at.with_timezone(&Utc).format(format).to_string(),
+            false => at.format(format).to_string(),
+        })
+    }
+}
+
+/// Parses the names returned by `Layout::name`
+impl FromStr for Layout {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "none" => Ok(Layout::Flat),
+            "daily" => Ok(Layout::Daily),
+            "monthly" => Ok(Layout::Monthly),
+            _ => Err(format!(
+                "`{}` is not a layout, expected none, daily or monthly",
+                name
+            )),
+        }
+    }
+}
+
+/// Clockwise turn applied to a stored image by `Storage::rotate_image`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rotation {
+    Rotate90,
+    Rotate180,
+    Rotate270,
+}
+
+impl Rotation {
+    /// The turn of `degrees`, which must be 90, 180 or 270
+    pub fn from_degrees(degrees: u32) -> Option<Self> {
+        match degrees {
+            90 => Some(Rotation::Rotate90),
+            180 => Some(Rotation::Rotate180),
+            270 => Some(Rotation::Rotate270),
+            _ => None,
+        }
+    }
+}
+
+/// Name of the copy of the newest image in each serial directory, `<stem>.<ext>` with the
+/// extension of the format of the image
+#[derive(Debug, Clone)]
+pub struct LatestName {
+    stem: String,
+}
+
+impl LatestName {
+    /// sorts before the stored images in a directory listing
+    pub const DEFAULT_STEM: &'static str = "aaa-latest";
+
+    /// The configured `name`, with or without an image extension. It follows the serial number
+    /// rules and can not take the names of the thumbnails, the timestamp-named images or the PUT
+    /// uploads.
+    pub fn new(name: &str) -> Result<Self, String> {
+        let stem = match name.rsplit_once('.') {
+            Some((stem, extension))
+                if ImageFormat::ALL
+                    .iter()
+                    .any(|format| format.extension() == extension) =>
+            {
+                stem
+            }
+            _ => name,
+        };
+        if !serial_is_valid(stem)
+            || ["thumb-", "image-", "upload-"]
+                .iter()
+                .any(|prefix| stem.starts_with(prefix))
+        {
+            return Err(format!(
+                "`{}` can not name the latest copy, expected ASCII letters, digits, dashes and \
+                 underscores not starting with `thumb-`, `image-` or `upload-`",
+                name
+            ));
+        }
+        Ok(LatestName {
+            stem: stem.to_owned(),
+        })
+    }
+
+    pub fn stem(&self) -> &str {
+        &self.stem
+    }
+
+    /// Name of the latest copy when the newest image is in `format`
+    pub fn filename(&self, format: ImageFormat) -> String {
+        format!("{}.{}", self.stem, format.extension())
+    }
+
+    /// Whether `filename` is one of the latest copies rather than an upload
+    pub fn matches(&self, filename: &str) -> bool {
+        ImageFormat::ALL
+            .iter()
+            .any(|format| filename == self.filename(*format))
+    }
+}
+
+impl Default for LatestName {
+    fn default() -> Self {
+        LatestName {
+            stem: LatestName::DEFAULT_STEM.to_owned(),
+        }
+    }
+}
+
+/// How the time an upload was received is written into the `image-<timestamp>.<ext>` name of an
+/// image stored without a requested one
+#[derive(Debug, Clone)]
+pub struct FilenameTime {
+    format: String,
+    utc: bool,
+}
+
+impl FilenameTime {
+    pub const DEFAULT_FORMAT: &'static str = "%Y%m%d-%H%M%S";
+
+    /// Write times with the strftime `format`, in UTC or in the local time zone. The format must
+    /// write only characters that are safe in a filename, and enough for the time to be read back.
+    pub fn new(format: &str, utc: bool) -> Result<Self, String> {
+        use std::fmt::Write;
+
+        let sample = NaiveDate::from_ymd_opt(2001, 2, 3)
+            .and_then(|date| date.and_hms_opt(4, 5, 6))
+            .expect("the sample time is valid");
+        let mut written = String::new();
+        if StrftimeItems::new(format).any(|item| item == chrono::format::Item::Error)
+            || write!(written, "{}", sample.format(format)).is_err()
+        {
+            return Err(format!("`{}` is not a valid time format", format));
+        }
+        if !written
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(format!(
+                "`{}` writes `{}`, only ASCII letters, digits, dashes and underscores are allowed",
+                format, written
+            ));
+        }
+        if NaiveDateTime::parse_from_str(&written, format).is_err() {
+            return Err(format!(
+                "`{}` needs to write at least the date, hours and minutes",
+                format
+            ));
+        }
+        Ok(FilenameTime {
+            format: format.to_owned(),
+            utc,
+        })
+    }
+
+    pub fn format(&self) -> &str {
+        &self.format
+    }
+
+    /// Whether times are written in UTC rather than in the local time zone
+    pub fn utc(&self) -> bool {
+        self.utc
+    }
+
+    /// Stem of the name of an image received at `at`
+    pub(crate) fn stem(&self, at: DateTime<Local>) -> String {
+        match self.utc {
+            true => format!("image-{}", at.with_timezone(&Utc).format(&self.format)),
+            false => format!("image-{}", at.format(&self.format)),
+        }
+    }
+
+    /// Time written as the filenames write it
+    pub fn parse(&self, text: &str) -> Option<DateTime<Utc>> {
+        let time = NaiveDateTime::parse_from_str(text, &self.format).ok()?;
+        match self.utc {
+            true => Some(time.and_utc()),
+            false => Local
+                .from_local_datetime(&time)
+                .earliest()
+                .map(|time| time.with_timezone(&Utc)),
+        }
+    }
+
+    /// Time embedded in an `image-<timestamp>.<ext>` name, or in the `image-<timestamp>-<n>.<ext>`
+    /// one of an image received within the same second as another
+    pub fn of_filename(&self, filename: &str) -> Option<DateTime<Utc>> {
+        let filename = filename.rsplit('/').next().unwrap_or(filename);
+        let (stem, _) = filename.strip_prefix("image-")?.rsplit_once('.')?;
+        self.parse(stem).or_else(|| {
+            let (stem, suffix) = stem.rsplit_once('-')?;
+            suffix.parse::<u32>().ok()?;
+            self.parse(stem)
+        })
+    }
+}
+
+impl Default for FilenameTime {
+    fn default() -> Self {
+        FilenameTime {
+            format: FilenameTime::DEFAULT_FORMAT.to_owned(),
+            utc: true,
+        }
+    }
+}
+
+/// Settings of `SaveOptions` that a serial number can have values of its own for, as read from an
+/// overrides file by `parse_overrides`. Those left out keep the value of `SaveOptions`.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SerialOverrides {
+    pub allowed_formats: Option<Vec<ImageFormat>>,
+    pub webp_quality: Option<f32>,
+    pub serial_quota_bytes: Option<u64>,
+    pub evict_oldest: Option<bool>,
+    pub near_duplicate_distance: Option<u32>,
+}
+
+/// A file or directory mode written in octal, such as `0640` or `750`
+pub fn parse_mode(value: &str) -> Result<u32, String> {
+    let digits = value.trim();
+    let digits = digits.strip_prefix("0o").unwrap_or(digits);
+    u32::from_str_radix(digits, 8)
+        .ok()
+        .filter(|mode| !digits.is_empty() && *mode <= 0o7777)
+        .ok_or_else(|| format!("`{}` is not a mode in octal such as 0640", value))
+}
+
+/// Overrides keyed by serial number, from JSON such as
+/// `{"cam-01": {"allowed_formats": ["jpeg"], "serial_quota_bytes": 50000000}}`
+pub fn parse_overrides(json: &str) -> Result<HashMap<String, SerialOverrides>, String> {
+    let overrides: HashMap<String, SerialOverrides> =
+        serde_json::from_str(json).map_err(|err| err.to_string())?;
+    for (serial_number, overrides) in &overrides {
+        if !serial_is_valid(serial_number) {
+            return Err(format!("`{}` is not a valid serial number", serial_number));
+        }
+        if overrides
+            .webp_quality
+            .is_some_and(|quality| !(0.0..=100.0).contains(&quality))
+        {
+            return Err(format!(
+                "`{}`: `webp_quality` must be a number from 0 to 100",
+                serial_number
+            ));
+        }
+        if overrides
+            .near_duplicate_distance
+            .is_some_and(|distance| distance > 64)
+        {
+            return Err(format!(
+                "`{}`: `near_duplicate_distance` must be a number of bits up to 64",
+                serial_number
+            ));
+        }
+    }
+    Ok(overrides)
+}
+
+/// The settings uploads of one serial number are stored with, its overrides applied over
+/// `SaveOptions`
+struct SerialSettings {
+    allowed_formats: Option<Vec<ImageFormat>>,
+    webp_quality: Option<f32>,
+    serial_quota_bytes: Option<u64>,
+    evict_oldest: bool,
+    near_duplicate_distance: Option<u32>,
+}
+
+/// Where an upload goes, worked out before any of its body is written
+struct Destination {
+    serial_dir: PathBuf,
+    /// date subdirectory of a bucketed layout, relative to `serial_dir`
+    bucket: Option<String>,
+    /// `upload-<upload_id>`, for an upload stored under its ID
+    upload_stem: Option<String>,
+    /// path of the image stored under the same upload ID before, relative to `serial_dir`
+    replacing: Option<String>,
+    /// stem a unique name is made from, for a name of its own picked before the body arrives
+    stem: Option<String>,
+    /// the upload is checked against the stored images, unless it is stored under its ID or kept
+    /// only as the latest copy
+    deduplicate: bool,
+}
+
+impl Destination {
+    /// Directory the image is written in
+    fn image_dir(&self) -> PathBuf {
+        match &self.bucket {
+            Some(bucket) => self.serial_dir.join(bucket),
+            None => self.serial_dir.clone(),
+        }
+    }
+
+    /// Path of `filename` in the date subdirectory, relative to the serial directory
+    fn in_bucket(&self, filename: String) -> String {
+        match &self.bucket {
+            Some(bucket) => format!("{}/{}", bucket, filename),
+            None => filename,
+        }
+    }
+}
+
+/// An upload received into a temporary file, before it is moved into place
+struct ReceivedUpload {
+    /// path the image is to be stored under, relative to the serial directory
+    filename: String,
+    temp_path: PathBuf,
+    /// size of the upload as received
+    bytes: u64,
+    /// hex SHA-256 of the upload as received
+    sha256: String,
+    /// perceptual hash of the upload, when near duplicates are dropped
+    frame_hash: Option<u64>,
+}
+
+/// How an upload compares with the images stored before it
+enum Comparison {
+    /// it has the bytes of, or looks like, this stored image
+    Duplicate(String),
+    /// it is a new image, with its perceptual hash when near duplicates are dropped
+    New(Option<u64>),
+}
+
+/// An upload once it has been stored
+#[derive(Debug)]
+pub struct SavedImage {
+    /// path of the stored image relative to its serial directory, `<filename>` or
+    /// `<date>/<filename>` with a bucketed layout
+    pub filename: String,
+    /// where the image was stored, its key for an object store
+    pub path: PathBuf,
+    /// size of the upload as received
+    pub bytes: u64,
+    pub format: ImageFormat,
+    /// the bytes matched an image already stored as `filename`, so nothing new was written
+    pub duplicate: bool,
+    /// an image stored under the same upload ID before was replaced
+    pub replaced: bool,
+    pub received_at: DateTime<Local>,
+}
+
+/// Where an upload came from, as far as the server can tell, and when
+#[derive(Debug, Clone, Default)]
+pub struct UploadSource {
+    pub client_ip: Option<IpAddr>,
+    pub user_agent: Option<String>,
+    /// when the server received the upload, before reading any of its body: the request for HTTP,
+    /// the request creating it for a resumable upload, and the first frame of the image for
+    /// websockets. The filename, date subdirectory, sidecar
+    /// and manifest of the stored image all go by it, however long the body then takes. The time
+    /// the save starts when not set.
+    pub received_at: Option<DateTime<Local>>,
+}
+
+/// Contents of the `<filename>.json` sidecar of a stored image, so that indexers need not read the
+/// image itself. `bytes` and `sha256` are of the upload as received.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Sidecar {
+    /// RFC 3339 time the upload started arriving
+    pub received_at: String,
+    pub client_ip: Option<IpAddr>,
+    pub user_agent: Option<String>,
+    pub bytes: u64,
+    /// size of the stored image, smaller than `bytes` once metadata is stripped or the image is
+    /// recompressed
+    #[serde(default)]
+    pub stored_bytes: Option<u64>,
+    pub sha256: String,
+    /// format of the stored image
+    pub format: ImageFormat,
+}
+
+/// What storing an upload would do, as found out by `Storage::validate_image`
+#[derive(Debug)]
+pub struct ValidatedImage {
+    /// name the image would be stored under, or that of the identical image already stored
+    pub filename: String,
+    pub bytes: u64,
+    /// format of the upload, before any re-encoding
+    pub format: ImageFormat,
+    pub duplicate: bool,
+}
+
+/// What `Storage::repair_latest` did about the latest copy of a serial number
+#[derive(Debug, PartialEq, Eq)]
+pub enum LatestRepair {
+    /// the latest copy already was the newest image
+    Unchanged,
+    /// the latest copy now points at this image, the newest one
+    Repointed(String),
+    /// the latest copies pointed at images that are gone, and there is none left to point at
+    Removed,
+}
+
+#[derive(Debug)]
+pub enum SaveError {
+    /// the serial number can not be used as a directory name
+    InvalidSerial,
+    /// the upload ID can not be used in a filename
+    InvalidUploadId,
+    /// the upload has no bytes at all
+    Empty,
+    /// the upload is not a supported image and there is no fallback format
+    UnsupportedFormat,
+    /// the upload is an image in a format left out of `SaveOptions::allowed_formats`
+    FormatNotAllowed(ImageFormat),
+    /// the upload is larger than the limit, in bytes
+    TooLarge(u64),
+    /// the image has more pixels than the limit
+    TooManyPixels {
+        width: u32,
+        height: u32,
+        max_pixels: u64,
+    },
+    /// as many uploads as allowed are being written already
+    Busy,
+    /// storing the upload would take its serial number over the quota, in bytes
+    QuotaExceeded(u64),
+    /// the body did not arrive within `SaveOptions::receive_timeout`
+    TimedOut(Duration),
+    Io(io::Error),
+}
+
+impl fmt::Display for SaveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SaveError::InvalidSerial => f.write_str("Invalid serial number"),
+            SaveError::InvalidUploadId => f.write_str("Invalid upload ID"),
+            SaveError::Empty => f.write_str("upload is empty"),
+            SaveError::UnsupportedFormat => f.write_str(
+                "request body is not a supported image (expected JPEG, PNG, GIF or WebP)",
+            ),
+            SaveError::FormatNotAllowed(format) => {
+                write!(f, "{} images are not accepted", format.name())
+            }
+            SaveError::TooLarge(limit) => {
+                write!(f, "upload exceeds the limit of {} bytes", limit)
+            }
+            SaveError::TooManyPixels {
+                width,
+                height,
+                max_pixels,
+            } => write!(
+                f,
+                "image of {}x{} pixels exceeds the limit of {} pixels",
+                width, height, max_pixels
+            ),
+            SaveError::Busy => f.write_str("too many uploads in progress, try again later"),
+            SaveError::QuotaExceeded(quota) => write!(
+                f,
+                "the images of this serial number would exceed the quota of {} bytes",
+                quota
+            ),
+            SaveError::TimedOut(timeout) => write!(
+                f,
+                "upload was not received within {} seconds",
+                timeout.as_secs_f64()
+            ),
+            SaveError::Io(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for SaveError {}
+
+impl From<io::Error> for SaveError {
+    fn from(err: io::Error) -> Self {
+        SaveError::Io(err)
+    }
+}
+
+/// The uploads directory, and the bookkeeping needed to store images in it concurrently
+pub struct Storage {
+    uploads_dir: PathBuf,
+    options: SaveOptions,
+    latest_locks: LatestLocks,
+    hash_locks: HashLocks,
+    /// one permit per upload being written to disk
+    writes: Semaphore,
+    /// per serial number, the bytes its stored images take up, once counted for the quota
+    usage: tokio::sync::Mutex<HashMap<String, u64>>,
+    /// per serial number, the perceptual hash and filename of the image last stored, when near
+    /// duplicates are dropped
+    previous_frames: Mutex<HashMap<String, (u64, String)>>,
+    /// settings of their own of some serial numbers, replaced as a whole when reloaded
+    overrides: RwLock<HashMap<String, SerialOverrides>>,
+}
+
+/// Per serial number, when the image the latest copy points at was received. Moving the latest copy
+/// happens with its lock held, so concurrent uploads can not leave an older image as the latest.
+#[derive(Default)]
+struct LatestLocks(Mutex<HashMap<String, Arc<LatestLock>>>);
+
+type LatestLock = tokio::sync::Mutex<Option<DateTime<Local>>>;
+
+impl LatestLocks {
+    fn get(&self, serial_number: &str) -> Arc<LatestLock> {
+        self.0
+            .lock()
+            .unwrap()
+            .entry(serial_number.to_owned())
+            .or_default()
+            .clone()
+    }
+}
+
+/// Per serial number, held from reading its hashes file to writing it back, so that concurrent
+/// uploads neither lose each other's entries nor both store the same bytes
+#[derive(Default)]
+struct HashLocks(Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>);
+
+impl HashLocks {
+    fn get(&self, serial_number: &str) -> Arc<tokio::sync::Mutex<()>> {
+        self.0
+            .lock()
+            .unwrap()
+            .entry(serial_number.to_owned())
+            .or_default()
+            .clone()
+    }
+}
+
+/// Keeps uploads from moving the latest copy of a serial number until it is dropped
+pub struct LatestGuard {
+    _guard: OwnedMutexGuard<Option<DateTime<Local>>>,
+}
+
+impl Storage {
+    pub fn new(uploads_dir: PathBuf, options: SaveOptions) -> Self {
+        Storage {
+            uploads_dir,
+            latest_locks: LatestLocks::default(),
+            hash_locks: HashLocks::default(),
+            writes: Semaphore::new(options.max_concurrent_writes),
+            usage: tokio::sync::Mutex::default(),
+            previous_frames: Mutex::default(),
+            overrides: RwLock::default(),
+            options,
+        }
+    }
+
+    pub fn uploads_dir(&self) -> &Path {
+        &self.uploads_dir
+    }
+
+    /// Replace the settings of their own of serial numbers, for the uploads that start from now on.
+    /// The serial numbers left out go back to `SaveOptions`.
+    pub fn set_overrides(&self, overrides: HashMap<String, SerialOverrides>) {
+        *self.overrides.write().unwrap() = overrides;
+    }
+
+    /// Settings uploads of `serial_number` are stored with
+    fn settings(&self, serial_number: &str) -> SerialSettings {
+        let overrides = self.overrides.read().unwrap();
+        let overrides = overrides.get(serial_number).cloned().unwrap_or_default();
+        SerialSettings {
+            allowed_formats: overrides
+                .allowed_formats
+                .or_else(|| self.options.allowed_formats.clone()),
+            webp_quality: overrides.webp_quality.or(self.options.webp_quality),
+            serial_quota_bytes: overrides
+                .serial_quota_bytes
+                .or(self.options.serial_quota_bytes),
+            evict_oldest: overrides.evict_oldest.unwrap_or(self.options.evict_oldest),
+            near_duplicate_distance: overrides
+                .near_duplicate_distance
+                .or(self.options.near_duplicate_distance),
+        }
+    }
+
+    /// Largest upload that is stored, in bytes
+    pub fn max_bytes(&self) -> u64 {
+        self.options.max_bytes
+    }
+
+    /// How timestamp-named images are named
+    pub fn filename_time(&self) -> &FilenameTime {
+        &self.options.filename_time
+    }
+
+    /// Name of the copy of the newest image of each serial number
+    pub fn latest_name(&self) -> &LatestName {
+        &self.options.latest_name
+    }
+
+    /// Mode the files written are given, when set
+    pub fn file_mode(&self) -> Option<u32> {
+        self.options.file_mode
+    }
+
+    /// Mode the directories made are given, when set
+    pub fn dir_mode(&self) -> Option<u32> {
+        self.options.dir_mode
+    }
+
+    /// Directory the images of `serial_number` are stored in, which must be valid
+    pub fn serial_dir(&self, serial_number: &str) -> PathBuf {
+        self.uploads_dir.join(serial_number)
+    }
+
+    /// Store the image read from `body` for `serial_number` and make it the latest one. It is named
+    /// after `requested_filename` (with the extension of its detected format) when the client gave
+    /// a safe one, and after the time it was received otherwise, with a `-<n>` suffix when that name
+    /// is taken. With `Naming::ContentHash` it is named after its hash instead. A duplicate keeps
+    /// the sidecar of the copy stored first.
+    pub async fn save_image<R>(
+        &self,
+        serial_number: &str,
+        requested_filename: Option<&str>,
+        source: &UploadSource,
+        body: R,
+    ) -> Result<SavedImage, SaveError>
+    where
+        R: AsyncRead + Unpin,
+    {
+        self.save(serial_number, requested_filename, None, source, body, true)
+            .await
+    }
+
+    /// Store the image read from `body` for `serial_number` under a name derived from `upload_id`,
+    /// `upload-<upload_id>` with the extension of its format, and make it the latest one. The image
+    /// stored under the same ID before is replaced where it is, so that retrying an upload never
+    /// adds a copy. Upload IDs follow the serial number rules. The image is stored even when its
+    /// bytes match another one, as the client asked for it under its own name.
+    pub async fn put_image<R>(
+        &self,
+        serial_number: &str,
+        upload_id: &str,
+        source: &UploadSource,
+        body: R,
+    ) -> Result<SavedImage, SaveError>
+    where
+        R: AsyncRead + Unpin,
+    {
+        self.save(serial_number, None, Some(upload_id), source, body, true)
+            .await
+    }
+
+    /// Store the image read from `body` as the latest copy of `serial_number` itself, keeping no
+    /// timestamped image of it, for devices that only ever need their current frame. It goes
+    /// through the same checks and rewrites as `save_image`, but has no sidecar, is not counted
+    /// against the quota and is never a duplicate, as the next upload replaces it anyway.
+    pub async fn save_latest_only<R>(
+        &self,
+        serial_number: &str,
+        source: &UploadSource,
+        body: R,
+    ) -> Result<SavedImage, SaveError>
+    where
+        R: AsyncRead + Unpin,
+    {
+        self.save(serial_number, None, None, source, body, false)
+            .await
+    }
+
+    /// Store the image read from `body` for `serial_number`: receive it into a temporary file, drop
+    /// it when it duplicates a stored image, rewrite it as configured, then move it into place and
+    /// record it. An image kept only as the latest copy skips the duplicate checks and the records.
+    async fn save<R>(
+        &self,
+        serial_number: &str,
+        requested_filename: Option<&str>,
+        upload_id: Option<&str>,
+        source: &UploadSource,
+        body: R,
+        keep_history: bool,
+    ) -> Result<SavedImage, SaveError>
+    where
+        R: AsyncRead + Unpin,
+    {
+        if !serial_is_valid(serial_number) {
+            return Err(SaveError::InvalidSerial);
+        }
+        if upload_id.is_some_and(|upload_id| !serial_is_valid(upload_id)) {
+            return Err(SaveError::InvalidUploadId);
+        }
+        let received_at = source.received_at.unwrap_or_else(Local::now);
+        let deadline = self.receive_deadline();
+        let settings = self.settings(serial_number);
+
+        // Sniff the first bytes before creating any file, so that a rejected body leaves nothing behind.
+        let mut body = body;
+        let mut header = [0; MAGIC_BYTES_LEN];
+        let header_len = self
+            .before_deadline(deadline, read_header(&mut body, &mut header))
+            .await??;
+        let header = &header[..header_len];
+        let format = self.accepted_format(&settings, header)?;
+
+        // Put the sniffed bytes back in front of the rest of the body.
+        // The hash is computed while the body streams to disk so it never has to be read back.
+        let body = HashingReader::new(header.chain(body));
+        let destination = self
+            .destination(
+                serial_number,
+                requested_filename,
+                upload_id,
+                received_at,
+                keep_history,
+            )
+            .await?;
+        let mut upload = self
+            .write_temp(serial_number, &destination, format, body, deadline)
+            .await?;
+        // an image kept only as the latest copy is never in the hashes file
+        let hashes_lock = self.hash_locks.get(serial_number);
+        let _hashes_guard = match keep_history {
+            true => Some(hashes_lock.lock().await),
+            false => None,
+        };
+
+        // An image already stored in its place is kept, and only the latest copy is refreshed.
+        if destination.deduplicate {
+            match self
+                .dedupe(serial_number, &settings, &destination, &upload)
+                .await?
+            {
+                Comparison::Duplicate(existing) => {
+                    let existing_path = destination.serial_dir.join(&existing);
+                    tokio::fs::remove_file(&upload.temp_path).await?;
+                    // the stored copy may have been re-encoded to another format
+                    let format = detect_file_format(&existing_path).await?.unwrap_or(format);
+                    self.set_latest(serial_number, &existing, format, received_at)
+                        .await?;
+                    return Ok(SavedImage {
+                        filename: existing,
+                        path: existing_path,
+                        bytes: upload.bytes,
+                        format,
+                        duplicate: true,
+                        replaced: false,
+                        received_at,
+                    });
+                }
+                Comparison::New(frame_hash) => upload.frame_hash = frame_hash,
+            }
+        }
+        self.rewrite_temp(&settings, &upload, format).await?;
+
+        if !keep_history {
+            let (filename, path, format) = self
+                .replace_latest(
+                    serial_number,
+                    &destination.serial_dir,
+                    upload.temp_path,
+                    format,
+                    settings.webp_quality,
+                    received_at,
+                )
+                .await?;
+            return Ok(SavedImage {
+                filename,
+                path,
+                bytes: upload.bytes,
+                format,
+                duplicate: false,
+                replaced: false,
+                received_at,
+            });
+        }
+
+        let (filename, path, format) = self
+            .finalize(serial_number, &settings, &destination, &upload, format)
+            .await?;
+        let saved = SavedImage {
+            filename,
+            path,
+            bytes: upload.bytes,
+            format,
+            duplicate: false,
+            replaced: destination.replacing.is_some(),
+            received_at,
+        };
+        self.record(serial_number, &destination, source, &upload, &saved)
+            .await?;
+        Ok(saved)
+    }
+
+    /// Work out where an upload of `serial_number` received at `received_at` goes, and make sure
+    /// the directories it goes in exist
+    async fn destination(
+        &self,
+        serial_number: &str,
+        requested_filename: Option<&str>,
+        upload_id: Option<&str>,
+        received_at: DateTime<Local>,
+        keep_history: bool,
+    ) -> io::Result<Destination> {
+        // With a bucketed layout the image goes in the subdirectory of the day or month it was
+        // received, made when the first image of it arrives. The filename is relative to the
+        // serial directory from here on.
+        // An image stored under the upload ID before is replaced in the subdirectory it is in, and
+        // none of the duplicate checks apply to it.
+        let serial_dir = self.serial_dir(serial_number);
+        let upload_stem = upload_id.map(|upload_id| format!("upload-{}", upload_id));
+        let replacing = match &upload_stem {
+            Some(upload_stem) => self.stored_upload(&serial_dir, upload_stem).await?,
+            None => None,
+        };
+        let deduplicate = keep_history && upload_id.is_none();
+        let bucket = match (&replacing, keep_history) {
+            (Some(existing), _) => existing
+                .rsplit_once('/')
+                .map(|(bucket, _)| bucket.to_owned()),
+            (None, true) => self
+                .options
+                .layout
+                .bucket(received_at, self.options.filename_time.utc()),
+            (None, false) => None,
+        };
+
+        // A content-hash name is only known once the whole body is hashed, and an image kept only
+        // as the latest copy gets no name of its own, so there is no stem for them.
+        let stem = match (deduplicate, self.options.naming) {
+            (true, Naming::Timestamp) => Some(
+                requested_filename
+                    .and_then(|filename| {
+                        requested_stem(
+                            filename,
+                            &self.options.latest_name,
+                            &self.options.filename_time,
+                        )
+                    })
+                    .unwrap_or_else(|| self.options.filename_time.stem(received_at)),
+            ),
+            _ => None,
+        };
+
+        let destination = Destination {
+            serial_dir,
+            bucket,
+            upload_stem,
+            replacing,
+            stem,
+            deduplicate,
+        };
+        self.ensure_destination(serial_number, &destination).await?;
+        Ok(destination)
+    }
+
+    /// Make sure the serial directory of `destination` exists, and its date subdirectory if any
+    async fn ensure_destination(
+        &self,
+        serial_number: &str,
+        destination: &Destination,
+    ) -> io::Result<()> {
+        self.ensure_serial_dir(serial_number, &destination.serial_dir)
+            .await?;
+        if let Some(bucket) = &destination.bucket {
+            self.ensure_bucket(&destination.serial_dir, bucket).await?;
+        }
+        Ok(())
+    }
+
+    /// Receive `body` into a temporary file next to where the image goes, reserving its name, and
+    /// turn it away when it goes past the size limit, the deadline or the pixel limit
+    async fn write_temp<B>(
+        &self,
+        serial_number: &str,
+        destination: &Destination,
+        format: ImageFormat,
+        mut body: HashingReader<B>,
+        deadline: Option<Instant>,
+    ) -> Result<ReceivedUpload, SaveError>
+    where
+        B: AsyncRead + Unpin,
+    {
+        let max_bytes = self.options.max_bytes;
+        let serial_dir = &destination.serial_dir;
+
+        // Writing is what thrashes the disk, so the permit covers creating the file up to flushing it.
+        let permit = match self.options.reject_when_busy {
+            true => self.writes.try_acquire().map_err(|_| SaveError::Busy)?,
+            false => self
+                .writes
+                .acquire()
+                .await
+                .expect("the semaphore is never closed"),
+        };
+
+        // Create the file. `File` implements `AsyncWrite`. The body streams into a temporary
+        // file that is only renamed into place once complete, so a partial image is never seen.
+        // Two uploads received within the same second get the same timestamp name, so generated
+        // names are made unique the same way requested ones are. The directory may have been
+        // removed again since it was made sure of, which is worth a single retry.
+        let reserve = || async {
+            if let Some(upload_stem) = &destination.upload_stem {
+                let filename =
+                    destination.in_bucket(format!("{}.{}", upload_stem, format.extension()));
+                let (temp_path, file) =
+                    create_temp(serial_dir, &filename, self.options.file_mode).await?;
+                return Ok((Some(filename), temp_path, file));
+            }
+            let image_dir = destination.image_dir();
+            match &destination.stem {
+                Some(stem) => {
+                    let (filename, temp_path, file) =
+                        create_unique(&image_dir, stem, format.extension(), self.options.file_mode)
+                            .await?;
+                    Ok::<_, io::Error>((Some(destination.in_bucket(filename)), temp_path, file))
+                }
+                None => {
+                    let (temp_path, file) =
+                        create_temp(&image_dir, "upload", self.options.file_mode).await?;
+                    Ok((None, temp_path, file))
+                }
+            }
+        };
+        let (reserved, temp_path, file) = match reserve().await {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                self.ensure_destination(serial_number, destination).await?;
+                reserve().await?
+            }
+            reserved => reserved?,
+        };
+        let mut file = BufWriter::with_capacity(self.options.write_buffer_bytes, file);
+
+        // Copy the body into the file a chunk at a time. Bytes are counted as they stream, since
+        // chunked uploads carry no `Content-Length`, and the copy stops at the first chunk that
+        // goes past the limit. Large uploads log their progress along the way.
+        // Flushing hands every buffered byte to the OS before the image is renamed into place.
+        // A client trickling its body past the deadline loses what it sent so far.
+        let copied = self
+            .before_deadline(deadline, async {
+                let mut chunk = vec![0; COPY_CHUNK_LEN];
+                let mut copied = 0;
+                let mut next_progress = PROGRESS_INTERVAL_BYTES;
+                loop {
+                    let read = body.read(&mut chunk).await?;
+                    if read == 0 {
+                        break;
+                    }
+                    copied += read as u64;
+                    if copied > max_bytes {
+                        break;
+                    }
+                    file.write_all(&chunk[..read]).await?;
+                    if copied >= next_progress {
+                        tracing::debug!(
+                            "received {} bytes of {} for {}",
+                            copied,
+                            reserved.as_deref().unwrap_or("an upload"),
+                            serial_number
+                        );
+                        next_progress += PROGRESS_INTERVAL_BYTES;
+                    }
+                }
+                file.flush().await?;
+                if self.options.fsync {
+                    file.get_ref().sync_all().await?;
+                }
+                Ok::<_, io::Error>(copied)
+            })
+            .await;
+        drop(file);
+        drop(permit);
+        let copied = match copied {
+            Ok(Ok(copied)) if copied <= max_bytes => copied,
+            Ok(Ok(_)) => {
+                tokio::fs::remove_file(&temp_path).await?;
+                return Err(SaveError::TooLarge(max_bytes));
+            }
+            Ok(Err(err)) => {
+                remove_if_exists(&temp_path).await?;
+                return Err(err.into());
+            }
+            Err(err) => {
+                remove_if_exists(&temp_path).await?;
+                return Err(err);
+            }
+        };
+
+        // An image small in bytes can still decode to gigabytes of pixels, so its size is read from
+        // the header before the thumbnailer or a re-encode decodes it. An image whose header can not
+        // be read is left to fail wherever it is decoded.
+        if self.options.max_pixels.is_some() {
+            let source = temp_path.clone();
+            let dimensions = tokio::task::spawn_blocking(move || image_dimensions(&source))
+                .await
+                .ok()
+                .flatten();
+            if let Err(err) = self.check_pixels(dimensions) {
+                tokio::fs::remove_file(&temp_path).await?;
+                return Err(err);
+            }
+        }
+
+        let sha256 = body.finish();
+        let filename = reserved
+            .unwrap_or_else(|| destination.in_bucket(format!("{}.{}", sha256, format.extension())));
+        Ok(ReceivedUpload {
+            filename,
+            temp_path,
+            bytes: copied,
+            sha256,
+            frame_hash: None,
+        })
+    }
+
+    /// Compare `upload` with the images stored for `serial_number`: the one with the same bytes,
+    /// and, when near duplicates are dropped, the one stored last if it looks alike
+    async fn dedupe(
+        &self,
+        serial_number: &str,
+        settings: &SerialSettings,
+        destination: &Destination,
+        upload: &ReceivedUpload,
+    ) -> io::Result<Comparison> {
+        let serial_dir = &destination.serial_dir;
+        let hashes = read_hashes(serial_dir).await?;
+        if let Some(existing) =
+            stored_duplicate(serial_dir, &hashes, &upload.sha256, self.options.naming).await?
+        {
+            tracing::debug!("image for {} is a duplicate of {}", serial_number, existing);
+            return Ok(Comparison::Duplicate(existing));
+        }
+
+        // A frame is compared with the one stored last rather than the last dropped, so that a
+        // scene changing slowly still gets stored once it drifted far enough. Decoding is
+        // CPU-bound, so it stays off the async worker threads, and an image that fails to decode
+        // is stored as it is.
+        let Some(max_distance) = settings.near_duplicate_distance else {
+            return Ok(Comparison::New(None));
+        };
+        let source = upload.temp_path.clone();
+        let Some(frame_hash) = tokio::task::spawn_blocking(move || difference_hash(&source))
+            .await
+            .ok()
+            .and_then(Result::ok)
+        else {
+            return Ok(Comparison::New(None));
+        };
+        let previous = self
+            .previous_frames
+            .lock()
+            .unwrap()
+            .get(serial_number)
+            .filter(|(previous_hash, _)| (previous_hash ^ frame_hash).count_ones() <= max_distance)
+            .map(|(_, previous)| previous.clone());
+        if let Some(existing) = previous {
+            if tokio::fs::try_exists(serial_dir.join(&existing)).await? {
+                tracing::debug!("image for {} looks like {}", serial_number, existing);
+                return Ok(Comparison::Duplicate(existing));
+            }
+        }
+        Ok(Comparison::New(Some(frame_hash)))
+    }
+
+    /// Rewrite `upload` in its temporary file as configured: without its metadata, and
+    /// recompressed when it is a JPEG over the budget
+    async fn rewrite_temp(
+        &self,
+        settings: &SerialSettings,
+        upload: &ReceivedUpload,
+        format: ImageFormat,
+    ) -> io::Result<()> {
+        // Rewritten in place, as the hash is of the bytes that were uploaded so that duplicates
+        // are still recognized.
+        if self.options.strip_metadata {
+            let original = tokio::fs::read(&upload.temp_path).await?;
+            let stripped = strip_metadata(&original, format);
+            if stripped != original {
+                rewrite(&upload.temp_path, &stripped, self.options.fsync).await?;
+            }
+        }
+
+        // Recompress a JPEG over the budget, unless it is about to be re-encoded as WebP anyway.
+        // Decoding and encoding are CPU-bound, so they stay off the async worker threads.
+        if let (Some(budget), ImageFormat::Jpeg, None) =
+            (self.options.jpeg_max_bytes, format, settings.webp_quality)
+        {
+            if tokio::fs::metadata(&upload.temp_path).await?.len() > budget {
+                let source = upload.temp_path.clone();
+                match tokio::task::spawn_blocking(move || recompress_jpeg(&source, budget))
+                    .await
+                    .map_err(BoxError::from)
+                    .and_then(|result| result)
+                {
+                    Ok(Some(recompressed)) => {
+                        rewrite(&upload.temp_path, &recompressed, self.options.fsync).await?
+                    }
+                    Ok(None) => {}
+                    Err(err) => {
+                        tracing::warn!("could not recompress {}: {}", upload.filename, err)
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Rename `upload` into place once the quota has room for it, and keep a WebP copy instead
+    /// when configured. The image it replaces is removed when it was under another name. Returns
+    /// the filename, path and format of the stored image.
+    async fn finalize(
+        &self,
+        serial_number: &str,
+        settings: &SerialSettings,
+        destination: &Destination,
+        upload: &ReceivedUpload,
+        format: ImageFormat,
+    ) -> Result<(String, PathBuf, ImageFormat), SaveError> {
+        let serial_dir = &destination.serial_dir;
+        let replacing = &destination.replacing;
+
+        // The quota is checked once the size on disk is final, and its count stays locked until the
+        // image is in place so that concurrent uploads can not both take the last of the room.
+        // A replaced image makes room for the one taking its place.
+        let stored_len = tokio::fs::metadata(&upload.temp_path).await?.len();
+        let replaced_len = match replacing {
+            Some(existing) => tokio::fs::metadata(serial_dir.join(existing))
+                .await
+                .map_or(0, |metadata| metadata.len()),
+            None => 0,
+        };
+        let usage = match settings.serial_quota_bytes {
+            Some(quota) => {
+                let mut usage = self.usage.lock().await;
+                if let Err(err) = self
+                    .make_room(
+                        &mut usage,
+                        serial_number,
+                        serial_dir,
+                        quota,
+                        settings.evict_oldest,
+                        stored_len.saturating_sub(replaced_len),
+                    )
+                    .await
+                {
+                    remove_if_exists(&upload.temp_path).await?;
+                    return Err(err);
+                }
+                Some(usage)
+            }
+            None => None,
+        };
+
+        // the rename is atomic as both paths are in the same directory
+        let filename = upload.filename.clone();
+        let path_buf = serial_dir.join(&filename);
+        tokio::fs::rename(&upload.temp_path, &path_buf).await?;
+        drop(usage);
+
+        // Keep a WebP copy instead of the original when configured. Animated GIFs would lose
+        // their frames, and an image that fails to decode is kept as it was uploaded.
+        let (filename, path_buf, format) = match settings.webp_quality {
+            Some(quality) if matches!(format, ImageFormat::Jpeg | ImageFormat::Png) => {
+                let stem = filename
+                    .rsplit_once('.')
+                    .map_or(&*filename, |(stem, _)| stem);
+                let webp_filename = format!("{}.{}", stem, ImageFormat::Webp.extension());
+                let (webp_temp_path, webp_temp) =
+                    create_temp(serial_dir, &webp_filename, self.options.file_mode).await?;
+                drop(webp_temp);
+                let (source, destination) = (path_buf.clone(), webp_temp_path.clone());
+                let fsync = self.options.fsync;
+                match tokio::task::spawn_blocking(move || {
+                    reencode_webp(&source, &destination, quality, fsync)
+                })
+                .await
+                .map_err(BoxError::from)
+                .and_then(|result| result)
+                {
+                    Ok(()) => {
+                        let webp_path = serial_dir.join(&webp_filename);
+                        tokio::fs::rename(&webp_temp_path, &webp_path).await?;
+                        tokio::fs::remove_file(&path_buf).await?;
+                        if settings.serial_quota_bytes.is_some() {
+                            let webp_len = tokio::fs::metadata(&webp_path).await?.len();
+                            if let Some(used) = self.usage.lock().await.get_mut(serial_number) {
+                                *used = used.saturating_sub(stored_len) + webp_len;
+                            }
+                        }
+                        (webp_filename, webp_path, ImageFormat::Webp)
+                    }
+                    Err(err) => {
+                        tracing::warn!("could not re-encode {} to WebP: {}", filename, err);
+                        remove_if_exists(&webp_temp_path).await?;
+                        (filename, path_buf, format)
+                    }
+                }
+            }
+            _ => (filename, path_buf, format),
+        };
+        // the replaced image may have been in another format, and so under another name
+        if let Some(existing) = replacing.as_ref().filter(|existing| **existing != filename) {
+            remove_if_exists(&serial_dir.join(existing)).await?;
+            remove_if_exists(&serial_dir.join(sidecar_filename(existing))).await?;
+        }
+        if replacing.is_some() {
+            self.forget_usage(serial_number).await;
+        }
+        Ok((filename, path_buf, format))
+    }
+
+    /// Record `saved` once it is in place: its sidecar, its hash for the duplicate checks, its
+    /// frame for the next one to be compared with, and it as the latest image
+    async fn record(
+        &self,
+        serial_number: &str,
+        destination: &Destination,
+        source: &UploadSource,
+        upload: &ReceivedUpload,
+        saved: &SavedImage,
+    ) -> io::Result<()> {
+        let serial_dir = &destination.serial_dir;
+        let sidecar = Sidecar {
+            received_at: saved.received_at.to_rfc3339(),
+            client_ip: source.client_ip,
+            user_agent: source.user_agent.clone(),
+            bytes: upload.bytes,
+            stored_bytes: Some(tokio::fs::metadata(&saved.path).await?.len()),
+            sha256: upload.sha256.clone(),
+            format: saved.format,
+        };
+        write_sidecar(
+            serial_dir,
+            &saved.filename,
+            &sidecar,
+            self.options.fsync,
+            self.options.file_mode,
+        )
+        .await?;
+        // the renames so far only last once the directory entries are on disk too
+        if self.options.fsync {
+            sync_dir(serial_dir).await?;
+        }
+
+        // the bytes of a replaced image are not stored anymore
+        let mut hashes = read_hashes(serial_dir).await?;
+        hashes.retain(|_, stored| {
+            *stored != saved.filename && Some(&*stored) != destination.replacing.as_ref()
+        });
+        hashes.insert(upload.sha256.clone(), saved.filename.clone());
+        write_hashes(
+            serial_dir,
+            &hashes,
+            self.options.fsync,
+            self.options.file_mode,
+        )
+        .await?;
+        if let Some(frame_hash) = upload.frame_hash {
+            self.previous_frames.lock().unwrap().insert(
+                serial_number.to_owned(),
+                (frame_hash, saved.filename.clone()),
+            );
+        }
+
+        self.set_latest(
+            serial_number,
+            &saved.filename,
+            saved.format,
+            saved.received_at,
+        )
+        .await?;
+        tracing::debug!(
+            "image saved to {}: {} and {}",
+            serial_number,
+            saved.filename,
+            self.options.latest_name.filename(saved.format)
+        );
+        Ok(())
+    }
+
+    /// Run the checks of `save_image` on `body` and tell the name it would get, storing nothing
+    pub async fn validate_image<R>(
+        &self,
+        serial_number: &str,
+        requested_filename: Option<&str>,
+        source: &UploadSource,
+        body: R,
+    ) -> Result<ValidatedImage, SaveError>
+    where
+        R: AsyncRead + Unpin,
+    {
+        if !serial_is_valid(serial_number) {
+            return Err(SaveError::InvalidSerial);
+        }
+        let max_bytes = self.options.max_bytes;
+        let deadline = self.receive_deadline();
+
+        let mut body = body;
+        let mut header = [0; MAGIC_BYTES_LEN];
+        let header_len = self
+            .before_deadline(deadline, read_header(&mut body, &mut header))
+            .await??;
+        let header = &header[..header_len];
+        let settings = self.settings(serial_number);
+        let format = self.accepted_format(&settings, header)?;
+
+        // The image is only kept in memory when its pixels are to be counted, which takes its header
+        // and may take all of it, as a JPEG can have any amount of metadata before its frame header.
+        let mut body = HashingReader::new(header.chain(body));
+        let mut kept = Vec::new();
+        let copied = self
+            .before_deadline(deadline, async {
+                let mut body = (&mut body).take(max_bytes + 1);
+                match self.options.max_pixels {
+                    Some(_) => tokio::io::copy(&mut body, &mut kept).await,
+                    None => tokio::io::copy(&mut body, &mut tokio::io::sink()).await,
+                }
+            })
+            .await??;
+        if copied > max_bytes {
+            return Err(SaveError::TooLarge(max_bytes));
+        }
+        if self.options.max_pixels.is_some() {
+            let dimensions = tokio::task::spawn_blocking(move || encoded_dimensions(&kept))
+                .await
+                .ok()
+                .flatten();
+            self.check_pixels(dimensions)?;
+        }
+
+        let serial_dir = self.serial_dir(serial_number);
+        let hash = body.finish();
+        let hashes = read_hashes(&serial_dir).await?;
+        if let Some(existing) =
+            stored_duplicate(&serial_dir, &hashes, &hash, self.options.naming).await?
+        {
+            return Ok(ValidatedImage {
+                filename: existing,
+                bytes: copied,
+                format,
+                duplicate: true,
+            });
+        }
+
+        let stored_format = match settings.webp_quality {
+            Some(_) if matches!(format, ImageFormat::Jpeg | ImageFormat::Png) => ImageFormat::Webp,
+            _ => format,
+        };
+        let received_at = source.received_at.unwrap_or_else(Local::now);
+        let bucket = self
+            .options
+            .layout
+            .bucket(received_at, self.options.filename_time.utc());
+        let in_bucket = |filename: String| match &bucket {
+            Some(bucket) => format!("{}/{}", bucket, filename),
+            None => filename,
+        };
+        if self.options.naming == Naming::ContentHash {
+            return Ok(ValidatedImage {
+                filename: in_bucket(format!("{}.{}", hash, stored_format.extension())),
+                bytes: copied,
+                format,
+                duplicate: false,
+            });
+        }
+        let image_dir = match &bucket {
+            Some(bucket) => serial_dir.join(bucket),
+            None => serial_dir,
+        };
+        let stem = requested_filename
+            .and_then(|filename| {
+                requested_stem(
+                    filename,
+                    &self.options.latest_name,
+                    &self.options.filename_time,
+                )
+            })
+            .unwrap_or_else(|| self.options.filename_time.stem(received_at));
+        let mut candidate = stem.clone();
+        let mut suffix = 0;
+        while stem_is_taken(&image_dir, &candidate).await? {
+            suffix += 1;
+            candidate = format!("{}-{}", stem, suffix);
+        }
+        let stem = candidate;
+        Ok(ValidatedImage {
+            filename: in_bucket(format!("{}.{}", stem, stored_format.extension())),
+            bytes: copied,
+            format,
+            duplicate: false,
+        })
+    }
+
+    /// Refuse an image of `dimensions` with more pixels than `SaveOptions::max_pixels`. An image
+    /// whose header could not be read is let through, to fail wherever it is decoded.
+    fn check_pixels(&self, dimensions: Option<(u32, u32)>) -> Result<(), SaveError> {
+        match (self.options.max_pixels, dimensions) {
+            (Some(max_pixels), Some((width, height)))
+                if u64::from(width) * u64::from(height) > max_pixels =>
+            {
+                Err(SaveError::TooManyPixels {
+                    width,
+                    height,
+                    max_pixels,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Path, relative to the serial directory `serial_dir`, of the image stored under `stem` by an
+    /// earlier `put_image`, whichever format and date subdirectory it is in
+    async fn stored_upload(&self, serial_dir: &Path, stem: &str) -> io::Result<Option<String>> {
+        if self.options.layout == Layout::Flat {
+            return stored_with_stem(serial_dir, stem).await;
+        }
+        let images = match stored_images(serial_dir, &self.options.latest_name).await {
+            Ok(images) => images,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        Ok(images.into_iter().map(|(path, _)| path).find(|path| {
+            let filename = path.rsplit('/').next().unwrap_or(path);
+            filename
+                .rsplit_once('.')
+                .is_some_and(|(image_stem, _)| image_stem == stem)
+        }))
+    }
+
+    /// Count `len` more bytes against the quota of `serial_number`, first evicting its oldest images
+    /// when they would not fit and `evict` allows it. The latest image is never evicted.
+    async fn make_room(
+        &self,
+        usage: &mut HashMap<String, u64>,
+        serial_number: &str,
+        serial_dir: &Path,
+        quota: u64,
+        evict: bool,
+        len: u64,
+    ) -> Result<(), SaveError> {
+        let mut used = match usage.get(serial_number) {
+            Some(used) => *used,
+            None => stored_images_len(serial_dir, &self.options.latest_name).await?,
+        };
+        if used + len > quota && evict && len <= quota {
+            used -= evict_oldest(serial_dir, &self.options.latest_name, used + len - quota).await?;
+        }
+        if used + len > quota {
+            usage.insert(serial_number.to_owned(), used);
+            return Err(SaveError::QuotaExceeded(quota));
+        }
+        usage.insert(serial_number.to_owned(), used + len);
+        Ok(())
+    }
+
+    /// Create the directory of `serial_number` along with the uploads directory, when they are
+    /// missing. Removing them while the server runs takes the images counted for the quota with
+    /// them, so the count starts over.
+    async fn ensure_serial_dir(&self, serial_number: &str, serial_dir: &Path) -> io::Result<()> {
+        if tokio::fs::metadata(serial_dir)
+            .await
+            .is_ok_and(|metadata| metadata.is_dir())
+        {
+            return Ok(());
+        }
+        // a read-only or full uploads volume fails this upload only, not the whole handler
+        tokio::fs::create_dir_all(serial_dir).await.map_err(|err| {
+            tracing::error!("could not create {}: {}", serial_dir.display(), err);
+            io::Error::new(
+                err.kind(),
+                format!(
+                    "failed to create the directory for {}: {}",
+                    serial_number, err
+                ),
+            )
+        })?;
+        set_mode(serial_dir, self.options.dir_mode).await?;
+        self.forget_usage(serial_number).await;
+        Ok(())
+    }
+
+    /// Create the date subdirectories `bucket` of a serial directory, when they are missing, giving
+    /// the ones made the directory mode
+    async fn ensure_bucket(&self, serial_dir: &Path, bucket: &str) -> io::Result<()> {
+        let mut dir = serial_dir.to_owned();
+        for component in bucket.split('/') {
+            dir.push(component);
+            match tokio::fs::create_dir(&dir).await {
+                Ok(()) => set_mode(&dir, self.options.dir_mode).await?,
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    /// Rotate the stored image `filename` of `serial_number` in place, re-encoded in its format
+    /// without the metadata it carried. GIFs are refused with `io::ErrorKind::Unsupported`, as
+    /// their animation frames would be lost. The rotated image replaces the original in a single
+    /// rename, so readers see one or the other.
+    pub async fn rotate_image(
+        &self,
+        serial_number: &str,
+        filename: &str,
+        rotation: Rotation,
+    ) -> io::Result<ImageFormat> {
+        let dir = self.serial_dir(serial_number);
+        let path = dir.join(filename);
+        let format = detect_file_format(&path).await?.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "stored file is not an image")
+        })?;
+        if format == ImageFormat::Gif {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "GIF images can not be rotated",
+            ));
+        }
+
+        // Decoding and encoding are CPU-bound, so they stay off the async worker threads.
+        let source = path.clone();
+        let webp_quality = self.settings(serial_number).webp_quality;
+        let rotated = tokio::task::spawn_blocking(move || {
+            rotate_encoded(&source, format, rotation, webp_quality)
+        })
+        .await
+        .map_err(io::Error::other)?
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+        let (temp_path, mut file) = create_temp(&dir, filename, self.options.file_mode).await?;
+        let written = async {
+            file.write_all(&rotated).await?;
+            file.flush().await?;
+            if self.options.fsync {
+                file.sync_all().await?;
+            }
+            Ok(())
+        }
+        .await;
+        drop(file);
+        if let Err(err) = written {
+            remove_if_exists(&temp_path).await?;
+            return Err(err);
+        }
+        tokio::fs::rename(&temp_path, &path).await?;
+
+        if let Some(mut sidecar) = read_sidecar(&dir, filename).await? {
+            sidecar.stored_bytes = Some(rotated.len() as u64);
+            write_sidecar(
+                &dir,
+                filename,
+                &sidecar,
+                self.options.fsync,
+                self.options.file_mode,
+            )
+            .await?;
+        }
+        if self.options.fsync {
+            sync_dir(&dir).await?;
+        }
+        self.forget_usage(serial_number).await;
+        tracing::debug!("image of {} rotated: {}", serial_number, filename);
+        Ok(format)
+    }
+
+    /// Drop the counted usage of `serial_number`, so that the quota counts its images again after
+    /// they were removed outside of `save_image`
+    pub async fn forget_usage(&self, serial_number: &str) {
+        self.usage.lock().await.remove(serial_number);
+    }
+
+    /// When the body of an upload starting now has to be received by, if ever
+    fn receive_deadline(&self) -> Option<Instant> {
+        self.options
+            .receive_timeout
+            .map(|timeout| Instant::now() + timeout)
+    }
+
+    /// Wait for `receiving` to finish, failing with `SaveError::TimedOut` once `deadline` passes
+    async fn before_deadline<F: Future>(
+        &self,
+        deadline: Option<Instant>,
+        receiving: F,
+    ) -> Result<F::Output, SaveError> {
+        match (deadline, self.options.receive_timeout) {
+            (Some(deadline), Some(timeout)) => tokio::time::timeout_at(deadline, receiving)
+                .await
+                .map_err(|_| SaveError::TimedOut(timeout)),
+            _ => Ok(receiving.await),
+        }
+    }
+
+    /// Format of an upload starting with `header`, if it is one that is stored. An empty body is
+    /// refused before any fallback format could apply to it.
+    fn accepted_format(
+        &self,
+        settings: &SerialSettings,
+        header: &[u8],
+    ) -> Result<ImageFormat, SaveError> {
+        if header.is_empty() {
+            return Err(SaveError::Empty);
+        }
+        let format = detect_image_format(header)
+            .or(self.options.fallback_format)
+            .ok_or(SaveError::UnsupportedFormat)?;
+        match &settings.allowed_formats {
+            Some(allowed) if !allowed.contains(&format) => Err(SaveError::FormatNotAllowed(format)),
+            _ => Ok(format),
+        }
+    }
+
+    /// Rename the upload at `temp_path` over the latest copy of `serial_number`, re-encoded as WebP
+    /// first when stored images would be. The image the previous latest copy pointed at stays
+    /// stored. Returns the filename and path of the latest copy, and the format of the image.
+    async fn replace_latest(
+        &self,
+        serial_number: &str,
+        serial_dir: &Path,
+        temp_path: PathBuf,
+        format: ImageFormat,
+        webp_quality: Option<f32>,
+        received_at: DateTime<Local>,
+    ) -> io::Result<(String, PathBuf, ImageFormat)> {
+        let (temp_path, format) = match webp_quality {
+            Some(quality) if matches!(format, ImageFormat::Jpeg | ImageFormat::Png) => {
+                let (webp_temp_path, webp_temp) =
+                    create_temp(serial_dir, "latest.webp", self.options.file_mode).await?;
+                drop(webp_temp);
+                let (source, destination) = (temp_path.clone(), webp_temp_path.clone());
+                let fsync = self.options.fsync;
+                match tokio::task::spawn_blocking(move || {
+                    reencode_webp(&source, &destination, quality, fsync)
+                })
+                .await
+                .map_err(BoxError::from)
+                .and_then(|result| result)
+                {
+                    Ok(()) => {
+                        tokio::fs::remove_file(&temp_path).await?;
+                        (webp_temp_path, ImageFormat::Webp)
+                    }
+                    Err(err) => {
+                        tracing::warn!(
+                            "could not re-encode the latest image of {} to WebP: {}",
+                            serial_number,
+                            err
+                        );
+                        remove_if_exists(&webp_temp_path).await?;
+                        (temp_path, format)
+                    }
+                }
+            }
+            _ => (temp_path, format),
+        };
+
+        let latest_name = &self.options.latest_name;
+        let filename = latest_name.filename(format);
+        let path = serial_dir.join(&filename);
+        let lock = self.latest_locks.get(serial_number);
+        let mut latest_received_at = lock.lock().await;
+        if latest_received_at.is_some_and(|latest| latest > received_at) {
+            tokio::fs::remove_file(&temp_path).await?;
+            tracing::debug!(
+                "latest image for {} was overtaken by a newer upload, dropped",
+                serial_number
+            );
+            return Ok((filename, path, format));
+        }
+        // replaces the symlink to a stored image as well as a previous latest-only image
+        tokio::fs::rename(&temp_path, &path).await?;
+        remove_stale_latest(serial_dir, latest_name, Some(format)).await?;
+        if self.options.fsync {
+            sync_dir(serial_dir).await?;
+        }
+        *latest_received_at = Some(received_at);
+        tracing::debug!("latest image of {} replaced: {}", serial_number, filename);
+        Ok((filename, path, format))
+    }
+
+    /// Hold off uploads of `serial_number` from moving its latest copy, for instance while the image
+    /// it points at is deleted
+    pub async fn lock_latest(&self, serial_number: &str) -> LatestGuard {
+        LatestGuard {
+            _guard: self.latest_locks.get(serial_number).lock_owned().await,
+        }
+    }
+
+    /// Point the latest copy of `serial_number` at its newest stored image again, after files were
+    /// moved around by hand. The newest image is the one its filename says was received last, or
+    /// the one modified last among names without a time. A plain copy, such as a
+    /// `save_latest_only` upload leaves, counts as up to date unless an image was modified after it.
+    pub async fn repair_latest(&self, serial_number: &str) -> io::Result<LatestRepair> {
+        let _latest_guard = self.lock_latest(serial_number).await;
+        let dir = self.serial_dir(serial_number);
+        let latest_name = &self.options.latest_name;
+
+        let mut newest = None;
+        for (filename, metadata) in stored_images(&dir, latest_name).await? {
+            let key = (
+                self.options.filename_time.of_filename(&filename),
+                metadata.modified()?,
+                filename,
+            );
+            newest = newest.max(Some(key));
+        }
+        let mut copies = Vec::new();
+        for format in ImageFormat::ALL {
+            match tokio::fs::symlink_metadata(dir.join(latest_name.filename(format))).await {
+                Ok(metadata) => copies.push((format, metadata)),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        let Some((_, newest_modified, newest)) = newest else {
+            // with no image to point at, only the links to images that are gone are in the way
+            let mut removed = false;
+            for (format, metadata) in copies {
+                let copy = dir.join(latest_name.filename(format));
+                if metadata.is_symlink() && !tokio::fs::try_exists(&copy).await? {
+                    tokio::fs::remove_file(copy).await?;
+                    removed = true;
+                }
+            }
+            return Ok(match removed {
+                true => LatestRepair::Removed,
+                false => LatestRepair::Unchanged,
+            });
+        };
+        let Some(format) = detect_file_format(&dir.join(&newest)).await? else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is not an image", dir.join(&newest).display()),
+            ));
+        };
+
+        let up_to_date = match copies.as_slice() {
+            [(copy_format, metadata)] if *copy_format == format => {
+                if metadata.is_symlink() {
+                    latest_target(&dir, latest_name).await?.as_ref() == Some(&newest)
+                } else {
+                    metadata.modified()? >= newest_modified
+                }
+            }
+            _ => false,
+        };
+        if up_to_date {
+            return Ok(LatestRepair::Unchanged);
+        }
+        update_latest_symlink(&dir, latest_name, &newest, format).await?;
+        remove_stale_latest(&dir, latest_name, Some(format)).await?;
+        if self.options.fsync {
+            sync_dir(&dir).await?;
+        }
+        Ok(LatestRepair::Repointed(newest))
+    }
+
+    /// Point the latest copy of `serial_number` at `target`, unless an image received later than
+    /// `received_at` already took its place while this one was still streaming
+    async fn set_latest(
+        &self,
+        serial_number: &str,
+        target: &str,
+        format: ImageFormat,
+        received_at: DateTime<Local>,
+    ) -> io::Result<()> {
+        let lock = self.latest_locks.get(serial_number);
+        let mut latest_received_at = lock.lock().await;
+        if latest_received_at.is_some_and(|latest| latest > received_at) {
+            tracing::debug!(
+                "{} for {} was overtaken by a newer upload, latest left as is",
+                target,
+                serial_number
+            );
+            return Ok(());
+        }
+
+        let serial_dir = self.serial_dir(serial_number);
+        let latest_name = &self.options.latest_name;
+        update_latest_symlink(&serial_dir, latest_name, target, format).await?;
+        remove_stale_latest(&serial_dir, latest_name, Some(format)).await?;
+        *latest_received_at = Some(received_at);
+        Ok(())
+    }
+}
+
+/// `AsyncRead` adapter that feeds everything read through it into a SHA-256 hasher
+struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R> HashingReader<R> {
+    fn new(inner: R) -> Self {
+        HashingReader {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// Hex digest of everything read so far
+    fn finish(self) -> String {
+        format!("{:x}", self.hasher.finalize())
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for HashingReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let already_filled = buf.filled().len();
+        let this = &mut *self;
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        this.hasher.update(&buf.filled()[already_filled..]);
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Read up to `buf.len()` bytes, stopping early only if the stream ends
+pub(crate) async fn read_header<R>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize>
+where
+    R: AsyncRead + Unpin + ?Sized,
+{
+    let mut filled = 0;
+    while filled < buf.len() {
+        let n = reader.read(&mut buf[filled..]).await?;
+        if n == 0 {
+            break;
+        }
+        filled += n;
+    }
+    Ok(filled)
+}
+
+/// Recognize an image format from its magic number
+pub fn detect_image_format(header: &[u8]) -> Option<ImageFormat> {
+    if header.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some(ImageFormat::Jpeg)
+    } else if header.starts_with(&[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A]) {
+        Some(ImageFormat::Png)
+    } else if header.starts_with(b"GIF87a") || header.starts_with(b"GIF89a") {
+        Some(ImageFormat::Gif)
+    } else if header.len() >= 12 && &header[..4] == b"RIFF" && &header[8..12] == b"WEBP" {
+        Some(ImageFormat::Webp)
+    } else {
+        None
+    }
+}
+
+/// Format of a stored file, judged from its first bytes
+pub async fn detect_file_format(path: &Path) -> io::Result<Option<ImageFormat>> {
+    let mut file = File::open(path).await?;
+    let mut header = [0; MAGIC_BYTES_LEN];
+    let header_len = read_header(&mut file, &mut header).await?;
+    Ok(detect_image_format(&header[..header_len]))
+}
+
+/// Copy of `bytes` without the metadata a JPEG or PNG carries besides its pixels, such as EXIF with
+/// GPS coordinates or text comments. An image that can not be parsed is returned unchanged.
+fn strip_metadata(bytes: &[u8], format: ImageFormat) -> Vec<u8> {
+    let stripped = match format {
+        ImageFormat::Jpeg => {
+            img_parts::jpeg::Jpeg::from_bytes(Bytes::copy_from_slice(bytes)).map(|mut jpeg| {
+                // APP0 (JFIF) and APP14 (Adobe) describe how the pixels are encoded, so they stay
+                jpeg.segments_mut()
+                    .retain(|segment| !matches!(segment.marker(), APP1..=APP13 | APP15 | COM));
+                jpeg.encoder().bytes()
+            })
+        }
+        ImageFormat::Png => {
+            img_parts::png::Png::from_bytes(Bytes::copy_from_slice(bytes)).map(|mut png| {
+                png.chunks_mut()
+                    .retain(|chunk| !PNG_METADATA_CHUNKS.contains(&&chunk.kind()));
+                png.encoder().bytes()
+            })
+        }
+        ImageFormat::Gif | ImageFormat::Webp => return bytes.to_vec(),
+    };
+    match stripped {
+        Ok(stripped) => stripped.into(),
+        Err(err) => {
+            tracing::warn!("could not strip metadata, keeping the image as is: {}", err);
+            bytes.to_vec()
+        }
+    }
+}
+
+/// Width and height of the image at `path`, read from its header without decoding the pixels
+fn image_dimensions(path: &Path) -> Option<(u32, u32)> {
+    reader_dimensions(image::ImageReader::open(path).ok()?)
+}
+
+/// Width and height of the image encoded in `bytes`, read the same way as `image_dimensions`
+fn encoded_dimensions(bytes: &[u8]) -> Option<(u32, u32)> {
+    reader_dimensions(image::ImageReader::new(std::io::Cursor::new(bytes)))
+}
+
+fn reader_dimensions<R: std::io::BufRead + std::io::Seek>(
+    reader: image::ImageReader<R>,
+) -> Option<(u32, u32)> {
+    reader.with_guessed_format().ok()?.into_dimensions().ok()
+}
+
+/// Perceptual hash of the image at `path`, the difference hash: shrunk to 9 by 8 grayscale pixels,
+/// one bit per pair of neighbours in a row telling whether the right one is brighter. Frames of the
+/// same scene end up a few bits apart however different their bytes are.
+fn difference_hash(path: &Path) -> Result<u64, BoxError> {
+    let image = image::ImageReader::open(path)?
+        .with_guessed_format()?
+        .decode()?;
+    let small = image
+        .resize_exact(9, 8, image::imageops::FilterType::Triangle)
+        .to_luma8();
+    let mut hash = 0;
+    for y in 0..8 {
+        for x in 0..8 {
+            let brighter = small.get_pixel(x + 1, y)[0] > small.get_pixel(x, y)[0];
+            hash = hash << 1 | u64::from(brighter);
+        }
+    }
+    Ok(hash)
+}
+
+/// Write `source` re-encoded as a WebP of `quality`, from 0 to 100, to `destination`
+fn reencode_webp(
+    source: &Path,
+    destination: &Path,
+    quality: f32,
+    fsync: bool,
+) -> Result<(), BoxError> {
+    let image = image::ImageReader::open(source)?
+        .with_guessed_format()?
+        .decode()?;
+    // the encoder only takes 8-bit RGB and RGBA pixels
+    let image = match image.color().has_alpha() {
+        true => image::DynamicImage::ImageRgba8(image.to_rgba8()),
+        false => image::DynamicImage::ImageRgb8(image.to_rgb8()),
+    };
+    let encoded = webp::Encoder::from_image(&image)?.encode(quality);
+    std::fs::write(destination, &*encoded)?;
+    if fsync {
+        std::fs::File::open(destination)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// The image at `source`, in `format`, turned by `rotation` and encoded again in that format. A
+/// WebP is encoded at `webp_quality` when uploads are re-encoded at one, and losslessly otherwise.
+fn rotate_encoded(
+    source: &Path,
+    format: ImageFormat,
+    rotation: Rotation,
+    webp_quality: Option<f32>,
+) -> Result<Vec<u8>, BoxError> {
+    let image = image::ImageReader::open(source)?
+        .with_guessed_format()?
+        .decode()?;
+    let image = match rotation {
+        Rotation::Rotate90 => image.rotate90(),
+        Rotation::Rotate180 => image.rotate180(),
+        Rotation::Rotate270 => image.rotate270(),
+    };
+    let mut encoded = Vec::new();
+    match format {
+        ImageFormat::Jpeg => image::DynamicImage::ImageRgb8(image.to_rgb8()).write_with_encoder(
+            image::codecs::jpeg::JpegEncoder::new_with_quality(&mut encoded, ROTATED_JPEG_QUALITY),
+        )?,
+        ImageFormat::Png => {
+            image.write_to(&mut io::Cursor::new(&mut encoded), image::ImageFormat::Png)?
+        }
+        ImageFormat::Webp => {
+            // the encoder only takes 8-bit RGB and RGBA pixels
+            let image = match image.color().has_alpha() {
+                true => image::DynamicImage::ImageRgba8(image.to_rgba8()),
+                false => image::DynamicImage::ImageRgb8(image.to_rgb8()),
+            };
+            let encoder = webp::Encoder::from_image(&image)?;
+            encoded = match webp_quality {
+                Some(quality) => encoder.encode(quality),
+                None => encoder.encode_lossless(),
+            }
+            .to_vec();
+        }
+        ImageFormat::Gif => return Err("GIF images can not be rotated".into()),
+    }
+    Ok(encoded)
+}
+
+/// Re-encode the JPEG at `source` at lower and lower qualities until it fits in `budget` bytes,
+/// settling for the smallest attempt when none does. `None` when no attempt is smaller than the
+/// original, which is then better kept. Metadata such as EXIF is not carried over.
+fn recompress_jpeg(source: &Path, budget: u64) -> Result<Option<Vec<u8>>, BoxError> {
+    let original_len = std::fs::metadata(source)?.len();
+    let image = image::ImageReader::open(source)?
+        .with_guessed_format()?
+        .decode()?;
+    // JPEG has no alpha channel
+    let image = image::DynamicImage::ImageRgb8(image.to_rgb8());
+    let mut smallest: Option<Vec<u8>> = None;
+    for quality in RECOMPRESS_QUALITIES {
+        let mut encoded = Vec::new();
+        image.write_with_encoder(image::codecs::jpeg::JpegEncoder::new_with_quality(
+            &mut encoded,
+            quality,
+        ))?;
+        let fits = encoded.len() as u64 <= budget;
+        if smallest
+            .as_ref()
+            .is_none_or(|smallest| encoded.len() < smallest.len())
+        {
+            smallest = Some(encoded);
+        }
+        if fits {
+            break;
+        }
+    }
+    Ok(smallest.filter(|smallest| (smallest.len() as u64) < original_len))
+}
+
+/// Replace the contents of the not yet published file at `path` with `bytes`
+async fn rewrite(path: &Path, bytes: &[u8], fsync: bool) -> io::Result<()> {
+    let mut file = File::create(path).await?;
+    file.write_all(bytes).await?;
+    file.flush().await?;
+    if fsync {
+        file.sync_all().await?;
+    }
+    Ok(())
+}
+
+/// Content hashes recorded for the serial directory `dir`. A corrupt file is treated as empty,
+/// it only costs us deduplication against the images stored before it.
+async fn read_hashes(dir: &Path) -> io::Result<HashMap<String, String>> {
+    match tokio::fs::read(dir.join(HASHES_FILENAME)).await {
+        Ok(bytes) => Ok(serde_json::from_slice(&bytes).unwrap_or_else(|err| {
+            tracing::warn!("ignoring unreadable {}: {}", HASHES_FILENAME, err);
+            HashMap::new()
+        })),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(HashMap::new()),
+        Err(err) => Err(err),
+    }
+}
+
+/// Write the content hashes of the serial directory `dir` under a temporary name and rename them
+/// into place, so that a crash halfway leaves the previous ones
+async fn write_hashes(
+    dir: &Path,
+    hashes: &HashMap<String, String>,
+    fsync: bool,
+    mode: Option<u32>,
+) -> io::Result<()> {
+    let (temp_path, mut file) = create_temp(dir, HASHES_FILENAME, mode).await?;
+    let written = async {
+        file.write_all(&serde_json::to_vec(hashes)?).await?;
+        file.flush().await?;
+        if fsync {
+            file.sync_all().await?;
+        }
+        Ok(())
+    }
+    .await;
+    drop(file);
+    match written {
+        Ok(()) => tokio::fs::rename(&temp_path, dir.join(HASHES_FILENAME)).await,
+        Err(err) => {
+            remove_if_exists(&temp_path).await?;
+            Err(err)
+        }
+    }
+}
+
+/// Name of the sidecar describing the image `filename`
+pub fn sidecar_filename(filename: &str) -> String {
+    format!("{}.json", filename)
+}
+
+/// Write the sidecar of the image `filename` in `dir` under a temporary name and rename it into
+/// place, so that readers never see a partial one
+async fn write_sidecar(
+    dir: &Path,
+    filename: &str,
+    sidecar: &Sidecar,
+    fsync: bool,
+    mode: Option<u32>,
+) -> io::Result<()> {
+    let sidecar_filename = sidecar_filename(filename);
+    let (temp_path, mut file) = create_temp(dir, &sidecar_filename, mode).await?;
+    let written = async {
+        file.write_all(&serde_json::to_vec(sidecar)?).await?;
+        file.flush().await?;
+        if fsync {
+            file.sync_all().await?;
+        }
+        Ok(())
+    }
+    .await;
+    drop(file);
+    match written {
+        Ok(()) => tokio::fs::rename(&temp_path, dir.join(sidecar_filename)).await,
+        Err(err) => {
+            remove_if_exists(&temp_path).await?;
+            Err(err)
+        }
+    }
+}
+
+/// Sync the entries of `dir`, such as files renamed into it, to the disk. Directories can not be
+/// opened as files everywhere, so elsewhere than on Unix this is left to the OS.
+async fn sync_dir(dir: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    File::open(dir).await?.sync_all().await?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+/// Sidecar of the image `filename` in `dir`, if it has a readable one. Images stored before
+/// sidecars were written have none.
+pub async fn read_sidecar(dir: &Path, filename: &str) -> io::Result<Option<Sidecar>> {
+    match tokio::fs::read(dir.join(sidecar_filename(filename))).await {
+        Ok(bytes) => Ok(serde_json::from_slice(&bytes)
+            .inspect_err(|err| {
+                tracing::warn!("ignoring unreadable sidecar of {}: {}", filename, err)
+            })
+            .ok()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Create `<stem>.<extension>` in `dir`, or `<stem>-1.<extension>`, `<stem>-2.<extension>`, ... if
+/// that stem is taken by an image of any format, so that nothing is overwritten and every stored
+/// image keeps a thumbnail of its own. The temporary file is given `mode`, when set.
+async fn create_unique(
+    dir: &Path,
+    stem: &str,
+    extension: &str,
+    mode: Option<u32>,
+) -> io::Result<(String, PathBuf, File)> {
+    let mut suffix = 0;
+    loop {
+        let candidate = match suffix {
+            0 => stem.to_owned(),
+            _ => format!("{}-{}", stem, suffix),
+        };
+        suffix += 1;
+
+        if stem_is_taken(dir, &candidate).await? {
+            continue;
+        }
+
+        // the temporary file doubles as the reservation of the stem while the upload streams
+        let temp_path = dir.join(format!(".{}.tmp", candidate));
+        let file = match File::create_new(&temp_path).await {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(err),
+        };
+        // an upload holding the reservation may have been renamed into place in the meantime
+        if stem_is_taken(dir, &candidate).await? {
+            drop(file);
+            tokio::fs::remove_file(&temp_path).await?;
+            continue;
+        }
+        set_mode(&temp_path, mode).await?;
+
+        return Ok((format!("{}.{}", candidate, extension), temp_path, file));
+    }
+}
+
+/// Whether an image named `stem` is stored in `dir`, in any format
+async fn stem_is_taken(dir: &Path, stem: &str) -> io::Result<bool> {
+    Ok(stored_with_stem(dir, stem).await?.is_some())
+}
+
+/// Filename of the image named `stem` stored in `dir`, in whichever format it is
+async fn stored_with_stem(dir: &Path, stem: &str) -> io::Result<Option<String>> {
+    for format in ImageFormat::ALL {
+        let filename = format!("{}.{}", stem, format.extension());
+        if tokio::fs::try_exists(dir.join(&filename)).await? {
+            return Ok(Some(filename));
+        }
+    }
+    Ok(None)
+}
+
+/// Filename of the image stored in the serial directory `dir` with the bytes hashed to `hash`, if
+/// any. A content-hash name is itself a record of the bytes, even when `hashes` lost track of it.
+async fn stored_duplicate(
+    dir: &Path,
+    hashes: &HashMap<String, String>,
+    hash: &str,
+    naming: Naming,
+) -> io::Result<Option<String>> {
+    if let Some(existing) = hashes.get(hash) {
+        if tokio::fs::try_exists(dir.join(existing)).await? {
+            return Ok(Some(existing.clone()));
+        }
+    }
+    match naming {
+        Naming::ContentHash => stored_with_stem(dir, hash).await,
+        Naming::Timestamp => Ok(None),
+    }
+}
+
+/// Create the temporary file an upload to be stored as `filename` is streamed into, in the same
+/// directory so that it can be renamed into place. The name is unique within the process, so
+/// concurrent uploads never share one, and starts with a dot so listings can skip it. The file is
+/// given `mode` before anything is written to it, when set, and keeps it once renamed.
+async fn create_temp(dir: &Path, filename: &str, mode: Option<u32>) -> io::Result<(PathBuf, File)> {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let (dir, filename) = match filename.rsplit_once('/') {
+        Some((subdir, filename)) => (dir.join(subdir), filename),
+        None => (dir.to_owned(), filename),
+    };
+    let path = dir.join(format!(".{}.{}.tmp", filename, id));
+    let file = File::create(&path).await?;
+    set_mode(&path, mode).await?;
+    Ok((path, file))
+}
+
+/// Give the file or directory at `path` the permissions `mode`, when set. Only Unix has modes;
+/// elsewhere this does nothing.
+pub async fn set_mode(path: &Path, mode: Option<u32>) -> io::Result<()> {
+    #[cfg(unix)]
+    if let Some(mode) = mode {
+        use std::os::unix::fs::PermissionsExt;
+        tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).await?;
+    }
+    #[cfg(not(unix))]
+    let _ = (path, mode);
+    Ok(())
+}
+
+/// Point the latest image of the serial directory `dir` at `target`, a file in the same directory.
+/// On Unix this is a symlink, created under a temporary name and renamed over the previous one so
+/// readers never see a missing or dangling latest image. Elsewhere the file is copied.
+pub async fn update_latest_symlink(
+    dir: &Path,
+    latest_name: &LatestName,
+    target: &str,
+    format: ImageFormat,
+) -> io::Result<()> {
+    let latest = dir.join(latest_name.filename(format));
+
+    #[cfg(unix)]
+    {
+        // named after the target so that concurrent updates do not share a temporary link
+        let temporary = dir.join(format!(".{}.latest.tmp", target.replace('/', "_")));
+        remove_if_exists(&temporary).await?;
+        tokio::fs::symlink(target, &temporary).await?;
+        tokio::fs::rename(&temporary, &latest).await?;
+    }
+
+    #[cfg(not(unix))]
+    tokio::fs::copy(dir.join(target), &latest).await?;
+
+    Ok(())
+}
+
+/// Filename the latest copy in the serial directory `dir` points at, when it is a symlink, relative
+/// to the directory
+pub async fn latest_target(dir: &Path, latest_name: &LatestName) -> io::Result<Option<String>> {
+    for format in ImageFormat::ALL {
+        match tokio::fs::read_link(dir.join(latest_name.filename(format))).await {
+            Ok(target) => return Ok(target.to_str().map(str::to_owned)),
+            // missing, or a plain copy
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::NotFound | io::ErrorKind::InvalidInput
+                ) => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(None)
+}
+
+/// Remove the latest copies of every format but `keep`, left behind by earlier uploads in a
+/// different format
+pub async fn remove_stale_latest(
+    dir: &Path,
+    latest_name: &LatestName,
+    keep: Option<ImageFormat>,
+) -> io::Result<()> {
+    for other in ImageFormat::ALL
+        .into_iter()
+        .filter(|other| Some(*other) != keep)
+    {
+        remove_if_exists(&dir.join(latest_name.filename(other))).await?;
+    }
+    Ok(())
+}
+
+/// Stored images of the serial directory `dir`, including those in the date subdirectories of a
+/// bucketed layout, as their path relative to `dir` along with their metadata
+pub async fn stored_images(
+    dir: &Path,
+    latest_name: &LatestName,
+) -> io::Result<Vec<(String, std::fs::Metadata)>> {
+    let mut images = Vec::new();
+    let mut subdirs = vec![String::new()];
+    while let Some(subdir) = subdirs.pop() {
+        let mut entries = tokio::fs::read_dir(dir.join(&subdir)).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let Ok(name) = entry.file_name().into_string() else {
+                continue;
+            };
+            let path = match subdir.is_empty() {
+                true => name.clone(),
+                false => format!("{}/{}", subdir, name),
+            };
+            let file_type = entry.file_type().await?;
+            if file_type.is_dir() && is_bucket(&name) {
+                subdirs.push(path);
+            } else if file_type.is_file() && is_stored_image(&name, latest_name) {
+                images.push((path, entry.metadata().await?));
+            }
+        }
+    }
+    Ok(images)
+}
+
+/// Whether `name` is the year, month or day directory of a bucketed layout
+fn is_bucket(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(|byte| byte.is_ascii_digit())
+}
+
+/// Bytes taken up by the stored images in `dir`
+async fn stored_images_len(dir: &Path, latest_name: &LatestName) -> io::Result<u64> {
+    Ok(stored_images(dir, latest_name)
+        .await?
+        .iter()
+        .map(|(_, metadata)| metadata.len())
+        .sum())
+}
+
+/// Remove the oldest stored images in `dir`, along with their thumbnails and sidecars, until at
+/// least `needed` bytes are freed or only the latest image is left. Returns the bytes freed.
+async fn evict_oldest(dir: &Path, latest_name: &LatestName, needed: u64) -> io::Result<u64> {
+    let latest = latest_target(dir, latest_name).await?;
+    let mut images = Vec::new();
+    for (filename, metadata) in stored_images(dir, latest_name).await? {
+        if latest.as_ref() != Some(&filename) {
+            images.push((metadata.modified()?, filename, metadata.len()));
+        }
+    }
+    images.sort();
+
+    let mut freed = 0;
+    for (_, filename, len) in images {
+        if freed >= needed {
+            break;
+        }
+        tokio::fs::remove_file(dir.join(&filename)).await?;
+        remove_if_exists(&dir.join(thumbnail_filename(&filename))).await?;
+        remove_if_exists(&dir.join(sidecar_filename(&filename))).await?;
+        tracing::info!(
+            "evicted {} to stay within the quota",
+            dir.join(&filename).display()
+        );
+        freed += len;
+    }
+    Ok(freed)
+}
+
+/// Whether `filename` is a stored image, rather than a hidden temporary, a bookkeeping file, a
+/// latest copy or a thumbnail
+pub fn is_stored_image(filename: &str, latest_name: &LatestName) -> bool {
+    let filename = filename.rsplit('/').next().unwrap_or(filename);
+    !filename.starts_with('.')
+        && has_image_extension(filename)
+        && !latest_name.matches(filename)
+        && !filename.starts_with("thumb-")
+}
+
+/// Thumbnail name for a stored image, `thumb-<timestamp>.jpg` for timestamp-named images and
+/// `thumb-<stem>.jpg` otherwise
+pub fn thumbnail_filename(filename: &str) -> String {
+    // next to the image, when it is in a subdirectory
+    let (subdir, filename) = match filename.rsplit_once('/') {
+        Some((subdir, filename)) => (Some(subdir), filename),
+        None => (None, filename),
+    };
+    let stem = filename.rsplit_once('.').map_or(filename, |(stem, _)| stem);
+    let thumbnail = format!("thumb-{}.jpg", stem.strip_prefix("image-").unwrap_or(stem));
+    match subdir {
+        Some(subdir) => format!("{}/{}", subdir, thumbnail),
+        None => thumbnail,
+    }
+}
+
+/// Whether `filename` ends in the extension of one of the supported formats
+fn has_image_extension(filename: &str) -> bool {
+    filename.rsplit_once('.').is_some_and(|(_, extension)| {
+        ImageFormat::ALL
+            .iter()
+            .any(|format| format.extension() == extension)
+    })
+}
+
+/// Remove the hidden `.tmp` files that saves interrupted by a crash left in the serial directories
+/// of `uploads_dir` and their date subdirectories, once they are older than `older_than`. Returns
+/// how many were removed.
+pub async fn remove_stale_temps(uploads_dir: &Path, older_than: Duration) -> io::Result<usize> {
+    let mut removed = 0;
+    let mut serial_dirs = match tokio::fs::read_dir(uploads_dir).await {
+        Ok(serial_dirs) => serial_dirs,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err),
+    };
+    while let Some(serial_dir) = serial_dirs.next_entry().await? {
+        let is_serial_dir = serial_dir.file_type().await?.is_dir()
+            && serial_dir.file_name().to_str().is_some_and(serial_is_valid);
+        if !is_serial_dir {
+            continue;
+        }
+        let mut dirs = vec![serial_dir.path()];
+        while let Some(dir) = dirs.pop() {
+            let mut entries = tokio::fs::read_dir(dir).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let Ok(filename) = entry.file_name().into_string() else {
+                    continue;
+                };
+                if is_bucket(&filename) && entry.file_type().await?.is_dir() {
+                    dirs.push(entry.path());
+                    continue;
+                }
+                if !(filename.starts_with('.') && filename.ends_with(".tmp")) {
+                    continue;
+                }
+                // `symlink_metadata`, as the temporary latest copy is a symlink
+                let age = tokio::fs::symlink_metadata(entry.path())
+                    .await?
+                    .modified()?
+                    .elapsed()
+                    .unwrap_or_default();
+                if age > older_than {
+                    remove_if_exists(&entry.path()).await?;
+                    removed += 1;
+                }
+            }
+        }
+    }
+    Ok(removed)
+}
+
+/// Remove a file, treating a missing file as already removed
+pub async fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match tokio::fs::remove_file(path).await {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+/// The serial number becomes a directory name, so it must be a single path component made only of
+/// ASCII letters, digits, dashes and underscores
+pub fn serial_is_valid(serial_number: &str) -> bool {
+    !serial_number.is_empty()
+        && serial_number
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        && path_is_valid(serial_number)
+}
+
+/// Whether `path` is a safe path of a file within a serial directory, such as an image in one of
+/// the date subdirectories of a bucketed layout: normal components only, at least one of them
+pub fn relative_path_is_valid(path: &str) -> bool {
+    let mut components = Path::new(path).components().peekable();
+    components.peek().is_some()
+        && components.all(|component| matches!(component, std::path::Component::Normal(_)))
+}
+
+/// A client supplied name (without extension) follows the serial number rules, and can not take
+/// the names the server uses for the latest copies, thumbnails and PUT uploads. Nor can it be a
+/// time as `filename_time` writes it, as its thumbnail would be that of the image named after the
+/// same time.
+fn stem_is_valid(stem: &str, latest_name: &LatestName, filename_time: &FilenameTime) -> bool {
+    serial_is_valid(stem)
+        && !stem.starts_with(latest_name.stem())
+        && !stem.starts_with("thumb-")
+        && !stem.starts_with("upload-")
+        && filename_time
+            .of_filename(&format!("image-{}.jpg", stem))
+            .is_none()
+}
+
+/// To prevent directory traversal attacks we ensure the path consists of exactly one normal
+/// component
+pub fn path_is_valid(path: &str) -> bool {
+    let path = Path::new(path);
+    let mut components = path.components().peekable();
+
+    if let Some(first) = components.peek() {
+        if !matches!(first, std::path::Component::Normal(_)) {
+            return false;
+        }
+    }
+    components.count() == 1
+}
+
+/// Name to store an upload under, from the filename the client gave it. The extension is dropped,
+/// as the stored one always follows the detected format.
+pub(crate) fn requested_stem(
+    filename: &str,
+    latest_name: &LatestName,
+    filename_time: &FilenameTime,
+) -> Option<String> {
+    if !path_is_valid(filename) {
+        return None;
+    }
+    Path::new(filename)
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .filter(|stem| stem_is_valid(stem, latest_name, filename_time))
+        .map(str::to_owned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+    use std::io::Cursor;
+    use tokio_util::io::StreamReader;
+
+    fn png(shade: u8) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        image::RgbImage::from_pixel(4, 4, image::Rgb([shade; 3]))
+            .write_to(&mut Cursor::new(&mut bytes), image::ImageFormat::Png)
+            .unwrap();
+        bytes
+    }
+
+    fn storage(uploads_dir: &Path) -> Storage {
+        Storage::new(
+            uploads_dir.to_owned(),
+            SaveOptions {
+                max_bytes: 1024 * 1024,
+                max_pixels: None,
+                strip_metadata: false,
+                webp_quality: None,
+                jpeg_max_bytes: None,
+                fallback_format: None,
+                allowed_formats: None,
+                max_concurrent_writes: 16,
+                reject_when_busy: false,
+                write_buffer_bytes: DEFAULT_WRITE_BUFFER_KB * 1024,
+                receive_timeout: None,
+                fsync: false,
+                serial_quota_bytes: None,
+                evict_oldest: false,
+                near_duplicate_distance: None,
+                naming: Naming::Timestamp,
+                layout: Layout::Flat,
+                filename_time: FilenameTime::default(),
+                latest_name: LatestName::default(),
+                file_mode: None,
+                dir_mode: None,
+            },
+        )
+    }
+
+    #[test]
+    fn serial_numbers_are_single_safe_components() {
+        assert!(serial_is_valid("cam-01_A"));
+        assert!(!serial_is_valid(""));
+        assert!(!serial_is_valid(".."));
+        assert!(!serial_is_valid("cam/01"));
+        assert!(!serial_is_valid("cam 01"));
+    }
+
+    #[test]
+    fn filename_time_formats_must_make_safe_names_that_read_back() {
+        let filename_time = FilenameTime::new("%Y-%m-%d_%H%M%S", true).unwrap();
+        let at = Local.with_ymd_and_hms(2024, 5, 6, 7, 8, 9).unwrap();
+        let stem = filename_time.stem(at);
+        assert_eq!(
+            filename_time.of_filename(&format!("{}.jpg", stem)),
+            Some(at.with_timezone(&Utc))
+        );
+        assert!(FilenameTime::new("%Y/%m/%d %H:%M", true).is_err());
+        assert!(FilenameTime::new("%Y%m%d", true).is_err());
+        assert!(FilenameTime::new("%Q", true).is_err());
+    }
+
+    #[test]
+    fn requested_names_keep_their_stem_only_when_safe() {
+        let latest_name = LatestName::default();
+        let filename_time = FilenameTime::default();
+        let requested_stem =
+            |filename, latest_name| requested_stem(filename, latest_name, &filename_time);
+        assert_eq!(
+            requested_stem("front-door.jpeg", &latest_name).as_deref(),
+            Some("front-door")
+        );
+        assert_eq!(requested_stem("../front-door.jpeg", &latest_name), None);
+        assert_eq!(requested_stem("aaa-latest.png", &latest_name), None);
+        assert_eq!(requested_stem("thumb-front-door.jpg", &latest_name), None);
+        assert_eq!(requested_stem("upload-retry-1.jpg", &latest_name), None);
+        // these would share the thumbnail of `image-20240101-120000.jpg` and the one after it
+        assert_eq!(
+            thumbnail_filename("image-20240101-120000.jpg"),
+            "thumb-20240101-120000.jpg"
+        );
+        assert_eq!(requested_stem("20240101-120000.jpg", &latest_name), None);
+        assert_eq!(requested_stem("20240101-120000-1.jpg", &latest_name), None);
+
+        let latest_name = LatestName::new("latest.jpg").unwrap();
+        assert_eq!(latest_name.filename(ImageFormat::Png), "latest.png");
+        assert_eq!(requested_stem("latest.png", &latest_name), None);
+        assert!(LatestName::new("../latest").is_err());
+        assert!(LatestName::new("upload-x.jpg").is_err());
+    }
+
+    #[test]
+    fn formats_are_detected_from_magic_numbers() {
+        assert_eq!(detect_image_format(&png(0)), Some(ImageFormat::Png));
+        assert_eq!(
+            detect_image_format(&[0xFF, 0xD8, 0xFF, 0xE0]),
+            Some(ImageFormat::Jpeg)
+        );
+        assert_eq!(detect_image_format(b"GIF89a"), Some(ImageFormat::Gif));
+        assert_eq!(
+            detect_image_format(b"RIFF\0\0\0\0WEBPVP8 "),
+            Some(ImageFormat::Webp)
+        );
+        assert_eq!(detect_image_format(b"not an image"), None);
+    }
+
+    #[tokio::test]
+    async fn unsupported_uploads_leave_nothing_behind() {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let result = storage(uploads_dir.path())
+            .save_image("cam", None, &UploadSource::default(), &b"not an image"[..])
+            .await;
+        assert!(matches!(result, Err(SaveError::UnsupportedFormat)));
+        assert!(!uploads_dir.path().join("cam").exists());
+    }
+
+    #[tokio::test]
+    async fn empty_uploads_are_rejected_even_with_a_fallback() {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let mut storage = storage(uploads_dir.path());
+        storage.options.fallback_format = Some(ImageFormat::Jpeg);
+        let result = storage
+            .save_image("cam", None, &UploadSource::default(), &b""[..])
+            .await;
+        assert!(matches!(result, Err(SaveError::Empty)));
+        assert!(!uploads_dir.path().join("cam").exists());
+    }
+
+    #[tokio::test]
+    async fn unwritable_serial_directories_are_an_error() {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        // a file in the way of the serial directory
+        std::fs::write(uploads_dir.path().join("cam"), b"").unwrap();
+        let result = storage(uploads_dir.path())
+            .save_image("cam", None, &UploadSource::default(), png(0).as_slice())
+            .await;
+        assert!(matches!(result, Err(SaveError::Io(_))));
+    }
+
+    #[tokio::test]
+    async fn oversized_uploads_are_rejected() {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let mut storage = storage(uploads_dir.path());
+        storage.options.max_bytes = 16;
+        let result = storage
+            .save_image("cam", None, &UploadSource::default(), png(0).as_slice())
+            .await;
+        assert!(matches!(result, Err(SaveError::TooLarge(16))));
+        let serial_dir = uploads_dir.path().join("cam");
+        assert_eq!(std::fs::read_dir(serial_dir).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn stalled_uploads_time_out_and_leave_nothing_behind() {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let mut storage = storage(uploads_dir.path());
+        storage.options.receive_timeout = Some(Duration::from_millis(50));
+        // the client sends the start of an image and then nothing more, without hanging up
+        let (mut client, body) = tokio::io::duplex(64);
+        client.write_all(&png(0)[..32]).await.unwrap();
+        let result = storage
+            .save_image("cam", None, &UploadSource::default(), body)
+            .await;
+        assert!(matches!(result, Err(SaveError::TimedOut(_))));
+        let serial_dir = uploads_dir.path().join("cam");
+        assert_eq!(std::fs::read_dir(serial_dir).unwrap().count(), 0);
+        drop(client);
+    }
+
+    #[tokio::test]
+    async fn images_with_too_many_pixels_are_rejected_from_their_header() {
+        // a small JPEG whose frame header claims 30000x30000 pixels, which would take gigabytes
+        // to decode
+        let mut upload = Vec::new();
+        image::RgbImage::from_pixel(8, 8, image::Rgb([0; 3]))
+            .write_to(&mut Cursor::new(&mut upload), image::ImageFormat::Jpeg)
+            .unwrap();
+        let frame = upload
+            .windows(2)
+            .position(|marker| marker == [0xFF, 0xC0])
+            .unwrap();
+        upload[frame + 5..frame + 9].copy_from_slice(&[0x75, 0x30, 0x75, 0x30]);
+
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let mut storage = storage(uploads_dir.path());
+        storage.options.max_pixels = Some(50_000_000);
+        let result = storage
+            .save_image("cam", None, &UploadSource::default(), upload.as_slice())
+            .await;
+        assert!(matches!(
+            result,
+            Err(SaveError::TooManyPixels {
+                width: 30000,
+                height: 30000,
+                max_pixels: 50_000_000,
+            })
+        ));
+        let serial_dir = uploads_dir.path().join("cam");
+        assert_eq!(std::fs::read_dir(serial_dir).unwrap().count(), 0);
+
+        // a dry run turns it away the same way
+        let result = storage
+            .validate_image("cam", None, &UploadSource::default(), upload.as_slice())
+            .await;
+        assert!(matches!(
+            result,
+            Err(SaveError::TooManyPixels {
+                width: 30000,
+                height: 30000,
+                max_pixels: 50_000_000,
+            })
+        ));
+        storage
+            .validate_image("cam", None, &UploadSource::default(), png(0).as_slice())
+            .await
+            .unwrap();
+
+        storage
+            .save_image("cam", None, &UploadSource::default(), png(0).as_slice())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn uploads_recover_from_the_uploads_directory_being_removed() {
+        let parent = tempfile::tempdir().unwrap();
+        let uploads_dir = parent.path().join("uploads");
+        let mut storage = storage(&uploads_dir);
+        // room for a single image, so that a count left over from the removed images fails
+        storage.options.serial_quota_bytes = Some(png(0).len() as u64 + 16);
+        let source = UploadSource::default();
+        storage
+            .save_image("cam", None, &source, png(0).as_slice())
+            .await
+            .unwrap();
+
+        std::fs::remove_dir_all(&uploads_dir).unwrap();
+        let saved = storage
+            .save_image("cam", Some("door"), &source, png(0).as_slice())
+            .await
+            .unwrap();
+        assert!(!saved.duplicate);
+        assert_eq!(saved.filename, "door.png");
+        assert_eq!(std::fs::read(&saved.path).unwrap(), png(0));
+        assert_eq!(
+            std::fs::read(uploads_dir.join("cam/aaa-latest.png")).unwrap(),
+            png(0)
+        );
+    }
+
+    #[tokio::test]
+    async fn rotated_images_replace_the_stored_copy() {
+        let mut upload = Vec::new();
+        image::RgbImage::from_fn(4, 2, |x, _| image::Rgb([x as u8 * 60; 3]))
+            .write_to(&mut Cursor::new(&mut upload), image::ImageFormat::Png)
+            .unwrap();
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let storage = storage(uploads_dir.path());
+        let saved = storage
+            .save_image("cam", None, &UploadSource::default(), upload.as_slice())
+            .await
+            .unwrap();
+
+        let format = storage
+            .rotate_image("cam", &saved.filename, Rotation::Rotate90)
+            .await
+            .unwrap();
+        assert_eq!(format, ImageFormat::Png);
+        let rotated = image::open(&saved.path).unwrap().to_rgb8();
+        assert_eq!(rotated.dimensions(), (2, 4));
+        // the left column ends up on top
+        assert_eq!(rotated.get_pixel(0, 0), &image::Rgb([0; 3]));
+        assert_eq!(rotated.get_pixel(0, 3), &image::Rgb([180; 3]));
+        let sidecar = read_sidecar(&uploads_dir.path().join("cam"), &saved.filename)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            sidecar.stored_bytes,
+            Some(std::fs::metadata(&saved.path).unwrap().len())
+        );
+    }
+
+    #[tokio::test]
+    async fn taken_names_get_a_suffix() {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let storage = storage(uploads_dir.path());
+        let first = storage
+            .save_image(
+                "cam",
+                Some("door.png"),
+                &UploadSource::default(),
+                png(0).as_slice(),
+            )
+            .await
+            .unwrap();
+        let second = storage
+            .save_image(
+                "cam",
+                Some("door.jpg"),
+                &UploadSource::default(),
+                png(1).as_slice(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(first.filename, "door.png");
+        assert_eq!(second.filename, "door-1.png");
+    }
+
+    #[tokio::test]
+    async fn uploads_within_the_same_second_are_all_kept() {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let storage = storage(uploads_dir.path());
+        let source = UploadSource::default();
+        let (first_body, second_body) = (png(0), png(1));
+        let (first, second) = tokio::join!(
+            storage.save_image("cam", None, &source, first_body.as_slice()),
+            storage.save_image("cam", None, &source, second_body.as_slice()),
+        );
+        let (first, second) = (first.unwrap(), second.unwrap());
+        assert_ne!(first.filename, second.filename);
+        assert_eq!(std::fs::read(&first.path).unwrap(), first_body);
+        assert_eq!(std::fs::read(&second.path).unwrap(), second_body);
+        for saved in [&first, &second] {
+            assert!(storage
+                .filename_time()
+                .of_filename(&saved.filename)
+                .is_some());
+        }
+    }
+
+    #[tokio::test]
+    async fn validation_names_the_image_without_storing_it() {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let storage = storage(uploads_dir.path());
+        let source = UploadSource::default();
+        storage
+            .save_image("cam", Some("door"), &source, png(0).as_slice())
+            .await
+            .unwrap();
+
+        let new = storage
+            .validate_image(
+                "cam",
+                Some("door.png"),
+                &UploadSource::default(),
+                png(1).as_slice(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            (new.filename.as_str(), new.duplicate),
+            ("door-1.png", false)
+        );
+        assert_eq!(new.bytes, png(1).len() as u64);
+        let duplicate = storage
+            .validate_image("cam", None, &UploadSource::default(), png(0).as_slice())
+            .await
+            .unwrap();
+        assert_eq!(
+            (duplicate.filename.as_str(), duplicate.duplicate),
+            ("door.png", true)
+        );
+        assert!(!uploads_dir.path().join("cam/door-1.png").exists());
+        assert!(matches!(
+            storage
+                .validate_image(
+                    "other",
+                    None,
+                    &UploadSource::default(),
+                    &b"not an image"[..]
+                )
+                .await,
+            Err(SaveError::UnsupportedFormat)
+        ));
+    }
+
+    #[tokio::test]
+    async fn jpegs_over_the_budget_are_recompressed() {
+        // noise compresses badly, so the upload is well over the budget at full quality
+        let noise = image::RgbImage::from_fn(128, 128, |_, _| image::Rgb(rand::random()));
+        let mut upload = Vec::new();
+        image::DynamicImage::ImageRgb8(noise)
+            .write_with_encoder(image::codecs::jpeg::JpegEncoder::new_with_quality(
+                &mut upload,
+                100,
+            ))
+            .unwrap();
+        let budget = upload.len() as u64 / 3;
+
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let mut storage = storage(uploads_dir.path());
+        storage.options.jpeg_max_bytes = Some(budget);
+        let saved = storage
+            .save_image("cam", None, &UploadSource::default(), upload.as_slice())
+            .await
+            .unwrap();
+        let stored = std::fs::read(&saved.path).unwrap();
+        assert!((stored.len() as u64) <= budget);
+        assert_eq!(detect_image_format(&stored), Some(ImageFormat::Jpeg));
+
+        let sidecar = read_sidecar(&uploads_dir.path().join("cam"), &saved.filename)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(sidecar.bytes, upload.len() as u64);
+        assert_eq!(sidecar.stored_bytes, Some(stored.len() as u64));
+    }
+
+    #[tokio::test]
+    async fn duplicates_point_at_the_stored_copy() {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let storage = storage(uploads_dir.path());
+        let first = storage
+            .save_image(
+                "cam",
+                Some("first"),
+                &UploadSource::default(),
+                png(0).as_slice(),
+            )
+            .await
+            .unwrap();
+        storage
+            .save_image(
+                "cam",
+                Some("other"),
+                &UploadSource::default(),
+                png(1).as_slice(),
+            )
+            .await
+            .unwrap();
+        let again = storage
+            .save_image(
+                "cam",
+                Some("again"),
+                &UploadSource::default(),
+                png(0).as_slice(),
+            )
+            .await
+            .unwrap();
+        assert!(!first.duplicate);
+        assert!(again.duplicate);
+        assert_eq!(again.filename, "first.png");
+
+        let serial_dir = uploads_dir.path().join("cam");
+        assert!(!serial_dir.join("again.png").exists());
+        assert_eq!(
+            std::fs::read(serial_dir.join("aaa-latest.png")).unwrap(),
+            png(0)
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_uploads_all_make_it_into_the_hashes() {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let storage = Arc::new(storage(uploads_dir.path()));
+        let uploads = (0..16u8).chain([0, 0]).map(|shade| {
+            let storage = storage.clone();
+            tokio::spawn(async move {
+                storage
+                    .save_image("cam", None, &UploadSource::default(), png(shade).as_slice())
+                    .await
+                    .unwrap()
+            })
+        });
+        let saved = futures::future::join_all(uploads).await;
+        let saved: Vec<_> = saved.into_iter().map(Result::unwrap).collect();
+
+        // the same bytes sent three times at once are stored once
+        assert_eq!(saved.iter().filter(|saved| !saved.duplicate).count(), 16);
+        let hashes = read_hashes(&uploads_dir.path().join("cam")).await.unwrap();
+        assert_eq!(hashes.len(), 16);
+        for saved in saved {
+            assert_eq!(
+                hashes.get(&format!(
+                    "{:x}",
+                    sha2::Sha256::digest(std::fs::read(&saved.path).unwrap())
+                )),
+                Some(&saved.filename)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn latest_only_uploads_keep_no_history() {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let storage = storage(uploads_dir.path());
+        let stored = storage
+            .save_image(
+                "cam",
+                Some("kept"),
+                &UploadSource::default(),
+                png(0).as_slice(),
+            )
+            .await
+            .unwrap();
+        let saved = storage
+            .save_latest_only("cam", &UploadSource::default(), png(1).as_slice())
+            .await
+            .unwrap();
+        assert_eq!(saved.filename, "aaa-latest.png");
+        assert!(!saved.duplicate);
+        // the same bytes as a stored image are still written out
+        storage
+            .save_latest_only("cam", &UploadSource::default(), png(0).as_slice())
+            .await
+            .unwrap();
+
+        let serial_dir = uploads_dir.path().join("cam");
+        let mut filenames: Vec<_> = std::fs::read_dir(&serial_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|filename| !filename.starts_with('.'))
+            .collect();
+        filenames.sort();
+        assert_eq!(
+            filenames,
+            ["aaa-latest.png", "hashes.json", "kept.png", "kept.png.json"]
+        );
+        assert!(
+            !std::fs::symlink_metadata(serial_dir.join("aaa-latest.png"))
+                .unwrap()
+                .is_symlink()
+        );
+        assert_eq!(
+            std::fs::read(serial_dir.join("aaa-latest.png")).unwrap(),
+            png(0)
+        );
+        assert_eq!(std::fs::read(stored.path).unwrap(), png(0));
+    }
+
+    #[tokio::test]
+    async fn repairing_points_the_latest_copy_at_the_newest_image() {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let storage = storage(uploads_dir.path());
+        for (name, image) in [("older", png(0)), ("newer", png(1))] {
+            storage
+                .save_image(
+                    "cam",
+                    Some(name),
+                    &UploadSource::default(),
+                    image.as_slice(),
+                )
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            storage.repair_latest("cam").await.unwrap(),
+            LatestRepair::Unchanged
+        );
+
+        // removed by hand, leaving the latest copy dangling
+        let serial_dir = uploads_dir.path().join("cam");
+        std::fs::remove_file(serial_dir.join("newer.png")).unwrap();
+        assert_eq!(
+            storage.repair_latest("cam").await.unwrap(),
+            LatestRepair::Repointed("older.png".to_owned())
+        );
+        assert_eq!(
+            std::fs::read(serial_dir.join("aaa-latest.png")).unwrap(),
+            png(0)
+        );
+        assert_eq!(
+            storage.repair_latest("cam").await.unwrap(),
+            LatestRepair::Unchanged
+        );
+
+        std::fs::remove_file(serial_dir.join("older.png")).unwrap();
+        assert_eq!(
+            storage.repair_latest("cam").await.unwrap(),
+            LatestRepair::Removed
+        );
+        assert!(std::fs::symlink_metadata(serial_dir.join("aaa-latest.png")).is_err());
+    }
+
+    #[tokio::test]
+    async fn frames_that_look_alike_are_dropped_as_near_duplicates() {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let mut storage = storage(uploads_dir.path());
+        storage.options.near_duplicate_distance = Some(2);
+        let gradient = |flip: bool, speck: u8| {
+            let mut image = image::RgbImage::from_fn(32, 32, |x, _| {
+                let shade = (x * 8) as u8;
+                image::Rgb([if flip { 255 - shade } else { shade }; 3])
+            });
+            image.put_pixel(5, 5, image::Rgb([speck; 3]));
+            let mut bytes = Vec::new();
+            image
+                .write_to(&mut Cursor::new(&mut bytes), image::ImageFormat::Png)
+                .unwrap();
+            bytes
+        };
+        let save = |image: Vec<u8>| {
+            let storage = &storage;
+            async move {
+                storage
+                    .save_image("cam", None, &UploadSource::default(), image.as_slice())
+                    .await
+                    .unwrap()
+            }
+        };
+
+        let first = save(gradient(false, 0)).await;
+        assert!(!first.duplicate);
+        // one pixel apart, so the bytes differ but the frame does not
+        let speck = save(gradient(false, 255)).await;
+        assert!(speck.duplicate);
+        assert_eq!(speck.filename, first.filename);
+        let flipped = save(gradient(true, 0)).await;
+        assert!(!flipped.duplicate);
+
+        let images = stored_images(&uploads_dir.path().join("cam"), storage.latest_name())
+            .await
+            .unwrap();
+        assert_eq!(images.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn overrides_only_apply_to_their_own_serial_number() {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let storage = storage(uploads_dir.path());
+        storage.set_overrides(
+            parse_overrides(r#"{"strict": {"allowed_formats": ["jpeg"]}}"#).unwrap(),
+        );
+        let (image, source) = (png(0), UploadSource::default());
+        let save =
+            |serial_number| storage.save_image(serial_number, None, &source, image.as_slice());
+        assert!(matches!(
+            save("strict").await,
+            Err(SaveError::FormatNotAllowed(ImageFormat::Png))
+        ));
+        assert!(save("other").await.is_ok());
+
+        // reloading replaces the overrides as a whole
+        storage.set_overrides(HashMap::new());
+        assert!(save("strict").await.is_ok());
+
+        assert!(parse_overrides(r#"{"../up": {}}"#).is_err());
+        assert!(parse_overrides(r#"{"cam": {"webp_quality": 101}}"#).is_err());
+        assert!(parse_overrides(r#"{"cam": {"max_bytes": 1}}"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn daily_layouts_store_images_in_date_buckets() {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let mut storage = storage(uploads_dir.path());
+        storage.options.layout = Layout::Daily;
+        let saved = storage
+            .save_image("cam", None, &UploadSource::default(), png(0).as_slice())
+            .await
+            .unwrap();
+        let (bucket, basename) = saved.filename.rsplit_once('/').unwrap();
+        // the default filename time is in UTC, and so is the date of the bucket
+        assert_eq!(
+            bucket,
+            saved
+                .received_at
+                .with_timezone(&Utc)
+                .format("%Y/%m/%d")
+                .to_string()
+        );
+        assert!(relative_path_is_valid(&saved.filename));
+        assert!(is_stored_image(&saved.filename, storage.latest_name()));
+        assert!(basename.ends_with(".png"));
+
+        let serial_dir = uploads_dir.path().join("cam");
+        assert_eq!(
+            std::fs::read(serial_dir.join("aaa-latest.png")).unwrap(),
+            png(0)
+        );
+        let images = stored_images(&serial_dir, storage.latest_name())
+            .await
+            .unwrap();
+        let filenames: Vec<_> = images.iter().map(|(filename, _)| filename).collect();
+        assert_eq!(filenames, [&saved.filename]);
+        assert!(!relative_path_is_valid("2024/../../other"));
+        assert!(!relative_path_is_valid("/2024/05/06/image.png"));
+    }
+
+    #[tokio::test]
+    async fn puts_under_the_same_upload_id_replace_each_other() {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let mut storage = storage(uploads_dir.path());
+        storage.options.layout = Layout::Daily;
+        let source = UploadSource::default();
+        let first = storage
+            .put_image("cam", "a1", &source, png(0).as_slice())
+            .await
+            .unwrap();
+        assert!(first.filename.ends_with("/upload-a1.png"));
+        assert!(!first.replaced);
+        // the same bytes under another ID are stored again, not taken as a duplicate
+        let other = storage
+            .put_image("cam", "b2", &source, png(0).as_slice())
+            .await
+            .unwrap();
+        assert!(!other.duplicate);
+
+        let again = storage
+            .put_image("cam", "a1", &source, png(1).as_slice())
+            .await
+            .unwrap();
+        assert!(again.replaced);
+        assert_eq!(again.filename, first.filename);
+        let serial_dir = uploads_dir.path().join("cam");
+        assert_eq!(std::fs::read(&again.path).unwrap(), png(1));
+        let stored = stored_images(&serial_dir, storage.latest_name())
+            .await
+            .unwrap();
+        assert_eq!(stored.len(), 2);
+
+        assert!(matches!(
+            storage
+                .put_image("cam", "../a1", &source, png(0).as_slice())
+                .await,
+            Err(SaveError::InvalidUploadId)
+        ));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn written_files_and_directories_get_the_configured_modes() {
+        use std::os::unix::fs::PermissionsExt;
+
+        assert_eq!(parse_mode("0640"), Ok(0o640));
+        assert_eq!(parse_mode("0o750"), Ok(0o750));
+        assert!(parse_mode("0980").is_err());
+        assert!(parse_mode("").is_err());
+
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let mut storage = storage(uploads_dir.path());
+        storage.options.layout = Layout::Monthly;
+        storage.options.file_mode = Some(0o600);
+        storage.options.dir_mode = Some(0o700);
+        let saved = storage
+            .save_image("cam", None, &UploadSource::default(), png(0).as_slice())
+            .await
+            .unwrap();
+
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o7777;
+        let serial_dir = uploads_dir.path().join("cam");
+        let (year, _) = saved.filename.split_once('/').unwrap();
+        for dir in [
+            serial_dir.clone(),
+            serial_dir.join(year),
+            saved.path.parent().unwrap().to_owned(),
+        ] {
+            assert_eq!(mode(&dir), 0o700, "{}", dir.display());
+        }
+        for file in [
+            saved.path.clone(),
+            serial_dir.join(sidecar_filename(&saved.filename)),
+            serial_dir.join(HASHES_FILENAME),
+        ] {
+            assert_eq!(mode(&file), 0o600, "{}", file.display());
+        }
+    }
+
+    #[tokio::test]
+    async fn images_go_by_the_time_they_were_received() {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let mut storage = storage(uploads_dir.path());
+        storage.options.layout = Layout::Daily;
+        let received_at = Local.with_ymd_and_hms(2001, 2, 3, 12, 5, 6).unwrap();
+        let source = UploadSource {
+            received_at: Some(received_at),
+            ..UploadSource::default()
+        };
+        let validated = storage
+            .validate_image("cam", None, &source, png(0).as_slice())
+            .await
+            .unwrap();
+        let saved = storage
+            .save_image("cam", None, &source, png(0).as_slice())
+            .await
+            .unwrap();
+
+        assert_eq!(saved.filename, validated.filename);
+        assert_eq!(saved.received_at, received_at);
+        let filename_time = storage.filename_time();
+        assert_eq!(
+            saved.filename,
+            format!(
+                "{}/{}.png",
+                Layout::Daily
+                    .bucket(received_at, filename_time.utc())
+                    .unwrap(),
+                filename_time.stem(received_at)
+            )
+        );
+        let sidecar = read_sidecar(&uploads_dir.path().join("cam"), &saved.filename)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(sidecar.received_at, received_at.to_rfc3339());
+    }
+
+    #[tokio::test]
+    async fn content_hash_names_store_the_same_bytes_once() {
+        use sha2::Digest;
+
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let mut storage = storage(uploads_dir.path());
+        storage.options.naming = Naming::ContentHash;
+        let source = UploadSource::default();
+        let first = storage
+            .save_image("cam", Some("door"), &source, png(0).as_slice())
+            .await
+            .unwrap();
+        assert_eq!(
+            first.filename,
+            format!("{:x}.png", sha2::Sha256::digest(png(0)))
+        );
+        assert!(!first.duplicate);
+        storage
+            .save_image("cam", None, &source, png(1).as_slice())
+            .await
+            .unwrap();
+
+        // the name alone tells the image is stored, even without the hash index
+        let serial_dir = uploads_dir.path().join("cam");
+        std::fs::remove_file(serial_dir.join(HASHES_FILENAME)).unwrap();
+        let again = storage
+            .save_image("cam", None, &source, png(0).as_slice())
+            .await
+            .unwrap();
+        assert!(again.duplicate);
+        assert_eq!(again.filename, first.filename);
+        assert_eq!(
+            std::fs::read(serial_dir.join("aaa-latest.png")).unwrap(),
+            png(0)
+        );
+        let stored = std::fs::read_dir(&serial_dir)
+            .unwrap()
+            .filter_map(|entry| entry.unwrap().file_name().into_string().ok())
+            .filter(|filename| is_stored_image(filename, storage.latest_name()))
+            .count();
+        assert_eq!(stored, 2);
+    }
+
+    #[tokio::test]
+    async fn unrecognized_uploads_use_the_fallback_format() {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let mut storage = storage(uploads_dir.path());
+        storage.options.fallback_format = Some(ImageFormat::Jpeg);
+        let saved = storage
+            .save_image("cam", None, &UploadSource::default(), &b"raw frame"[..])
+            .await
+            .unwrap();
+        assert_eq!(saved.format, ImageFormat::Jpeg);
+        assert!(saved.filename.ends_with(".jpg"));
+        assert!(uploads_dir.path().join("cam/aaa-latest.jpg").exists());
+    }
+
+    #[tokio::test]
+    async fn newer_upload_stays_latest_when_an_older_one_finishes_last() {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let storage = storage(uploads_dir.path());
+
+        // the older upload starts first but its body only completes after the newer one is stored
+        let older = png(0);
+        let (head, tail) = older.split_at(MAGIC_BYTES_LEN);
+        let older_body = futures::stream::iter([Ok::<_, io::Error>(Bytes::copy_from_slice(head))])
+            .chain(futures::stream::once(async {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                Ok(Bytes::copy_from_slice(tail))
+            }));
+        let older_body = StreamReader::new(older_body);
+        futures::pin_mut!(older_body);
+        let newer = png(255);
+        let source = UploadSource::default();
+
+        let (older, newer) = tokio::join!(
+            storage.save_image("cam", Some("older"), &source, older_body),
+            async {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                storage
+                    .save_image("cam", Some("newer"), &source, newer.as_slice())
+                    .await
+            }
+        );
+        assert_eq!(older.unwrap().filename, "older.png");
+        assert_eq!(newer.unwrap().filename, "newer.png");
+
+        let serial_dir = uploads_dir.path().join("cam");
+        let latest = std::fs::read(serial_dir.join("aaa-latest.png")).unwrap();
+        assert_eq!(latest, std::fs::read(serial_dir.join("newer.png")).unwrap());
+    }
+}
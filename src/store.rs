@@ -0,0 +1,815 @@
+//! Keeping images somewhere else than in the uploads directory, for deployments without a disk of
+//! their own.
+//!
+//! `ImageStore` is what images are stored with and read back from. `Storage` is its filesystem
+//! implementation; `S3Store` keeps them in a bucket of an S3-compatible object store instead, each
+//! image under `<serial>/<filename>` and the latest copy next to them, and `MemoryStore` in the
+//! memory of the process. These check uploads the same way, but leave out what takes a
+//! filesystem: thumbnails, sidecars, deduplication, quotas, re-encoding, date subdirectories and
+//! the settings of serial numbers of their own.
+
+use crate::storage::{
+    detect_image_format, is_stored_image, read_header, relative_path_is_valid, requested_stem,
+    serial_is_valid, stored_images, ImageFormat, SaveError, SaveOptions, SavedImage, Storage,
+    UploadSource, MAGIC_BYTES_LEN,
+};
+use async_trait::async_trait;
+use aws_sdk_s3::{
+    error::DisplayErrorContext,
+    primitives::ByteStream,
+    types::{CompletedMultipartUpload, CompletedPart},
+    Client,
+};
+use chrono::{DateTime, Local, Utc};
+use std::{collections::HashMap, future::Future, io, path::PathBuf, sync::Mutex};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    sync::OnceCell,
+};
+
+/// Bytes sent in each part of a multipart upload, the smallest S3 takes for all parts but the last
+const S3_PART_LEN: usize = 5 * 1024 * 1024;
+
+/// Where stored images are kept
+#[async_trait]
+pub trait ImageStore: Send + Sync {
+    /// Store the image read from `body` for `serial_number` and make it the latest one, named the
+    /// way `Storage::save_image` names it
+    async fn put(
+        &self,
+        serial_number: &str,
+        requested_filename: Option<&str>,
+        source: &UploadSource,
+        body: &mut (dyn AsyncRead + Send + Unpin),
+    ) -> Result<SavedImage, SaveError>;
+
+    /// Bytes of the image `filename` of `serial_number`, `None` when there is none
+    async fn get(&self, serial_number: &str, filename: &str) -> io::Result<Option<Vec<u8>>>;
+
+    /// Bytes of the latest copy of `serial_number`, `None` when it has none
+    async fn latest(&self, serial_number: &str) -> io::Result<Option<Vec<u8>>>;
+
+    /// Images stored for `serial_number`, in no particular order
+    async fn list(&self, serial_number: &str) -> io::Result<Vec<StoredEntry>>;
+}
+
+/// An image as listed by `ImageStore::list`
+#[derive(Debug, Clone)]
+pub struct StoredEntry {
+    pub filename: String,
+    pub size: u64,
+    pub modified: DateTime<Utc>,
+}
+
+#[async_trait]
+impl ImageStore for Storage {
+    async fn put(
+        &self,
+        serial_number: &str,
+        requested_filename: Option<&str>,
+        source: &UploadSource,
+        body: &mut (dyn AsyncRead + Send + Unpin),
+    ) -> Result<SavedImage, SaveError> {
+        self.save_image(serial_number, requested_filename, source, body)
+            .await
+    }
+
+    async fn get(&self, serial_number: &str, filename: &str) -> io::Result<Option<Vec<u8>>> {
+        if !serial_is_valid(serial_number)
+            || !relative_path_is_valid(filename)
+            || !is_stored_image(filename, self.latest_name())
+        {
+            return Ok(None);
+        }
+        read_if_exists(self.serial_dir(serial_number).join(filename)).await
+    }
+
+    async fn latest(&self, serial_number: &str) -> io::Result<Option<Vec<u8>>> {
+        if !serial_is_valid(serial_number) {
+            return Ok(None);
+        }
+        let dir = self.serial_dir(serial_number);
+        for format in ImageFormat::ALL {
+            let latest = read_if_exists(dir.join(self.latest_name().filename(format))).await?;
+            if latest.is_some() {
+                return Ok(latest);
+            }
+        }
+        Ok(None)
+    }
+
+    async fn list(&self, serial_number: &str) -> io::Result<Vec<StoredEntry>> {
+        if !serial_is_valid(serial_number) {
+            return Ok(Vec::new());
+        }
+        let images = match stored_images(&self.serial_dir(serial_number), self.latest_name()).await
+        {
+            Ok(images) => images,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err),
+        };
+        images
+            .into_iter()
+            .map(|(filename, metadata)| {
+                Ok(StoredEntry {
+                    filename,
+                    size: metadata.len(),
+                    modified: metadata.modified()?.into(),
+                })
+            })
+            .collect()
+    }
+}
+
+/// Contents of the file at `path`, `None` when there is none
+async fn read_if_exists(path: PathBuf) -> io::Result<Option<Vec<u8>>> {
+    match tokio::fs::read(path).await {
+        Ok(bytes) => Ok(Some(bytes)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// First bytes of an upload to an object store, once they pass the checks `Storage` makes: a valid
+/// serial number, and an image in a format it accepts
+async fn read_start(
+    options: &SaveOptions,
+    serial_number: &str,
+    body: &mut (dyn AsyncRead + Send + Unpin),
+) -> Result<(ImageFormat, Vec<u8>), SaveError> {
+    if !serial_is_valid(serial_number) {
+        return Err(SaveError::InvalidSerial);
+    }
+    let mut header = [0; MAGIC_BYTES_LEN];
+    let header_len = read_header(body, &mut header).await?;
+    let header = &header[..header_len];
+    if header.is_empty() {
+        return Err(SaveError::Empty);
+    }
+    let format = detect_image_format(header)
+        .or(options.fallback_format)
+        .ok_or(SaveError::UnsupportedFormat)?;
+    match &options.allowed_formats {
+        Some(allowed) if !allowed.contains(&format) => Err(SaveError::FormatNotAllowed(format)),
+        _ => Ok((format, header.to_vec())),
+    }
+}
+
+/// `receiving`, given up on with `SaveError::TimedOut` once it takes longer than
+/// `SaveOptions::receive_timeout`
+async fn before_timeout<T>(
+    options: &SaveOptions,
+    receiving: impl Future<Output = Result<T, SaveError>>,
+) -> Result<T, SaveError> {
+    match options.receive_timeout {
+        Some(timeout) => tokio::time::timeout(timeout, receiving)
+            .await
+            .map_err(|_| SaveError::TimedOut(timeout))?,
+        None => receiving.await,
+    }
+}
+
+/// Names an upload received at `received_at` is stored under, in the order they are tried: the stem
+/// it gets from `Storage`, then with a `-<n>` suffix
+fn candidate_filenames<'a>(
+    options: &'a SaveOptions,
+    requested_filename: Option<&str>,
+    format: ImageFormat,
+    received_at: DateTime<Local>,
+) -> impl Iterator<Item = String> + 'a {
+    let stem = requested_filename
+        .and_then(|filename| requested_stem(filename, &options.latest_name, &options.filename_time))
+        .unwrap_or_else(|| options.filename_time.stem(received_at));
+    (0..).map(move |suffix| match suffix {
+        0 => format!("{}.{}", stem, format.extension()),
+        _ => format!("{}-{}.{}", stem, suffix, format.extension()),
+    })
+}
+
+/// Read from `body` onto `part` until it holds `len` bytes. Returns whether the body ended first.
+async fn fill_part(
+    body: &mut (dyn AsyncRead + Send + Unpin),
+    part: &mut Vec<u8>,
+    len: usize,
+) -> io::Result<bool> {
+    let wanted = len.saturating_sub(part.len()) as u64;
+    let read = body.take(wanted).read_to_end(part).await?;
+    Ok((read as u64) < wanted)
+}
+
+/// Where `S3Store` keeps its images
+#[derive(Debug, Clone)]
+pub struct S3Settings {
+    pub bucket: String,
+    /// put in front of every key, such as `uploads/`
+    pub prefix: String,
+    /// endpoint of an S3-compatible store other than AWS, such as `http://localhost:9000`
+    pub endpoint_url: Option<String>,
+    /// address the bucket in the path rather than in the host name, as most S3-compatible stores
+    /// other than AWS need
+    pub force_path_style: bool,
+}
+
+/// Images kept in a bucket of an S3-compatible object store. Uploads stream to it as multipart
+/// uploads, so that only one part of each is held in memory, and the latest copy is a copy of the
+/// object of the image made by the store itself. The latest copy is that of the upload that
+/// finished last. Credentials and the region come from the usual AWS environment variables and
+/// profiles.
+pub struct S3Store {
+    settings: S3Settings,
+    options: SaveOptions,
+    /// made on first use, as loading the AWS configuration takes the runtime
+    client: OnceCell<Client>,
+}
+
+impl S3Store {
+    pub fn new(settings: S3Settings, options: SaveOptions) -> Self {
+        S3Store {
+            settings,
+            options,
+            client: OnceCell::new(),
+        }
+    }
+
+    async fn client(&self) -> &Client {
+        self.client
+            .get_or_init(|| async {
+                let mut loader = aws_config::defaults(aws_config::BehaviorVersion::latest());
+                if let Some(endpoint_url) = &self.settings.endpoint_url {
+                    loader = loader.endpoint_url(endpoint_url);
+                }
+                let shared = loader.load().await;
+                let config = aws_sdk_s3::config::Builder::from(&shared)
+                    .force_path_style(self.settings.force_path_style)
+                    .build();
+                Client::from_conf(config)
+            })
+            .await
+    }
+
+    /// Key of the object of `filename` of `serial_number`
+    fn key(&self, serial_number: &str, filename: &str) -> String {
+        format!("{}{}/{}", self.settings.prefix, serial_number, filename)
+    }
+
+    async fn exists(&self, key: &str) -> io::Result<bool> {
+        let head = self
+            .client()
+            .await
+            .head_object()
+            .bucket(&self.settings.bucket)
+            .key(key)
+            .send()
+            .await;
+        match head {
+            Ok(_) => Ok(true),
+            Err(err) if err.as_service_error().is_some_and(|err| err.is_not_found()) => Ok(false),
+            Err(err) => Err(s3_error(err)),
+        }
+    }
+
+    async fn read(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        let object = self
+            .client()
+            .await
+            .get_object()
+            .bucket(&self.settings.bucket)
+            .key(key)
+            .send()
+            .await;
+        match object {
+            Ok(object) => {
+                let bytes = object.body.collect().await.map_err(io::Error::other)?;
+                Ok(Some(bytes.to_vec()))
+            }
+            Err(err)
+                if err
+                    .as_service_error()
+                    .is_some_and(|err| err.is_no_such_key()) =>
+            {
+                Ok(None)
+            }
+            Err(err) => Err(s3_error(err)),
+        }
+    }
+
+    /// Send `start` and the rest of `body` to `key`: as a single object when it fits in one part,
+    /// and as a multipart upload otherwise, given up on once it goes past the size limit. Returns
+    /// the size of the upload.
+    async fn upload(
+        &self,
+        key: &str,
+        format: ImageFormat,
+        start: Vec<u8>,
+        body: &mut (dyn AsyncRead + Send + Unpin),
+    ) -> Result<u64, SaveError> {
+        let client = self.client().await;
+        let bucket = &self.settings.bucket;
+        let max_bytes = self.options.max_bytes;
+
+        let mut part = start;
+        let mut ended = fill_part(body, &mut part, S3_PART_LEN).await?;
+        let mut sent = part.len() as u64;
+        if sent > max_bytes {
+            return Err(SaveError::TooLarge(max_bytes));
+        }
+        if ended {
+            client
+                .put_object()
+                .bucket(bucket)
+                .key(key)
+                .content_type(format.mime_type())
+                .body(ByteStream::from(part))
+                .send()
+                .await
+                .map_err(s3_error)?;
+            return Ok(sent);
+        }
+
+        let upload_id = client
+            .create_multipart_upload()
+            .bucket(bucket)
+            .key(key)
+            .content_type(format.mime_type())
+            .send()
+            .await
+            .map_err(s3_error)?
+            .upload_id
+            .ok_or_else(|| io::Error::other("S3 answered without an upload ID"))?;
+        let uploaded = async {
+            let mut parts = Vec::new();
+            loop {
+                // the part after tells whether this one is the last, which may be smaller
+                let mut next = Vec::new();
+                if !ended {
+                    ended = fill_part(body, &mut next, S3_PART_LEN).await?;
+                    sent += next.len() as u64;
+                    if sent > max_bytes {
+                        return Err(SaveError::TooLarge(max_bytes));
+                    }
+                }
+                let part_number = parts.len() as i32 + 1;
+                let e_tag = client
+                    .upload_part()
+                    .bucket(bucket)
+                    .key(key)
+                    .upload_id(&upload_id)
+                    .part_number(part_number)
+                    .body(ByteStream::from(std::mem::take(&mut part)))
+                    .send()
+                    .await
+                    .map_err(s3_error)?
+                    .e_tag;
+                parts.push(
+                    CompletedPart::builder()
+                        .part_number(part_number)
+                        .set_e_tag(e_tag)
+                        .build(),
+                );
+                if next.is_empty() {
+                    break;
+                }
+                part = next;
+            }
+            client
+                .complete_multipart_upload()
+                .bucket(bucket)
+                .key(key)
+                .upload_id(&upload_id)
+                .multipart_upload(
+                    CompletedMultipartUpload::builder()
+                        .set_parts(Some(parts))
+                        .build(),
+                )
+                .send()
+                .await
+                .map_err(s3_error)?;
+            Ok(sent)
+        }
+        .await;
+        if uploaded.is_err() {
+            // the parts sent so far are billed until the upload is aborted
+            let aborted = client
+                .abort_multipart_upload()
+                .bucket(bucket)
+                .key(key)
+                .upload_id(&upload_id)
+                .send()
+                .await;
+            if let Err(err) = aborted {
+                tracing::warn!(
+                    "could not abort the upload of {}: {}",
+                    key,
+                    DisplayErrorContext(err)
+                );
+            }
+        }
+        uploaded
+    }
+
+    /// Copy the object of `filename` of `serial_number` over its latest copy, removing the latest
+    /// copies in the other formats
+    async fn set_latest(
+        &self,
+        serial_number: &str,
+        filename: &str,
+        format: ImageFormat,
+    ) -> io::Result<()> {
+        let client = self.client().await;
+        let bucket = &self.settings.bucket;
+        let latest_name = &self.options.latest_name;
+        client
+            .copy_object()
+            .bucket(bucket)
+            .copy_source(format!("{}/{}", bucket, self.key(serial_number, filename)))
+            .key(self.key(serial_number, &latest_name.filename(format)))
+            .content_type(format.mime_type())
+            .send()
+            .await
+            .map_err(s3_error)?;
+        for other in ImageFormat::ALL
+            .into_iter()
+            .filter(|other| *other != format)
+        {
+            client
+                .delete_object()
+                .bucket(bucket)
+                .key(self.key(serial_number, &latest_name.filename(other)))
+                .send()
+                .await
+                .map_err(s3_error)?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ImageStore for S3Store {
+    async fn put(
+        &self,
+        serial_number: &str,
+        requested_filename: Option<&str>,
+        source: &UploadSource,
+        body: &mut (dyn AsyncRead + Send + Unpin),
+    ) -> Result<SavedImage, SaveError> {
+        let received_at = source.received_at.unwrap_or_else(Local::now);
+        before_timeout(&self.options, async {
+            let (format, start) = read_start(&self.options, serial_number, body).await?;
+            // a concurrent upload picking the same name meanwhile may still take it first
+            let mut filename = None;
+            for candidate in
+                candidate_filenames(&self.options, requested_filename, format, received_at)
+            {
+                if !self.exists(&self.key(serial_number, &candidate)).await? {
+                    filename = Some(candidate);
+                    break;
+                }
+            }
+            let filename = filename.expect("the candidate filenames never run out");
+            let key = self.key(serial_number, &filename);
+
+            let bytes = self.upload(&key, format, start, body).await?;
+            self.set_latest(serial_number, &filename, format).await?;
+            tracing::debug!("image saved to {}: {}", serial_number, key);
+            Ok(SavedImage {
+                filename,
+                path: PathBuf::from(key),
+                bytes,
+                format,
+                duplicate: false,
+                replaced: false,
+                received_at,
+            })
+        })
+        .await
+    }
+
+    async fn get(&self, serial_number: &str, filename: &str) -> io::Result<Option<Vec<u8>>> {
+        if !serial_is_valid(serial_number)
+            || !relative_path_is_valid(filename)
+            || !is_stored_image(filename, &self.options.latest_name)
+        {
+            return Ok(None);
+        }
+        self.read(&self.key(serial_number, filename)).await
+    }
+
+    async fn latest(&self, serial_number: &str) -> io::Result<Option<Vec<u8>>> {
+        if !serial_is_valid(serial_number) {
+            return Ok(None);
+        }
+        for format in ImageFormat::ALL {
+            let key = self.key(serial_number, &self.options.latest_name.filename(format));
+            let latest = self.read(&key).await?;
+            if latest.is_some() {
+                return Ok(latest);
+            }
+        }
+        Ok(None)
+    }
+
+    async fn list(&self, serial_number: &str) -> io::Result<Vec<StoredEntry>> {
+        if !serial_is_valid(serial_number) {
+            return Ok(Vec::new());
+        }
+        let prefix = self.key(serial_number, "");
+        let mut pages = self
+            .client()
+            .await
+            .list_objects_v2()
+            .bucket(&self.settings.bucket)
+            .prefix(&prefix)
+            .into_paginator()
+            .send();
+        let mut entries = Vec::new();
+        while let Some(page) = pages.next().await {
+            for object in page.map_err(s3_error)?.contents.unwrap_or_default() {
+                let Some(filename) = object
+                    .key
+                    .as_deref()
+                    .and_then(|key| key.strip_prefix(&prefix))
+                else {
+                    continue;
+                };
+                if filename.contains('/') || !is_stored_image(filename, &self.options.latest_name) {
+                    continue;
+                }
+                let modified = object
+                    .last_modified
+                    .and_then(|modified| {
+                        DateTime::from_timestamp(modified.secs(), modified.subsec_nanos())
+                    })
+                    .unwrap_or_default();
+                entries.push(StoredEntry {
+                    filename: filename.to_owned(),
+                    size: object.size.unwrap_or(0).try_into().unwrap_or(0),
+                    modified,
+                });
+            }
+        }
+        Ok(entries)
+    }
+}
+
+/// An error of the S3 client, with the causes it wraps in its message
+fn s3_error<E: std::error::Error>(err: E) -> io::Error {
+    io::Error::other(DisplayErrorContext(err).to_string())
+}
+
+/// Images kept in the memory of the process, gone once it exits, for tests and demos that should
+/// leave nothing behind on disk
+pub struct MemoryStore {
+    options: SaveOptions,
+    serials: Mutex<HashMap<String, MemorySerial>>,
+}
+
+#[derive(Default)]
+struct MemorySerial {
+    images: HashMap<String, MemoryImage>,
+    /// filename of the latest image, and when it was received
+    latest: Option<(String, DateTime<Local>)>,
+}
+
+struct MemoryImage {
+    bytes: Vec<u8>,
+    stored_at: DateTime<Utc>,
+}
+
+impl MemoryStore {
+    pub fn new(options: SaveOptions) -> Self {
+        MemoryStore {
+            options,
+            serials: Mutex::default(),
+        }
+    }
+}
+
+#[async_trait]
+impl ImageStore for MemoryStore {
+    async fn put(
+        &self,
+        serial_number: &str,
+        requested_filename: Option<&str>,
+        source: &UploadSource,
+        body: &mut (dyn AsyncRead + Send + Unpin),
+    ) -> Result<SavedImage, SaveError> {
+        let received_at = source.received_at.unwrap_or_else(Local::now);
+        let max_bytes = self.options.max_bytes;
+        let (format, bytes) = before_timeout(&self.options, async {
+            let (format, mut bytes) = read_start(&self.options, serial_number, body).await?;
+            // one byte more than the limit tells an upload that is too large
+            body.take(max_bytes.saturating_add(1))
+                .read_to_end(&mut bytes)
+                .await?;
+            match bytes.len() as u64 > max_bytes {
+                true => Err(SaveError::TooLarge(max_bytes)),
+                false => Ok((format, bytes)),
+            }
+        })
+        .await?;
+
+        let mut serials = self.serials.lock().unwrap();
+        let serial = serials.entry(serial_number.to_owned()).or_default();
+        let filename = candidate_filenames(&self.options, requested_filename, format, received_at)
+            .find(|candidate| !serial.images.contains_key(candidate))
+            .expect("the candidate filenames never run out");
+        let len = bytes.len() as u64;
+        serial.images.insert(
+            filename.clone(),
+            MemoryImage {
+                bytes,
+                stored_at: Utc::now(),
+            },
+        );
+        // an upload that took longer than a later one does not take the latest copy back
+        if serial
+            .latest
+            .as_ref()
+            .is_none_or(|(_, latest_at)| *latest_at <= received_at)
+        {
+            serial.latest = Some((filename.clone(), received_at));
+        }
+        Ok(SavedImage {
+            path: PathBuf::from(serial_number).join(&filename),
+            filename,
+            bytes: len,
+            format,
+            duplicate: false,
+            replaced: false,
+            received_at,
+        })
+    }
+
+    async fn get(&self, serial_number: &str, filename: &str) -> io::Result<Option<Vec<u8>>> {
+        let serials = self.serials.lock().unwrap();
+        Ok(serials
+            .get(serial_number)
+            .and_then(|serial| serial.images.get(filename))
+            .map(|image| image.bytes.clone()))
+    }
+
+    async fn latest(&self, serial_number: &str) -> io::Result<Option<Vec<u8>>> {
+        let serials = self.serials.lock().unwrap();
+        Ok(serials.get(serial_number).and_then(|serial| {
+            let (filename, _) = serial.latest.as_ref()?;
+            serial.images.get(filename).map(|image| image.bytes.clone())
+        }))
+    }
+
+    async fn list(&self, serial_number: &str) -> io::Result<Vec<StoredEntry>> {
+        let serials = self.serials.lock().unwrap();
+        Ok(serials
+            .get(serial_number)
+            .map(|serial| {
+                serial
+                    .images
+                    .iter()
+                    .map(|(filename, image)| StoredEntry {
+                        filename: filename.clone(),
+                        size: image.bytes.len() as u64,
+                        modified: image.stored_at,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{FilenameTime, LatestName, Layout, Naming, DEFAULT_WRITE_BUFFER_KB};
+    use std::io::Cursor;
+
+    fn png() -> Vec<u8> {
+        let mut bytes = Vec::new();
+        image::RgbImage::from_pixel(4, 4, image::Rgb([128; 3]))
+            .write_to(&mut Cursor::new(&mut bytes), image::ImageFormat::Png)
+            .unwrap();
+        bytes
+    }
+
+    fn options() -> SaveOptions {
+        SaveOptions {
+            max_bytes: 1024 * 1024,
+            max_pixels: None,
+            strip_metadata: false,
+            webp_quality: None,
+            jpeg_max_bytes: None,
+            fallback_format: None,
+            allowed_formats: None,
+            max_concurrent_writes: 16,
+            reject_when_busy: false,
+            write_buffer_bytes: DEFAULT_WRITE_BUFFER_KB * 1024,
+            receive_timeout: None,
+            fsync: false,
+            serial_quota_bytes: None,
+            evict_oldest: false,
+            near_duplicate_distance: None,
+            naming: Naming::Timestamp,
+            layout: Layout::Flat,
+            filename_time: FilenameTime::default(),
+            latest_name: LatestName::default(),
+            file_mode: None,
+            dir_mode: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn the_filesystem_store_reads_back_what_it_stored() {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let store: &dyn ImageStore = &Storage::new(uploads_dir.path().to_owned(), options());
+        let png = png();
+
+        let saved = store
+            .put(
+                "cam",
+                Some("front.png"),
+                &UploadSource::default(),
+                &mut &png[..],
+            )
+            .await
+            .unwrap();
+        assert_eq!(saved.filename, "front.png");
+        assert_eq!(
+            store.get("cam", "front.png").await.unwrap(),
+            Some(png.clone())
+        );
+        assert_eq!(store.latest("cam").await.unwrap(), Some(png.clone()));
+        let listed = store.list("cam").await.unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].filename, "front.png");
+        assert_eq!(listed[0].size, png.len() as u64);
+
+        assert_eq!(store.get("cam", "../cam/front.png").await.unwrap(), None);
+        assert_eq!(store.get("cam", "missing.png").await.unwrap(), None);
+        assert_eq!(store.latest("other").await.unwrap(), None);
+        assert!(store.list("other").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn uploads_to_object_stores_are_checked_like_the_others() {
+        let options = options();
+        let png = png();
+        let (format, start) = read_start(&options, "cam", &mut &png[..]).await.unwrap();
+        assert_eq!(format, ImageFormat::Png);
+        assert_eq!(start, png[..MAGIC_BYTES_LEN]);
+
+        assert!(matches!(
+            read_start(&options, "../cam", &mut &png[..]).await,
+            Err(SaveError::InvalidSerial)
+        ));
+        assert!(matches!(
+            read_start(&options, "cam", &mut &b""[..]).await,
+            Err(SaveError::Empty)
+        ));
+        assert!(matches!(
+            read_start(&options, "cam", &mut &b"not an image"[..]).await,
+            Err(SaveError::UnsupportedFormat)
+        ));
+    }
+
+    #[test]
+    fn object_store_names_get_a_suffix_when_taken() {
+        let options = options();
+        let names: Vec<_> =
+            candidate_filenames(&options, Some("front.jpg"), ImageFormat::Png, Local::now())
+                .take(3)
+                .collect();
+        assert_eq!(names, ["front.png", "front-1.png", "front-2.png"]);
+    }
+
+    #[tokio::test]
+    async fn the_memory_store_keeps_the_latest_received_upload() {
+        let store = MemoryStore::new(options());
+        let png = png();
+        let at = |second| UploadSource {
+            received_at: Some(Local::now() + chrono::Duration::seconds(second)),
+            ..UploadSource::default()
+        };
+
+        let later = store
+            .put("cam", Some("later.png"), &at(1), &mut &png[..])
+            .await
+            .unwrap();
+        // received first, it finishes last
+        let mut earlier = png.clone();
+        earlier.push(0);
+        store
+            .put("cam", Some("earlier.png"), &at(0), &mut &earlier[..])
+            .await
+            .unwrap();
+        assert_eq!(store.latest("cam").await.unwrap(), Some(png.clone()));
+        assert_eq!(store.get("cam", &later.filename).await.unwrap(), Some(png));
+        assert_eq!(store.list("cam").await.unwrap().len(), 2);
+
+        let mut small = options();
+        small.max_bytes = 8;
+        assert!(matches!(
+            MemoryStore::new(small)
+                .put("cam", None, &UploadSource::default(), &mut &earlier[..])
+                .await,
+            Err(SaveError::TooLarge(8))
+        ));
+    }
+}
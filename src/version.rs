@@ -0,0 +1,26 @@
+//! Which build of the servers is running
+
+use chrono::DateTime;
+
+/// Version of the crate
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Commit the servers were built from, `unknown` when built outside a git checkout
+pub const GIT_COMMIT: &str = env!("GIT_COMMIT");
+
+/// When the servers were built, in seconds since the Unix epoch
+pub const BUILD_TIMESTAMP: &str = env!("BUILD_TIMESTAMP");
+
+/// The build as served by `/version`, with the build time in RFC 3339
+pub fn build_info() -> serde_json::Value {
+    let built_at = BUILD_TIMESTAMP
+        .parse()
+        .ok()
+        .and_then(|secs| DateTime::from_timestamp(secs, 0))
+        .map(|built_at| built_at.to_rfc3339());
+    serde_json::json!({
+        "version": VERSION,
+        "git_commit": GIT_COMMIT,
+        "built_at": built_at,
+    })
+}
@@ -0,0 +1,531 @@
+//! The upload protocol of the WebSocket route, shared by the servers that serve it. A client sends
+//! its serial number as the first text message, then the frames of each image, ending every image
+//! with an empty binary frame or an `END` text message.
+
+use crate::storage::{serial_is_valid, set_mode, SaveError, Storage, UploadSource};
+use axum::{
+    extract::ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade},
+    response::Response,
+};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::{
+    borrow::Cow,
+    io,
+    net::SocketAddr,
+    ops::ControlFlow,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::io::AsyncWriteExt;
+
+/// text message that, like an empty binary frame, marks the end of an image
+const END_OF_IMAGE: &str = "END";
+
+/// per serial directory, one JSON line for every image saved
+const MANIFEST_FILENAME: &str = "manifest.jsonl";
+
+/// used when `FRAME_RATE_LIMIT` is set but `FRAME_RATE_BURST` is not
+pub const DEFAULT_FRAME_RATE_BURST: f64 = 10.0;
+
+/// used when `PING_INTERVAL_SECS` is not set
+pub const DEFAULT_PING_INTERVAL_SECS: u64 = 30;
+
+/// used when `IDLE_TIMEOUT_SECS` is not set
+pub const DEFAULT_IDLE_TIMEOUT_SECS: u64 = 90;
+
+/// What a connection is uploading, and how it went so far
+struct Session {
+    serial_number: String,
+    /// an image may be split across several binary frames, they are collected here until the
+    /// client marks the end of the image
+    image: Vec<u8>,
+    /// when the first frame of `image` arrived, the time the image is stored as received at
+    image_received_at: Option<DateTime<Local>>,
+    images_saved: u64,
+    images_failed: u64,
+    bytes_saved: u64,
+}
+
+/// Commands a client may send as JSON text, such as `{"cmd":"stats"}`
+#[derive(Deserialize)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+enum Command {
+    /// answered with `{"cmd":"pong"}`
+    Ping,
+    /// store the next images under another serial number
+    SetSerial { value: String },
+    /// answered with the counts of the connection
+    Stats,
+}
+
+/// Line appended to the manifest for each saved image
+#[derive(Serialize)]
+struct ManifestEntry<'a> {
+    timestamp: String,
+    filename: &'a str,
+    bytes: u64,
+}
+
+/// What every connection is handled with
+pub struct SocketState {
+    pub storage: Arc<Storage>,
+    /// frames and images larger than this close the connection
+    pub max_upload_bytes: usize,
+    /// binary frames per connection are limited, when set
+    pub frame_rate_limit: Option<RateLimit>,
+    /// how often connections are pinged to keep them alive
+    pub ping_interval: Duration,
+    /// connections that send nothing, not even a pong, for this long are closed
+    pub idle_timeout: Duration,
+}
+
+/// Rate and burst size of a token bucket
+#[derive(Clone, Copy)]
+pub struct RateLimit {
+    pub per_second: f64,
+    pub burst: f64,
+}
+
+/// Token bucket that starts full and refills at `limit.per_second`, holding at most `limit.burst`
+struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: RateLimit) -> Self {
+        TokenBucket {
+            limit,
+            tokens: limit.burst,
+            updated: Instant::now(),
+        }
+    }
+
+    /// Take a token, or tell how long until the next one is available
+    fn try_take(&mut self) -> Result<(), Duration> {
+        let now = Instant::now();
+        self.tokens = self.tokens_at(now);
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - self.tokens) / self.limit.per_second,
+            ))
+        }
+    }
+
+    fn tokens_at(&self, now: Instant) -> f64 {
+        let refilled = now.duration_since(self.updated).as_secs_f64() * self.limit.per_second;
+        (self.tokens + refilled).min(self.limit.burst)
+    }
+}
+
+/// Finish the upgrade of a connection from `who` to a WebSocket, its images stored as coming from
+/// `source`. A message can not be larger than the image it carries, and the frame limit turns away
+/// an oversized frame from its header, before any of it is buffered.
+pub fn upgrade(
+    ws: WebSocketUpgrade,
+    who: SocketAddr,
+    source: UploadSource,
+    state: Arc<SocketState>,
+) -> Response {
+    ws.max_message_size(state.max_upload_bytes)
+        .max_frame_size(state.max_upload_bytes)
+        .on_upgrade(move |socket| handle_socket(socket, who, source, state))
+}
+
+/// Actual websocket statemachine (one will be spawned per connection)
+async fn handle_socket(
+    mut socket: WebSocket,
+    who: SocketAddr,
+    source: UploadSource,
+    state: Arc<SocketState>,
+) {
+    let mut serial_number = String::from("undefined");
+    // send a ping (unsupported by some browsers) just to kick things off and get a response
+    if socket.send(Message::Ping(vec![1, 2, 3])).await.is_ok() {
+        tracing::debug!("Pinged {who}...");
+    } else {
+        tracing::warn!("Could not send ping {who}!");
+        // no Error here since the only thing we can do is to close the connection.
+        // If we can not send messages, there is no way to salvage the statemachine anyway.
+        return;
+    }
+
+    let Ok(first) = tokio::time::timeout(state.idle_timeout, socket.recv()).await else {
+        tracing::warn!(
+            "{who} sent no serial number within {:?}",
+            state.idle_timeout
+        );
+        return;
+    };
+    if let Some(msg) = first {
+        match msg {
+            Ok(msg) => {
+                let message = get_text(msg, who);
+                if message.is_break() {
+                    return;
+                } else {
+                    if let Some(text) = message.continue_value() {
+                        // the serial number names the directory the images are saved in
+                        if !serial_is_valid(&text) {
+                            tracing::warn!("{who} sent an invalid serial number {text:?}, closing");
+                            let _ = socket
+                                .send(Message::Close(Some(CloseFrame {
+                                    code: close_code::POLICY,
+                                    reason: Cow::from(
+                                        "invalid serial number, expected ASCII letters, digits, `-` and `_`",
+                                    ),
+                                })))
+                                .await;
+                            return;
+                        }
+                        serial_number = text.clone();
+                        tracing::info!("received serial_number = {}", serial_number);
+                    }
+                }
+            }
+            Err(err) => {
+                close_after_receive_error(&mut socket, who, err, &state).await;
+                return;
+            }
+        }
+    }
+
+    let mut session = Session {
+        serial_number,
+        image: Vec::new(),
+        image_received_at: None,
+        images_saved: 0,
+        images_failed: 0,
+        bytes_saved: 0,
+    };
+    let mut frame_bucket = state.frame_rate_limit.map(TokenBucket::new);
+
+    // the first tick completes right away, and a ping was just sent
+    let mut ping = tokio::time::interval(state.ping_interval);
+    ping.tick().await;
+    let idle = tokio::time::sleep(state.idle_timeout);
+    tokio::pin!(idle);
+
+    // receive single message from a client (we can either receive or send with socket).
+    // this will likely be the Pong for our Ping or a hello message from client.
+    // waiting for message from a client will block this task, but will not block other client's
+    // connections. Meanwhile the client is pinged periodically, and dropped once it goes silent.
+    loop {
+        let msg = tokio::select! {
+            msg = socket.recv() => msg,
+            _ = ping.tick() => {
+                if socket.send(Message::Ping(vec![1, 2, 3])).await.is_err() {
+                    tracing::warn!("Could not send ping {who}!");
+                    return;
+                }
+                continue;
+            }
+            _ = &mut idle => {
+                tracing::info!("{who} sent nothing for {:?}, closing", state.idle_timeout);
+                let _ = socket
+                    .send(Message::Close(Some(CloseFrame {
+                        code: close_code::AWAY,
+                        reason: Cow::from("connection idle for too long"),
+                    })))
+                    .await;
+                return;
+            }
+        };
+        let Some(msg) = msg else {
+            break;
+        };
+        idle.as_mut()
+            .reset(tokio::time::Instant::now() + state.idle_timeout);
+
+        if let Ok(msg) = msg {
+            if let (Message::Binary(_), Some(bucket)) = (&msg, &mut frame_bucket) {
+                if bucket.try_take().is_err() {
+                    tracing::warn!("{who} sends frames too fast, closing");
+                    let _ = socket
+                        .send(Message::Close(Some(CloseFrame {
+                            code: close_code::POLICY,
+                            reason: Cow::from("binary frames sent too fast"),
+                        })))
+                        .await;
+                    return;
+                }
+            }
+            if let ControlFlow::Break(close_frame) =
+                process_message(&mut socket, msg, who, &source, &state, &mut session).await
+            {
+                if let Some(close_frame) = close_frame {
+                    let _ = socket.send(Message::Close(Some(close_frame))).await;
+                }
+                return;
+            }
+        } else if let Err(err) = msg {
+            close_after_receive_error(&mut socket, who, err, &state).await;
+            return;
+        }
+    }
+
+    // returning from the handler closes the websocket connection
+    tracing::debug!("Websocket context {who} destroyed");
+}
+
+/// Tell a client whose message broke the size limits why it is dropped. Any other receive error
+/// means the connection is gone, so there is nobody to tell.
+async fn close_after_receive_error(
+    socket: &mut WebSocket,
+    who: SocketAddr,
+    err: axum::Error,
+    state: &SocketState,
+) {
+    // axum hands back the error of the tungstenite version it is built with
+    let too_large = err
+        .into_inner()
+        .downcast_ref::<tungstenite::Error>()
+        .is_some_and(|err| matches!(err, tungstenite::Error::Capacity(_)));
+    if !too_large {
+        tracing::warn!("client {who} abruptly disconnected");
+        return;
+    }
+    tracing::warn!(
+        "message from {who} exceeds {} bytes, closing",
+        state.max_upload_bytes
+    );
+    let _ = socket
+        .send(Message::Close(Some(CloseFrame {
+            code: close_code::SIZE,
+            reason: Cow::from(format!(
+                "message exceeds the limit of {} bytes",
+                state.max_upload_bytes
+            )),
+        })))
+        .await;
+}
+
+fn get_text(msg: Message, who: SocketAddr) -> ControlFlow<(), String> {
+    match msg {
+        Message::Text(text) => {
+            tracing::debug!(">>> {who} sent str: {text:?}");
+            ControlFlow::Continue(text)
+        }
+        Message::Close(c) => {
+            if let Some(cf) = c {
+                tracing::debug!(
+                    ">>> {} sent close with code {} and reason `{}`",
+                    who,
+                    cf.code,
+                    cf.reason
+                );
+            } else {
+                tracing::debug!(">>> {who} somehow sent close message without CloseFrame");
+            }
+            ControlFlow::Break(())
+        }
+        _ => {
+            tracing::warn!("unexpected message");
+            ControlFlow::Break(())
+        }
+    }
+}
+
+/// helper to log the contents of messages. Has special treatment for Close.
+/// Binary frames are appended to the image of `session`, which is saved once an empty binary
+/// frame or an `END` text message arrives, and the outcome is acknowledged on `socket`. Text that
+/// is a JSON object with a `cmd` field is a `Command`, other text is only printed.
+/// Breaking with a `CloseFrame` asks the caller to send it.
+async fn process_message(
+    socket: &mut WebSocket,
+    msg: Message,
+    who: SocketAddr,
+    source: &UploadSource,
+    state: &SocketState,
+    session: &mut Session,
+) -> ControlFlow<Option<CloseFrame<'static>>, ()> {
+    match msg {
+        Message::Text(t) if t == END_OF_IMAGE => {
+            tracing::debug!(">>> {who} ended the image");
+            finish_image(socket, who, source, state, session).await;
+        }
+        Message::Text(t) => {
+            tracing::debug!(">>> {who} sent str: {t:?}");
+            // the cameras send their serial number as text before every image
+            let command = match serde_json::from_str::<serde_json::Value>(&t) {
+                Ok(value) if value.get("cmd").is_some() => serde_json::from_value(value),
+                _ => return ControlFlow::Continue(()),
+            };
+            let reply = match command {
+                Ok(command) => run_command(command, session),
+                Err(err) => serde_json::json!({ "error": format!("invalid command: {}", err) }),
+            };
+            if socket.send(Message::Text(reply.to_string())).await.is_err() {
+                tracing::warn!("could not answer the command of {who}");
+            }
+        }
+        Message::Binary(d) if d.is_empty() => {
+            tracing::debug!(">>> {who} ended the image");
+            finish_image(socket, who, source, state, session).await;
+        }
+        Message::Binary(d) => {
+            tracing::debug!(">>> {} sent {} bytes", who, d.len());
+            let image = &mut session.image;
+            if image.len() + d.len() > state.max_upload_bytes {
+                tracing::warn!(
+                    "image from {who} exceeds {} bytes, closing",
+                    state.max_upload_bytes
+                );
+                return ControlFlow::Break(Some(CloseFrame {
+                    code: close_code::SIZE,
+                    reason: Cow::from(format!(
+                        "image exceeds the limit of {} bytes",
+                        state.max_upload_bytes
+                    )),
+                }));
+            }
+            session.image_received_at.get_or_insert_with(Local::now);
+            image.extend_from_slice(&d);
+        }
+        Message::Close(c) => {
+            if let Some(cf) = c {
+                tracing::debug!(
+                    ">>> {} sent close with code {} and reason `{}`",
+                    who,
+                    cf.code,
+                    cf.reason
+                );
+            } else {
+                tracing::debug!(">>> {who} somehow sent close message without CloseFrame");
+            }
+            return ControlFlow::Break(None);
+        }
+
+        Message::Pong(v) => {
+            tracing::debug!(">>> {who} sent pong with {v:?}");
+        }
+        // You should never need to manually handle Message::Ping, as axum's websocket library
+        // will do so for you automagically by replying with Pong and copying the v according to
+        // spec. But if you need the contents of the pings you can see them here.
+        Message::Ping(v) => {
+            tracing::debug!(">>> {who} sent ping with {v:?}");
+        }
+    }
+    ControlFlow::Continue(())
+}
+
+/// The reply to `command`
+fn run_command(command: Command, session: &mut Session) -> serde_json::Value {
+    match command {
+        Command::Ping => serde_json::json!({ "cmd": "pong" }),
+        Command::SetSerial { value } if !serial_is_valid(&value) => serde_json::json!({
+            "error": "invalid serial number, expected ASCII letters, digits, `-` and `_`",
+        }),
+        // the frames received so far belong to the serial number they were sent for
+        Command::SetSerial { .. } if !session.image.is_empty() => serde_json::json!({
+            "error": "an image is being received, end it before changing the serial number",
+        }),
+        Command::SetSerial { value } => {
+            tracing::info!(
+                "serial_number changed from {} to {}",
+                session.serial_number,
+                value
+            );
+            session.serial_number = value;
+            serde_json::json!({ "serial_number": session.serial_number })
+        }
+        Command::Stats => serde_json::json!({
+            "serial_number": session.serial_number,
+            "images_saved": session.images_saved,
+            "images_failed": session.images_failed,
+            "bytes_saved": session.bytes_saved,
+        }),
+    }
+}
+
+/// Save the frames collected so far as one image and start collecting the next one. The client
+/// is told whether the image was stored, so it knows when it can drop its own copy.
+async fn finish_image(
+    socket: &mut WebSocket,
+    who: SocketAddr,
+    source: &UploadSource,
+    state: &SocketState,
+    session: &mut Session,
+) {
+    tracing::debug!("going to save received image to file");
+    let image = std::mem::take(&mut session.image);
+    let bytes = image.len() as u64;
+    let source = UploadSource {
+        received_at: session.image_received_at.take(),
+        ..source.clone()
+    };
+    let ack = match save_image(state, &session.serial_number, &source, image).await {
+        Ok(filename) => {
+            session.images_saved += 1;
+            session.bytes_saved += bytes;
+            serde_json::json!({ "saved": true, "filename": filename })
+        }
+        Err(err) => {
+            session.images_failed += 1;
+            tracing::warn!("could not save image from {who}: {err}");
+            serde_json::json!({ "saved": false, "error": err })
+        }
+    };
+    if socket.send(Message::Text(ack.to_string())).await.is_err() {
+        tracing::warn!("could not acknowledge image to {who}");
+    }
+}
+
+/// Store `data` as the latest image of `serial_number` and record it in the manifest
+async fn save_image(
+    state: &SocketState,
+    serial_number: &str,
+    source: &UploadSource,
+    data: Vec<u8>,
+) -> Result<String, String> {
+    async {
+        let saved = state
+            .storage
+            .save_image(serial_number, None, source, data.as_slice())
+            .await?;
+
+        // a duplicate was recorded when it was first stored
+        if !saved.duplicate {
+            append_to_manifest(
+                &state.storage.serial_dir(serial_number),
+                &ManifestEntry {
+                    timestamp: saved.received_at.to_rfc3339(),
+                    filename: &saved.filename,
+                    bytes: saved.bytes,
+                },
+                state.storage.file_mode(),
+            )
+            .await?;
+        }
+
+        Ok::<_, SaveError>(saved.filename)
+    }
+    .await
+    .map_err(|err| err.to_string())
+}
+
+/// Append `entry` to the manifest of the serial directory `dir`. The line goes out in a single
+/// append-mode write, so entries from concurrent connections never interleave. The manifest is
+/// given `file_mode` like the images it lists.
+async fn append_to_manifest(
+    dir: &std::path::Path,
+    entry: &ManifestEntry<'_>,
+    file_mode: Option<u32>,
+) -> io::Result<()> {
+    let mut line = serde_json::to_vec(entry)?;
+    line.push(b'\n');
+    let path = dir.join(MANIFEST_FILENAME);
+    let mut manifest = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .await?;
+    set_mode(&path, file_mode).await?;
+    manifest.write_all(&line).await
+}